@@ -0,0 +1,739 @@
+//! An assembler for the mnemonics of Cowgod's technical reference, with the
+//! SUPER-CHIP and XO-CHIP additions, as printed by [`Op`] and the
+//! [disassembler](crate::disasm).
+//!
+//! ```text
+//! ; comments start with a semicolon
+//! SPEED   EQU 3                   ; constants
+//!         ORG #200                ; where the following code goes
+//! start:  LD I, sprite            ; labels end with a colon
+//!         ADD V0, SPEED * 2       ; expressions with + - * / % & | ^ ~ << >>
+//!         JP start
+//! sprite: db #F0, 0b10010000, "A" ; bytes, and dw for big-endian words
+//!         include "font.s"        ; relative to the including file
+//! ```
+//!
+//! Numbers are decimal, or hexadecimal when prefixed with `#` or `0x`, or
+//! binary with `0b`. Mnemonics, directives and registers are not case
+//! sensitive, labels and constants are.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::{error, fmt, fs};
+
+use crate::Op;
+
+/// Where programs start unless the source says otherwise with `ORG`.
+const DEFAULT_ORIGIN: u16 = 0x200;
+/// Constants referring to each other deeper than this are assumed to be
+/// defined in terms of themselves.
+const MAX_DEPTH: usize = 64;
+
+const PUNCTUATION: [&str; 17] = [
+    "<<", ">>", "+", "-", "*", "/", "%", "&", "|", "^", "~", "(", ")", "[", "]", ",", ":",
+];
+/// Binary operators from the loosest to the tightest binding.
+const PRECEDENCE: [&[&str]; 6] = [
+    &["|"],
+    &["^"],
+    &["&"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+/// Assembles `source`, returning the bytes of the program from its first
+/// address, which is `0x200` unless the source starts with an `ORG`.
+/// `name` is used in errors and to find included files.
+///
+/// ```
+/// let program = chip8::asm::assemble("loop.s", "start: ADD V0, 1\n JP start").unwrap();
+/// assert_eq!(program, [0x70, 0x01, 0x12, 0x00]);
+/// ```
+pub fn assemble(name: &str, source: &str) -> Result<Vec<u8>, AsmError> {
+    let path = Path::new(name);
+    let mut assembler = Assembler {
+        statements: Vec::new(),
+        symbols: HashMap::new(),
+        origin: None,
+        pc: DEFAULT_ORIGIN as u32,
+        includes: vec![fs::canonicalize(path).unwrap_or_else(|_| path.into())],
+    };
+    assembler.read(path, source)?;
+    assembler.finish()
+}
+
+struct Assembler {
+    statements: Vec<Statement>,
+    symbols: HashMap<String, Symbol>,
+    /// Address of the first byte of the program, once known.
+    origin: Option<u16>,
+    /// Address of the next statement, up to the end of the address space.
+    pc: u32,
+    /// Files being read, to catch files including themselves.
+    includes: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+struct Location {
+    file: Rc<str>,
+    line: usize,
+}
+
+impl Location {
+    fn error(&self, message: String) -> AsmError {
+        AsmError {
+            file: self.file.to_string(),
+            line: self.line,
+            message,
+        }
+    }
+}
+
+struct Statement {
+    location: Location,
+    addr: u16,
+    kind: StatementKind,
+}
+
+enum StatementKind {
+    Instruction {
+        mnemonic: String,
+        operands: Vec<Operand>,
+    },
+    /// `db` with a width of one byte or `dw` with two.
+    Data { width: usize, items: Vec<Item> },
+}
+
+enum Item {
+    Expr(Expr),
+    Str(String),
+}
+
+enum Symbol {
+    Label(u16),
+    /// Evaluated where it is used, so constants may refer to labels and
+    /// constants defined further down.
+    Constant(Expr),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(i64),
+    Str(String),
+    Punct(&'static str),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Number(i64),
+    Symbol(String),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Reg(u8),
+    I,
+    /// `[I]`, the memory `I` points at.
+    IndirectI,
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    B,
+    R,
+    /// `LONG addr`, the 16-bit address of `F000 NNNN`.
+    Long(Expr),
+    Expr(Expr),
+}
+
+impl Assembler {
+    /// First pass over a file: defines the labels and lays out the
+    /// statements, which are encoded by [`Assembler::finish`].
+    fn read(&mut self, path: &Path, source: &str) -> Result<(), AsmError> {
+        let file: Rc<str> = path.display().to_string().into();
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for (i, text) in source.lines().enumerate() {
+            let location = Location {
+                file: Rc::clone(&file),
+                line: i + 1,
+            };
+            let Some(include) = self
+                .line(&location, text)
+                .map_err(|message| location.error(message))?
+            else {
+                continue;
+            };
+
+            let path = dir.join(include);
+            let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+            if self.includes.contains(&canonical) {
+                let message = format!("{} is already being included", path.display());
+                return Err(location.error(message));
+            }
+            let source = fs::read_to_string(&path)
+                .map_err(|err| location.error(format!("cannot read {}: {err}", path.display())))?;
+            self.includes.push(canonical);
+            self.read(&path, &source)?;
+            self.includes.pop();
+        }
+        Ok(())
+    }
+
+    /// Reads one line, returning the file it includes if any.
+    fn line(&mut self, location: &Location, text: &str) -> Result<Option<String>, String> {
+        let tokens = lex(text)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            pos: 0,
+        };
+        while let [Token::Ident(name), Token::Punct(":"), ..] = parser.rest() {
+            let addr = u16::try_from(self.pc)
+                .map_err(|_| format!("'{name}' is past the end of memory"))?;
+            self.define(name, Symbol::Label(addr))?;
+            parser.pos += 2;
+        }
+        let word = match parser.next() {
+            None => return Ok(None),
+            Some(Token::Ident(word)) => word.clone(),
+            Some(_) => return Err(String::from("expected an instruction or directive")),
+        };
+        if matches!(parser.peek(), Some(Token::Ident(equ)) if equ.eq_ignore_ascii_case("EQU")) {
+            parser.pos += 1;
+            let value = parser.expr()?;
+            parser.end()?;
+            self.define(&word, Symbol::Constant(value))?;
+            return Ok(None);
+        }
+
+        let mnemonic = word.to_ascii_uppercase();
+        match mnemonic.as_str() {
+            "ORG" => {
+                let expr = parser.expr()?;
+                parser.end()?;
+                let addr = self.evaluate(&expr, 0)?;
+                self.org(addr)?;
+            }
+            "INCLUDE" => {
+                let Some(Token::Str(path)) = parser.next() else {
+                    return Err(String::from("expected a file name in quotes"));
+                };
+                let path = path.clone();
+                parser.end()?;
+                return Ok(Some(path));
+            }
+            "DB" | "DW" => {
+                let width = if mnemonic == "DB" { 1 } else { 2 };
+                let items = parser.items()?;
+                let mut size = 0;
+                for item in &items {
+                    size += match item {
+                        Item::Expr(_) => width,
+                        Item::Str(s) if width == 1 => s.len(),
+                        Item::Str(_) => return Err(String::from("strings are only allowed in db")),
+                    };
+                }
+                self.push(location, size, StatementKind::Data { width, items })?;
+            }
+            _ => {
+                let operands = parser.operands()?;
+                let size = match operands.as_slice() {
+                    [Operand::I, Operand::Long(_)] => 4,
+                    _ => 2,
+                };
+                let kind = StatementKind::Instruction { mnemonic, operands };
+                self.push(location, size, kind)?;
+            }
+        }
+        Ok(None)
+    }
+
+    fn define(&mut self, name: &str, symbol: Symbol) -> Result<(), String> {
+        if self.symbols.contains_key(name) {
+            return Err(format!("'{name}' is already defined"));
+        }
+        self.symbols.insert(name.to_string(), symbol);
+        Ok(())
+    }
+
+    fn org(&mut self, addr: i64) -> Result<(), String> {
+        let addr = u16::try_from(addr).map_err(|_| format!("ORG {addr} is out of memory"))?;
+        if self.origin.is_some() && (addr as u32) < self.pc {
+            return Err(format!(
+                "ORG #{addr:03X} would go back over the code before it at #{:03X}",
+                self.pc
+            ));
+        }
+        self.pc = addr as u32;
+        Ok(())
+    }
+
+    fn push(
+        &mut self,
+        location: &Location,
+        size: usize,
+        kind: StatementKind,
+    ) -> Result<(), String> {
+        if self.pc as usize + size > 0x10000 {
+            return Err(String::from("the program runs past the end of memory"));
+        }
+        self.origin.get_or_insert(self.pc as u16);
+        self.statements.push(Statement {
+            location: location.clone(),
+            addr: self.pc as u16,
+            kind,
+        });
+        self.pc += size as u32;
+        Ok(())
+    }
+
+    /// Second pass: encodes the statements now that all labels are known.
+    fn finish(self) -> Result<Vec<u8>, AsmError> {
+        let origin = self.origin.unwrap_or(DEFAULT_ORIGIN);
+        let mut program = Vec::new();
+        for statement in &self.statements {
+            // The gaps left by ORG are filled with zeros
+            program.resize((statement.addr - origin) as usize, 0);
+            self.encode(statement, &mut program)
+                .map_err(|message| statement.location.error(message))?;
+        }
+        Ok(program)
+    }
+
+    fn encode(&self, statement: &Statement, out: &mut Vec<u8>) -> Result<(), String> {
+        match &statement.kind {
+            StatementKind::Data { width, items } => {
+                for item in items {
+                    match item {
+                        Item::Expr(expr) => {
+                            let value = self.value(expr, 8 * *width as u32, true)?;
+                            out.extend_from_slice(&value.to_be_bytes()[2 - width..]);
+                        }
+                        Item::Str(s) => out.extend_from_slice(s.as_bytes()),
+                    }
+                }
+            }
+            StatementKind::Instruction { mnemonic, operands } => {
+                let op = self.instruction(mnemonic, operands)?;
+                let bytes = op
+                    .encode()
+                    .map_err(|err| format!("cannot encode {op}: {err}"))?;
+                out.extend_from_slice(&bytes);
+            }
+        }
+        Ok(())
+    }
+
+    fn instruction(&self, mnemonic: &str, operands: &[Operand]) -> Result<Op, String> {
+        use Operand::*;
+        let nibble = |expr| self.value(expr, 4, false).map(|value| value as u8);
+        let byte = |expr| self.value(expr, 8, true).map(|value| value as u8);
+        let addr = |expr| self.value(expr, 12, false);
+
+        let op = match (mnemonic, operands) {
+            ("CLS", []) => Op::Clear,
+            ("RET", []) => Op::Return,
+            ("SCD", [Expr(n)]) => Op::ScrollDown(nibble(n)?),
+            ("SCU", [Expr(n)]) => Op::ScrollUp(nibble(n)?),
+            ("SCR", []) => Op::ScrollRight,
+            ("SCL", []) => Op::ScrollLeft,
+            ("EXIT", []) => Op::Exit,
+            ("LOW", []) => Op::LowRes,
+            ("HIGH", []) => Op::HighRes,
+            ("JP", [Expr(a)]) => Op::AbsJump(addr(a)?),
+            ("JP", [Reg(0), Expr(a)]) => Op::OffsetJump(addr(a)?),
+            ("CALL", [Expr(a)]) => Op::Call(addr(a)?),
+            ("SE", [Reg(x), Expr(kk)]) => Op::SkipEqVal(*x, byte(kk)?),
+            ("SE", [Reg(x), Reg(y)]) => Op::SkipEqReg(*x, *y),
+            ("SNE", [Reg(x), Expr(kk)]) => Op::SkipNeqVal(*x, byte(kk)?),
+            ("SNE", [Reg(x), Reg(y)]) => Op::SkipNeqReg(*x, *y),
+            ("SAVE", [Reg(x), Reg(y)]) => Op::SaveRange(*x, *y),
+            ("LOAD", [Reg(x), Reg(y)]) => Op::LoadRange(*x, *y),
+            ("LD", [Reg(x), Expr(kk)]) => Op::SetVal(*x, byte(kk)?),
+            ("LD", [Reg(x), Reg(y)]) => Op::Mov(*x, *y),
+            ("LD", [Reg(x), K]) => Op::GetKey(*x),
+            ("LD", [Reg(x), Dt]) => Op::GetDelay(*x),
+            ("LD", [Dt, Reg(x)]) => Op::SetDelay(*x),
+            ("LD", [St, Reg(x)]) => Op::SetSoundTimer(*x),
+            ("LD", [I, Expr(a)]) => Op::SetIndex(addr(a)?),
+            ("LD", [I, Long(a)]) => Op::LongIndex(self.value(a, 16, false)?),
+            ("LD", [F, Reg(x)]) => Op::SetSpriteI(*x),
+            ("LD", [Hf, Reg(x)]) => Op::SetBigSpriteI(*x),
+            ("LD", [B, Reg(x)]) => Op::DecimalRepr(*x),
+            ("LD", [IndirectI, Reg(x)]) => Op::DumpRegisters(*x),
+            ("LD", [Reg(x), IndirectI]) => Op::LoadRegisters(*x),
+            ("LD", [R, Reg(x)]) => Op::DumpFlags(*x),
+            ("LD", [Reg(x), R]) => Op::LoadFlags(*x),
+            ("ADD", [Reg(x), Expr(kk)]) => Op::AddVal(*x, byte(kk)?),
+            ("ADD", [Reg(x), Reg(y)]) => Op::Add(*x, *y),
+            ("ADD", [I, Reg(x)]) => Op::IncrIndex(*x),
+            ("OR", [Reg(x), Reg(y)]) => Op::Or(*x, *y),
+            ("AND", [Reg(x), Reg(y)]) => Op::And(*x, *y),
+            ("XOR", [Reg(x), Reg(y)]) => Op::Xor(*x, *y),
+            ("SUB", [Reg(x), Reg(y)]) => Op::Sub(*x, *y),
+            ("SUBN", [Reg(x), Reg(y)]) => Op::SubN(*x, *y),
+            ("SHR", [Reg(x)]) => Op::Shr(*x, *x),
+            ("SHR", [Reg(x), Reg(y)]) => Op::Shr(*x, *y),
+            ("SHL", [Reg(x)]) => Op::Shl(*x, *x),
+            ("SHL", [Reg(x), Reg(y)]) => Op::Shl(*x, *y),
+            ("RND", [Reg(x), Expr(kk)]) => Op::Rand(*x, byte(kk)?),
+            ("DRW", [Reg(x), Reg(y), Expr(n)]) => Op::Draw(*x, *y, nibble(n)?),
+            ("SKP", [Reg(x)]) => Op::SkipKey(*x),
+            ("SKNP", [Reg(x)]) => Op::SkipNoKey(*x),
+            ("PLANE", [Expr(n)]) => Op::SelectPlanes(nibble(n)?),
+            ("AUDIO", []) => Op::LoadAudio,
+            ("PITCH", [Reg(x)]) => Op::SetPitch(*x),
+            (
+                "CLS" | "RET" | "SCD" | "SCU" | "SCR" | "SCL" | "EXIT" | "LOW" | "HIGH" | "JP"
+                | "CALL" | "SE" | "SNE" | "SAVE" | "LOAD" | "LD" | "ADD" | "OR" | "AND" | "XOR"
+                | "SUB" | "SUBN" | "SHR" | "SHL" | "RND" | "DRW" | "SKP" | "SKNP" | "PLANE"
+                | "AUDIO" | "PITCH",
+                _,
+            ) => return Err(format!("invalid operands for {mnemonic}")),
+            _ => return Err(format!("unknown instruction {mnemonic}")),
+        };
+        Ok(op)
+    }
+
+    /// Evaluates an operand that must fit in `bits` bits, or, if `signed`,
+    /// may also be a negative number of that size.
+    fn value(&self, expr: &Expr, bits: u32, signed: bool) -> Result<u16, String> {
+        let value = self.evaluate(expr, 0)?;
+        let min = if signed { -(1 << (bits - 1)) } else { 0 };
+        if value < min || value >= 1 << bits {
+            return Err(format!("{value} does not fit in {bits} bits"));
+        }
+        Ok((value & ((1 << bits) - 1)) as u16)
+    }
+
+    fn evaluate(&self, expr: &Expr, depth: usize) -> Result<i64, String> {
+        let value = match expr {
+            Expr::Number(n) => *n,
+            Expr::Symbol(name) => match self.symbols.get(name) {
+                Some(Symbol::Label(addr)) => *addr as i64,
+                Some(Symbol::Constant(expr)) if depth < MAX_DEPTH => {
+                    self.evaluate(expr, depth + 1)?
+                }
+                Some(Symbol::Constant(_)) => {
+                    return Err(format!("'{name}' is defined in terms of itself"))
+                }
+                None => return Err(format!("undefined symbol '{name}'")),
+            },
+            Expr::Unary(op, operand) => {
+                let operand = self.evaluate(operand, depth)?;
+                match *op {
+                    "-" => operand.wrapping_neg(),
+                    "~" => !operand,
+                    _ => operand,
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let (lhs, rhs) = (self.evaluate(lhs, depth)?, self.evaluate(rhs, depth)?);
+                match *op {
+                    "+" => lhs.wrapping_add(rhs),
+                    "-" => lhs.wrapping_sub(rhs),
+                    "*" => lhs.wrapping_mul(rhs),
+                    "/" | "%" if rhs == 0 => return Err(String::from("division by zero")),
+                    "/" => lhs.wrapping_div(rhs),
+                    "%" => lhs.wrapping_rem(rhs),
+                    "&" => lhs & rhs,
+                    "|" => lhs | rhs,
+                    "^" => lhs ^ rhs,
+                    _ => {
+                        let shifted = u32::try_from(rhs).ok().and_then(|rhs| match *op {
+                            "<<" => lhs.checked_shl(rhs),
+                            _ => lhs.checked_shr(rhs),
+                        });
+                        shifted.ok_or_else(|| format!("cannot shift by {rhs}"))?
+                    }
+                }
+            }
+        };
+        Ok(value)
+    }
+}
+
+fn lex(line: &str) -> Result<Vec<Token>, String> {
+    let word_len = |s: &str| {
+        s.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+            .unwrap_or(s.len())
+    };
+
+    let mut tokens = Vec::new();
+    let mut rest = line.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = match c {
+            ';' => break,
+            '"' => {
+                let end = rest[1..].find('"').ok_or("unterminated string")?;
+                tokens.push(Token::Str(rest[1..=end].to_string()));
+                end + 2
+            }
+            '#' => {
+                let len = word_len(&rest[1..]);
+                let digits = &rest[1..=len];
+                let value = i64::from_str_radix(digits, 16)
+                    .map_err(|_| format!("invalid hexadecimal number '#{digits}'"))?;
+                tokens.push(Token::Number(value));
+                len + 1
+            }
+            c if c.is_ascii_alphanumeric() || c == '_' || c == '.' => {
+                let len = word_len(rest);
+                let word = &rest[..len];
+                if c.is_ascii_digit() {
+                    tokens.push(Token::Number(parse_number(word)?));
+                } else {
+                    tokens.push(Token::Ident(word.to_string()));
+                }
+                len
+            }
+            _ => {
+                let punct = PUNCTUATION
+                    .iter()
+                    .find(|punct| rest.starts_with(*punct))
+                    .ok_or_else(|| format!("unexpected character '{c}'"))?;
+                tokens.push(Token::Punct(punct));
+                punct.len()
+            }
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+fn parse_number(word: &str) -> Result<i64, String> {
+    let lower = word.to_ascii_lowercase();
+    let parsed = if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16)
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2)
+    } else {
+        lower.parse()
+    };
+    parsed.map_err(|_| format!("invalid number '{word}'"))
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a [Token] {
+        &self.tokens[self.pos..]
+    }
+
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<&'a Token> {
+        let token = self.peek();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Punct(p)) if *p == punct);
+        self.pos += found as usize;
+        found
+    }
+
+    fn end(&self) -> Result<(), String> {
+        match self.peek() {
+            None => Ok(()),
+            Some(_) => Err(String::from("unexpected text at the end of the line")),
+        }
+    }
+
+    /// Comma-separated operands up to the end of the line.
+    fn operands(&mut self) -> Result<Vec<Operand>, String> {
+        let mut operands = Vec::new();
+        if self.peek().is_none() {
+            return Ok(operands);
+        }
+        loop {
+            operands.push(self.operand()?);
+            if !self.eat(",") {
+                self.end()?;
+                return Ok(operands);
+            }
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        if self.eat("[") {
+            if !matches!(self.next(), Some(Token::Ident(i)) if i.eq_ignore_ascii_case("I"))
+                || !self.eat("]")
+            {
+                return Err(String::from("expected [I]"));
+            }
+            return Ok(Operand::IndirectI);
+        }
+        if let Some(Token::Ident(name)) = self.peek() {
+            let name = name.to_ascii_uppercase();
+            if name == "LONG" {
+                self.pos += 1;
+                return Ok(Operand::Long(self.expr()?));
+            }
+            let register = match name.as_str() {
+                "I" => Some(Operand::I),
+                "DT" => Some(Operand::Dt),
+                "ST" => Some(Operand::St),
+                "K" => Some(Operand::K),
+                "F" => Some(Operand::F),
+                "HF" => Some(Operand::Hf),
+                "B" => Some(Operand::B),
+                "R" => Some(Operand::R),
+                _ => name
+                    .strip_prefix('V')
+                    .filter(|digit| digit.len() == 1)
+                    .and_then(|digit| u8::from_str_radix(digit, 16).ok())
+                    .map(Operand::Reg),
+            };
+            // Otherwise the name starts an expression such as `B + 1`
+            let alone = matches!(self.rest(), [_] | [_, Token::Punct(","), ..]);
+            if let Some(register) = register.filter(|_| alone) {
+                self.pos += 1;
+                return Ok(register);
+            }
+        }
+        Ok(Operand::Expr(self.expr()?))
+    }
+
+    /// Comma-separated expressions and strings of a `db` or `dw`.
+    fn items(&mut self) -> Result<Vec<Item>, String> {
+        let mut items = Vec::new();
+        loop {
+            match self.peek() {
+                Some(Token::Str(s)) => {
+                    self.pos += 1;
+                    items.push(Item::Str(s.clone()));
+                }
+                _ => items.push(Item::Expr(self.expr()?)),
+            }
+            if !self.eat(",") {
+                self.end()?;
+                return Ok(items);
+            }
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr, String> {
+        self.binary(0)
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        let Some(operators) = PRECEDENCE.get(level) else {
+            return self.unary();
+        };
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(&Token::Punct(op)) = self.peek() {
+            if !operators.contains(&op) {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(&Token::Punct(op @ ("-" | "~" | "+"))) => {
+                Ok(Expr::Unary(op, Box::new(self.unary()?)))
+            }
+            Some(Token::Punct("(")) => {
+                let expr = self.expr()?;
+                if !self.eat(")") {
+                    return Err(String::from("expected )"));
+                }
+                Ok(expr)
+            }
+            Some(Token::Number(n)) => Ok(Expr::Number(*n)),
+            Some(Token::Ident(name)) => Ok(Expr::Symbol(name.clone())),
+            _ => Err(String::from("expected an expression")),
+        }
+    }
+}
+
+/// An error in the source, with the file and line it was found at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+impl error::Error for AsmError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::Disassembly;
+
+    const SOURCE: &str = "
+start:  LD I, sprite
+        LD V0, 10
+loop:   DRW V0, V1, 2
+        ADD V0, 1
+        CALL sub
+        JP loop
+sub:    RET
+sprite: db #F0, #90
+";
+
+    #[test]
+    fn assembles_instructions_and_data() {
+        let program = assemble("test.s", SOURCE).unwrap();
+        assert_eq!(
+            program,
+            [
+                0xA2, 0x0E, 0x60, 0x0A, 0xD0, 0x12, 0x70, 0x01, 0x22, 0x0C, 0x12, 0x04, 0x00, 0xEE,
+                0xF0, 0x90
+            ]
+        );
+    }
+
+    #[test]
+    fn disassembly_assembles_back() {
+        let mut rng = fastrand::Rng::with_seed(8);
+        let random: Vec<u8> = (0..1024).map(|_| rng.u8(..)).collect();
+        let programs = [assemble("test.s", SOURCE).unwrap(), random];
+        for program in programs {
+            for load_address in [0x200, 0x600] {
+                let listing = Disassembly::new(&program, load_address).to_string();
+                let assembled = assemble("listing.s", &listing).unwrap();
+                assert_eq!(assembled, program, "{listing}");
+            }
+        }
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let err = assemble("test.s", "start: JP start\n  JP nowhere").unwrap_err();
+        assert_eq!(err.to_string(), "test.s:2: undefined symbol 'nowhere'");
+    }
+
+    #[test]
+    fn labels_must_fit_into_memory() {
+        let source = "ORG #FFFE\nlast: CLS\nend:";
+        let err = assemble("test.s", source).unwrap_err();
+        assert_eq!(err.to_string(), "test.s:3: 'end' is past the end of memory");
+
+        let program = assemble("test.s", "ORG #FFFE\nlast: dw last").unwrap();
+        assert_eq!(program, [0xFF, 0xFE]);
+    }
+}
@@ -0,0 +1,287 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::{error, fmt};
+
+/// Sample rate used unless the frontend asks for another one.
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+/// Sample rates accepted by [`Audio`] and [`WavWriter`], in Hz.
+pub const SAMPLE_RATES: RangeInclusive<u32> = 1_000..=384_000;
+
+/// XO-CHIP sound generator. While the sound timer is running it loops over a
+/// 128-bit pattern, one bit per step, at a rate controlled by the pitch
+/// register: `4000 * 2^((pitch - 64) / 48)` bits per second.
+#[derive(Clone)]
+pub struct Audio {
+    pub(crate) pattern: [u8; PATTERN_SIZE],
+    pub(crate) pitch: u8,
+    pub(crate) sample_rate: u32,
+    /// Playback position within the pattern, in bits.
+    pub(crate) position: f64,
+}
+
+pub const PATTERN_SIZE: usize = 16;
+const PATTERN_BITS: f64 = (PATTERN_SIZE * 8) as f64;
+
+/// Peak amplitude of the generated wave, leaving some headroom.
+const AMPLITUDE: f32 = 0.25;
+
+impl Audio {
+    /// A square wave of 250 Hz at the default pitch, standing in for the
+    /// plain buzzer of the older platforms until a ROM loads its own pattern.
+    const DEFAULT_PATTERN: [u8; PATTERN_SIZE] = [
+        0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, //
+        0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00,
+    ];
+    const DEFAULT_PITCH: u8 = 64;
+
+    /// A generator at [`DEFAULT_SAMPLE_RATE`].
+    pub fn new() -> Self {
+        Audio {
+            pattern: Self::DEFAULT_PATTERN,
+            pitch: Self::DEFAULT_PITCH,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            position: 0.0,
+        }
+    }
+
+    #[inline]
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) -> Result<(), InvalidSampleRate> {
+        self.sample_rate = check_sample_rate(sample_rate)?;
+        Ok(())
+    }
+
+    /// Replaces the pattern, as done by `F002`.
+    pub fn load_pattern(&mut self, pattern: &[u8; PATTERN_SIZE]) {
+        self.pattern = *pattern;
+    }
+
+    /// Sets the pitch register, as done by `Fx3A`.
+    pub fn set_pitch(&mut self, pitch: u8) {
+        self.pitch = pitch;
+    }
+
+    /// Pattern bits played per second at the current pitch.
+    pub fn playback_rate(&self) -> f64 {
+        4000.0 * 2f64.powf((self.pitch as f64 - 64.0) / 48.0)
+    }
+
+    /// Fills `out` with mono samples, producing silence while `active` is
+    /// false.
+    pub fn render(&mut self, active: bool, out: &mut [f32]) {
+        if !active {
+            out.fill(0.0);
+            return;
+        }
+
+        let step = self.playback_rate() / self.sample_rate as f64;
+        for sample in out.iter_mut() {
+            let bit = self.position as usize;
+            let on = self.pattern[bit / 8] & (0x80 >> (bit % 8)) != 0;
+            *sample = if on { AMPLITUDE } else { -AMPLITUDE };
+            self.position = (self.position + step) % PATTERN_BITS;
+        }
+    }
+}
+
+impl Default for Audio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A sample rate outside of [`SAMPLE_RATES`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidSampleRate(pub u32);
+
+impl fmt::Display for InvalidSampleRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sample rate of {} Hz is not between {} and {} Hz",
+            self.0,
+            SAMPLE_RATES.start(),
+            SAMPLE_RATES.end()
+        )
+    }
+}
+
+impl error::Error for InvalidSampleRate {}
+
+fn check_sample_rate(sample_rate: u32) -> Result<u32, InvalidSampleRate> {
+    if SAMPLE_RATES.contains(&sample_rate) {
+        Ok(sample_rate)
+    } else {
+        Err(InvalidSampleRate(sample_rate))
+    }
+}
+
+/// Writes mono samples to a 16-bit PCM WAV file.
+pub struct WavWriter {
+    file: BufWriter<File>,
+    sample_rate: u32,
+    samples: u32,
+}
+
+impl WavWriter {
+    const HEADER_SIZE: u32 = 44;
+    /// The most samples whose size fits into the header.
+    const MAX_SAMPLES: u32 = (u32::MAX - Self::HEADER_SIZE + 8) / 2;
+
+    /// Creates the file, failing with [`io::ErrorKind::InvalidInput`] if
+    /// `sample_rate` is outside of [`SAMPLE_RATES`].
+    pub fn create(path: impl AsRef<Path>, sample_rate: u32) -> io::Result<Self> {
+        let sample_rate = check_sample_rate(sample_rate)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let mut writer = WavWriter {
+            file: BufWriter::new(File::create(path)?),
+            sample_rate,
+            samples: 0,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let sample_rate = self.sample_rate;
+        let data_size = self.samples * 2;
+        let f = &mut self.file;
+        f.write_all(b"RIFF")?;
+        f.write_all(&(Self::HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+        f.write_all(b"WAVEfmt ")?;
+        f.write_all(&16u32.to_le_bytes())?; // fmt chunk size
+        f.write_all(&1u16.to_le_bytes())?; // PCM
+        f.write_all(&1u16.to_le_bytes())?; // mono
+        f.write_all(&sample_rate.to_le_bytes())?;
+        f.write_all(&(sample_rate * 2).to_le_bytes())?; // byte rate
+        f.write_all(&2u16.to_le_bytes())?; // block align
+        f.write_all(&16u16.to_le_bytes())?; // bits per sample
+        f.write_all(b"data")?;
+        f.write_all(&data_size.to_le_bytes())
+    }
+
+    /// Appends samples, failing once the file would outgrow the 4 GiB the
+    /// header can describe.
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        let total = u32::try_from(samples.len())
+            .ok()
+            .and_then(|len| self.samples.checked_add(len))
+            .filter(|&total| total <= Self::MAX_SAMPLES)
+            .ok_or_else(|| io::Error::other("the WAV file is full"))?;
+        for &sample in samples {
+            let pcm = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.file.write_all(&pcm.to_le_bytes())?;
+        }
+        self.samples = total;
+        Ok(())
+    }
+
+    /// Fills in the chunk sizes now that the amount of audio is known.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+
+    const ON: f32 = AMPLITUDE;
+    const OFF: f32 = -AMPLITUDE;
+
+    /// A generator playing one pattern bit per sample at the default pitch.
+    fn audio(pattern: [u8; PATTERN_SIZE]) -> Audio {
+        let mut audio = Audio::new();
+        audio.set_sample_rate(4000).unwrap();
+        audio.load_pattern(&pattern);
+        audio
+    }
+
+    #[test]
+    fn plays_the_pattern_in_a_loop() {
+        let mut pattern = [0; PATTERN_SIZE];
+        pattern[0] = 0b1011_0000;
+        pattern[PATTERN_SIZE - 1] = 0b0000_0001;
+        let mut audio = audio(pattern);
+
+        let mut out = [0.0; 130];
+        audio.render(true, &mut out);
+        assert_eq!(out[..5], [ON, OFF, ON, ON, OFF]);
+        assert_eq!(out[126..], [OFF, ON, ON, OFF]);
+
+        // Silence does not advance the pattern
+        audio.render(false, &mut out);
+        assert!(out.iter().all(|&sample| sample == 0.0));
+        audio.render(true, &mut out[..2]);
+        assert_eq!(out[..2], [ON, ON]);
+    }
+
+    #[test]
+    fn pitch_sets_the_playback_rate() {
+        let mut audio = audio([0b1010_1010; PATTERN_SIZE]);
+        for (pitch, rate) in [(64, 4000.0), (112, 8000.0), (16, 2000.0), (88, 5656.854)] {
+            audio.set_pitch(pitch);
+            assert!((audio.playback_rate() - rate).abs() < 0.001, "{pitch}");
+        }
+
+        // Twice the rate skips every other bit
+        audio.set_pitch(112);
+        let mut out = [0.0; 4];
+        audio.render(true, &mut out);
+        assert_eq!(out, [ON; 4]);
+
+        // Half the rate plays each bit twice
+        audio.set_pitch(16);
+        audio.render(true, &mut out);
+        assert_eq!(out, [ON, ON, OFF, OFF]);
+    }
+
+    #[test]
+    fn rejects_invalid_sample_rates() {
+        let mut audio = Audio::new();
+        for sample_rate in [0, 999, 384_001, u32::MAX] {
+            assert_eq!(
+                audio.set_sample_rate(sample_rate),
+                Err(InvalidSampleRate(sample_rate))
+            );
+        }
+        assert_eq!(audio.sample_rate(), DEFAULT_SAMPLE_RATE);
+        assert_eq!(
+            InvalidSampleRate(0).to_string(),
+            "sample rate of 0 Hz is not between 1000 and 384000 Hz"
+        );
+
+        let path = env::temp_dir().join("chip8-audio-invalid.wav");
+        let err = WavWriter::create(&path, 0).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn writes_wav_files() {
+        let path = env::temp_dir().join(format!("chip8-audio-{}.wav", std::process::id()));
+        let mut wav = WavWriter::create(&path, 384_000).unwrap();
+        wav.write_samples(&[0.0, 1.0, -2.0]).unwrap();
+        wav.finish().unwrap();
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(path).unwrap();
+
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        assert_eq!(data.len(), 50);
+        assert_eq!(u32_at(4), 42);
+        assert_eq!((u32_at(24), u32_at(28)), (384_000, 768_000));
+        assert_eq!(u32_at(40), 6);
+        assert_eq!(data[44..], [0x00, 0x00, 0xFF, 0x7F, 0x01, 0x80]);
+    }
+}
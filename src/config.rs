@@ -0,0 +1,470 @@
+use std::ops::Range;
+use std::{error, fmt};
+
+use crate::emulator::Chip8;
+use crate::quirks::{Quirks, StackLocation};
+use crate::scheduler::Speed;
+
+/// The machine a ROM targets: its memory layout, interpreter quirks and
+/// speed. Settings are checked together by [`Chip8Config::build`].
+///
+/// ```
+/// use chip8::{Chip8Config, Quirks};
+///
+/// // ETI-660 programs start at 0x600
+/// let chip8 = Chip8Config::new()
+///     .memory_size(0x1000)
+///     .load_address(0x600)
+///     .quirks(Quirks::CHIP8)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chip8Config {
+    /// Set by [`Chip8Config::memory_size`], or else the platform's.
+    pub(crate) memory_size: Option<usize>,
+    pub(crate) platform: Platform,
+    pub(crate) load_address: u16,
+    pub(crate) font: [u8; 16 * FONT_CHAR_SIZE],
+    pub(crate) font_address: u16,
+    pub(crate) big_font: [u8; 16 * BIG_FONT_CHAR_SIZE],
+    pub(crate) big_font_address: u16,
+    pub(crate) quirks: Quirks,
+    pub(crate) speed: Speed,
+}
+
+/// XO-CHIP's address space, the most the 16-bit index register can reach.
+pub const MAX_MEMORY_SIZE: usize = 0x10000;
+
+/// The memory of the other platforms.
+pub const DEFAULT_MEMORY_SIZE: usize = 0x1000;
+
+/// The family of interpreters a ROM targets, which decides the quirks and
+/// how much memory there is unless they are set individually.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    Chip8,
+    Chip48,
+    SuperChip,
+    XoChip,
+}
+
+impl Platform {
+    pub const ALL: &'static [(&'static str, Platform)] = &[
+        ("chip8", Platform::Chip8),
+        ("chip48", Platform::Chip48),
+        ("schip", Platform::SuperChip),
+        ("xochip", Platform::XoChip),
+    ];
+
+    pub fn from_name(name: &str) -> Option<Platform> {
+        Self::ALL
+            .iter()
+            .find(|(platform, _)| platform.eq_ignore_ascii_case(name))
+            .map(|&(_, platform)| platform)
+    }
+
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Platform::Chip8 => Quirks::CHIP8,
+            Platform::Chip48 => Quirks::CHIP48,
+            Platform::SuperChip => Quirks::SCHIP,
+            Platform::XoChip => Quirks::XOCHIP,
+        }
+    }
+
+    /// XO-CHIP extends memory to 64 KiB, the others have 4 KiB.
+    pub fn memory_size(self) -> usize {
+        match self {
+            Platform::XoChip => MAX_MEMORY_SIZE,
+            _ => DEFAULT_MEMORY_SIZE,
+        }
+    }
+}
+
+pub const FONT_CHAR_SIZE: usize = 5;
+pub const BIG_FONT_CHAR_SIZE: usize = 10;
+
+impl Chip8Config {
+    /// The CHIP-8 platform: 4 KiB of memory with programs loaded at `0x200`,
+    /// the fonts below them and the `chip8` quirks.
+    pub fn new() -> Self {
+        Chip8Config {
+            memory_size: None,
+            platform: Platform::Chip8,
+            load_address: 0x200,
+            font: FONT,
+            font_address: 0x50,
+            big_font: BIG_FONT,
+            big_font_address: 0xA0,
+            quirks: Quirks::CHIP8,
+            speed: Speed::default(),
+        }
+    }
+
+    pub fn memory_size(mut self, size: usize) -> Self {
+        self.memory_size = Some(size);
+        self
+    }
+
+    /// Where the program is loaded and execution starts.
+    pub fn load_address(mut self, addr: u16) -> Self {
+        self.load_address = addr;
+        self
+    }
+
+    /// The digits used by `Fx29` and where they are kept in memory.
+    pub fn font(mut self, font: [u8; 16 * FONT_CHAR_SIZE], addr: u16) -> Self {
+        self.font = font;
+        self.font_address = addr;
+        self
+    }
+
+    /// The large digits used by `Fx30` and where they are kept in memory.
+    pub fn big_font(mut self, font: [u8; 16 * BIG_FONT_CHAR_SIZE], addr: u16) -> Self {
+        self.big_font = font;
+        self.big_font_address = addr;
+        self
+    }
+
+    /// Targets `platform`, replacing all quirks with its own. Individual
+    /// quirks and the memory size can be changed afterwards.
+    pub fn platform(mut self, platform: Platform) -> Self {
+        self.platform = platform;
+        self.quirks = platform.quirks();
+        self
+    }
+
+    /// Replaces all quirks, including the stack depth, keeping the platform.
+    pub fn quirks(mut self, quirks: Quirks) -> Self {
+        self.quirks = quirks;
+        self
+    }
+
+    pub fn stack_depth(mut self, depth: usize) -> Self {
+        self.quirks.stack_depth = depth;
+        self
+    }
+
+    pub fn stack_location(mut self, location: StackLocation) -> Self {
+        self.quirks.stack_location = location;
+        self
+    }
+
+    /// How fast the CPU runs when driven by a [`Scheduler`](crate::Scheduler).
+    pub fn speed(mut self, speed: Speed) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    /// Checks that the settings fit together and creates the emulator.
+    pub fn build(self) -> Result<Chip8, ConfigError> {
+        self.validate()?;
+        Ok(Chip8::with_config(self))
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let size = self.memory_len();
+        if size == 0 || size > MAX_MEMORY_SIZE {
+            return Err(ConfigError::MemorySize { size });
+        }
+        if self.load_address as usize >= size {
+            return Err(ConfigError::LoadAddressOutOfRange {
+                addr: self.load_address,
+            });
+        }
+
+        let font = self.font_range();
+        let big_font = self.big_font_range();
+        for range in [&font, &big_font] {
+            if range.end > size {
+                return Err(ConfigError::FontOutOfRange {
+                    addr: range.start as u16,
+                    len: range.len(),
+                });
+            }
+        }
+        if font.start < big_font.end && big_font.start < font.end {
+            return Err(ConfigError::FontsOverlap);
+        }
+
+        if let Some(stack) = self.stack_range() {
+            let (top, depth) = (stack.end as u16, self.quirks.stack_depth);
+            if stack.end > size || depth.saturating_mul(2) > stack.end {
+                return Err(ConfigError::StackOutOfRange { top, depth });
+            }
+            let overlaps =
+                |range: &Range<usize>| stack.start < range.end && range.start < stack.end;
+            if overlaps(&font) || overlaps(&big_font) {
+                return Err(ConfigError::StackOverlapsFont { top, depth });
+            }
+            if stack.contains(&(self.load_address as usize)) {
+                return Err(ConfigError::StackOverlapsProgram { top, depth });
+            }
+        }
+
+        let clock_rate = match self.speed {
+            Speed::InstructionsPerFrame(n) | Speed::InstructionsPerSecond(n) => n,
+        };
+        if clock_rate == 0 {
+            return Err(ConfigError::ZeroSpeed);
+        }
+        Ok(())
+    }
+
+    /// The size of memory, explicitly set or the platform's.
+    pub(crate) fn memory_len(&self) -> usize {
+        self.memory_size
+            .unwrap_or_else(|| self.platform.memory_size())
+    }
+
+    /// Where the program is loaded, up to an in-memory stack above it or the
+    /// end of memory.
+    pub(crate) fn program_range(&self) -> Range<usize> {
+        let start = self.load_address as usize;
+        let end = match self.stack_range() {
+            Some(stack) if stack.start >= start => stack.start,
+            _ => self.memory_len(),
+        };
+        start..end.max(start)
+    }
+
+    /// Where return addresses are kept, when they are kept in memory.
+    fn stack_range(&self) -> Option<Range<usize>> {
+        match self.quirks.stack_location {
+            StackLocation::Internal => None,
+            StackLocation::Memory { top } => {
+                let top = top as usize;
+                Some(top.saturating_sub(self.quirks.stack_depth.saturating_mul(2))..top)
+            }
+        }
+    }
+
+    pub(crate) fn font_range(&self) -> Range<usize> {
+        let start = self.font_address as usize;
+        start..start + self.font.len()
+    }
+
+    pub(crate) fn big_font_range(&self) -> Range<usize> {
+        let start = self.big_font_address as usize;
+        start..start + self.big_font.len()
+    }
+}
+
+impl Default for Chip8Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Settings rejected by [`Chip8Config::build`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// Memory must hold at least one byte and at most 64 KiB.
+    MemorySize {
+        size: usize,
+    },
+    LoadAddressOutOfRange {
+        addr: u16,
+    },
+    /// A font of `len` bytes at `addr` reaching past the end of memory.
+    FontOutOfRange {
+        addr: u16,
+        len: usize,
+    },
+    FontsOverlap,
+    /// A stack in memory that does not fit below `top`, or reaches past the
+    /// end of memory.
+    StackOutOfRange {
+        top: u16,
+        depth: usize,
+    },
+    /// A stack in memory sharing bytes with one of the fonts.
+    StackOverlapsFont {
+        top: u16,
+        depth: usize,
+    },
+    /// A stack in memory that covers the load address.
+    StackOverlapsProgram {
+        top: u16,
+        depth: usize,
+    },
+    ZeroSpeed,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::MemorySize { size } => write!(
+                f,
+                "memory size of {size} bytes is not between 1 and {MAX_MEMORY_SIZE} bytes"
+            ),
+            ConfigError::LoadAddressOutOfRange { addr } => {
+                write!(f, "load address {addr:#05x} is outside of memory")
+            }
+            ConfigError::FontOutOfRange { addr, len } => {
+                write!(
+                    f,
+                    "font of {len} bytes at {addr:#05x} does not fit into memory"
+                )
+            }
+            ConfigError::FontsOverlap => write!(f, "the small and big fonts overlap"),
+            ConfigError::StackOutOfRange { top, depth } => write!(
+                f,
+                "stack of {depth} levels below {top:#05x} does not fit into memory"
+            ),
+            ConfigError::StackOverlapsFont { top, depth } => write!(
+                f,
+                "stack of {depth} levels below {top:#05x} overlaps a font"
+            ),
+            ConfigError::StackOverlapsProgram { top, depth } => write!(
+                f,
+                "stack of {depth} levels below {top:#05x} overlaps the program"
+            ),
+            ConfigError::ZeroSpeed => write!(f, "the CPU must run at least one instruction"),
+        }
+    }
+}
+
+impl error::Error for ConfigError {}
+
+/// The hexadecimal digits drawn by `Fx29`, 4x5 pixels each.
+pub const FONT: [u8; 16 * FONT_CHAR_SIZE] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+/// SUPER-CHIP's 8x10 pixel digits, drawn by `Fx30`.
+pub const BIG_FONT: [u8; 16 * BIG_FONT_CHAR_SIZE] = [
+    0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+    0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+    0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+    0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_memory_size_depends_on_the_platform() {
+        for &(name, platform) in Platform::ALL {
+            let chip8 = Chip8Config::new().platform(platform).build().unwrap();
+            let size = match platform {
+                Platform::XoChip => MAX_MEMORY_SIZE,
+                _ => DEFAULT_MEMORY_SIZE,
+            };
+            assert_eq!(chip8.memory().len(), size, "{name}");
+            assert_eq!(chip8.quirks(), &platform.quirks(), "{name}");
+        }
+        let chip8 = Chip8Config::new().memory_size(0x2000).build().unwrap();
+        assert_eq!(chip8.memory().len(), 0x2000);
+    }
+
+    #[test]
+    fn changing_a_quirk_keeps_the_platform() {
+        let xochip = || Chip8Config::new().platform(Platform::XoChip);
+        let configs = [
+            xochip().stack_depth(32),
+            xochip().stack_location(StackLocation::VIP),
+            xochip().quirks(Quirks::CHIP8),
+        ];
+        for config in configs {
+            assert_eq!(config.build().unwrap().memory().len(), MAX_MEMORY_SIZE);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        let error = |config: Chip8Config| config.build().err().unwrap();
+        assert_eq!(
+            error(Chip8Config::new().memory_size(MAX_MEMORY_SIZE + 1)),
+            ConfigError::MemorySize {
+                size: MAX_MEMORY_SIZE + 1
+            }
+        );
+        assert_eq!(
+            error(Chip8Config::new().load_address(0x1000)),
+            ConfigError::LoadAddressOutOfRange { addr: 0x1000 }
+        );
+        assert_eq!(
+            error(Chip8Config::new().font(FONT, 0xFD0)),
+            ConfigError::FontOutOfRange {
+                addr: 0xFD0,
+                len: FONT.len()
+            }
+        );
+        assert_eq!(
+            error(Chip8Config::new().font(FONT, 0xA0)),
+            ConfigError::FontsOverlap
+        );
+        assert_eq!(
+            error(Chip8Config::new().speed(Speed::InstructionsPerSecond(0))),
+            ConfigError::ZeroSpeed
+        );
+    }
+
+    #[test]
+    fn stack_in_memory_must_not_overlap() {
+        let stack = |top| Chip8Config::new().stack_location(StackLocation::Memory { top });
+        let vip = Chip8Config::new().stack_location(StackLocation::VIP);
+        assert!(vip.build().is_ok());
+        assert_eq!(
+            stack(0x1002).build().err(),
+            Some(ConfigError::StackOutOfRange {
+                top: 0x1002,
+                depth: 12
+            })
+        );
+        assert_eq!(
+            stack(0x60).build().err(),
+            Some(ConfigError::StackOverlapsFont {
+                top: 0x60,
+                depth: 12
+            })
+        );
+        assert_eq!(
+            stack(0x210).build().err(),
+            Some(ConfigError::StackOverlapsProgram {
+                top: 0x210,
+                depth: 12
+            })
+        );
+        // Programs are loaded below the stack
+        let mut chip8 = stack(0x300).build().unwrap();
+        assert!(chip8.load_program(&[0; 0xE8]).is_ok());
+        assert!(matches!(
+            chip8.load_program(&[0; 0xE9]),
+            Err(crate::Chip8Error::RomTooLarge {
+                size: 0xE9,
+                max: 0xE8
+            })
+        ));
+    }
+}
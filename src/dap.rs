@@ -0,0 +1,952 @@
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::{fs, thread};
+
+use serde_json::{json, Value};
+
+use crate::debug::{Breakpoints, Condition, Hit, Watch};
+use crate::{Chip8, Chip8Config, Op, Platform, Scheduler};
+
+/// The only thread of the debuggee.
+const THREAD_ID: i64 = 1;
+/// The disassembly of the program, the one source shown to the client.
+const SOURCE_REFERENCE: i64 = 1;
+const REGISTERS_REFERENCE: i64 = 1;
+const TIMERS_REFERENCE: i64 = 2;
+
+/// Serves the Debug Adapter Protocol, as spoken by editors such as VS Code,
+/// over a pair of streams such as stdin and stdout.
+///
+/// The program is shown to the client as a listing with one line per
+/// two-byte word from the load address, so line breakpoints map directly to
+/// addresses. Instruction breakpoints, conditions in the syntax of
+/// [`Condition`], stepping, stack traces, registers and memory reads are
+/// supported. The program runs without a screen.
+pub struct DapServer<W: Write> {
+    output: W,
+    /// Sequence number of the next message sent.
+    seq: i64,
+    session: Option<Session>,
+}
+
+struct Session {
+    chip8: Chip8,
+    scheduler: Scheduler,
+    breakpoints: Breakpoints,
+    /// Breakpoints set by `setBreakpoints` and `setInstructionBreakpoints`,
+    /// which each replace their previous set.
+    line_breakpoints: Vec<usize>,
+    instruction_breakpoints: Vec<usize>,
+    /// Name of the program's file.
+    name: String,
+    load_address: u16,
+    len: usize,
+    stop_on_entry: bool,
+    state: State,
+    /// The error a step stopped at, reported with the next `stopped` event.
+    fault: Option<String>,
+}
+
+enum State {
+    /// Waiting for `configurationDone` before starting.
+    Configuring,
+    Stopped,
+    Running,
+    /// Running until a call at the given depth returns to `ret`.
+    StepOver {
+        ret: u16,
+        depth: usize,
+    },
+    /// Running until the call depth drops below `depth`.
+    StepOut {
+        depth: usize,
+    },
+    Exited,
+}
+
+impl State {
+    fn is_running(&self) -> bool {
+        matches!(
+            self,
+            State::Running | State::StepOver { .. } | State::StepOut { .. }
+        )
+    }
+}
+
+/// Whether to keep serving after a request.
+#[derive(PartialEq, Eq)]
+enum Flow {
+    Continue,
+    Exit,
+}
+
+impl<W: Write> DapServer<W> {
+    pub fn new(output: W) -> Self {
+        DapServer {
+            output,
+            seq: 1,
+            session: None,
+        }
+    }
+
+    /// Serves requests read from `input` until the client disconnects.
+    pub fn run(&mut self, input: impl BufRead + Send + 'static) -> io::Result<()> {
+        let requests = spawn_reader(input);
+        loop {
+            let running = self
+                .session
+                .as_ref()
+                .is_some_and(|session| session.state.is_running());
+
+            // Requests such as `pause` are still handled while running
+            let request = if running {
+                match requests.try_recv() {
+                    Ok(request) => Some(request),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            } else {
+                match requests.recv() {
+                    Ok(request) => Some(request),
+                    Err(_) => return Ok(()),
+                }
+            };
+
+            match request {
+                Some(request) => {
+                    if self.handle(&request)? == Flow::Exit {
+                        return Ok(());
+                    }
+                }
+                None => self.run_frame()?,
+            }
+        }
+    }
+
+    fn handle(&mut self, request: &Value) -> io::Result<Flow> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let args = &request["arguments"];
+        let was_running = self
+            .session
+            .as_ref()
+            .is_some_and(|session| session.state.is_running());
+        let result = match command {
+            "initialize" => {
+                let capabilities = json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsConditionalBreakpoints": true,
+                    "supportsInstructionBreakpoints": true,
+                    "supportsReadMemoryRequest": true,
+                    "supportsDisassembleRequest": true,
+                    "supportsTerminateRequest": true,
+                });
+                self.respond(request, Ok(capabilities))?;
+                self.event("initialized", json!({}))?;
+                return Ok(Flow::Continue);
+            }
+            "launch" => self.launch(args),
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(Value::Null))?;
+                if self.session.take().is_some() {
+                    self.event("terminated", json!({}))?;
+                }
+                return Ok(Flow::Exit);
+            }
+            _ => match self.session.as_mut() {
+                Some(session) => session.handle(command, args),
+                None => Err(String::from("no program launched")),
+            },
+        };
+        self.respond(request, result)?;
+
+        // Some requests change the state, which is reported after the response
+        if let Some(session) = self.session.as_mut() {
+            match (command, &session.state) {
+                ("configurationDone", State::Configuring) => {
+                    if session.stop_on_entry {
+                        session.state = State::Stopped;
+                        self.stopped("entry", None, &[])?;
+                    } else {
+                        session.resume(State::Running);
+                    }
+                }
+                ("pause", State::Stopped) if was_running => self.stopped("pause", None, &[])?,
+                ("stepIn" | "next" | "stepOut", State::Stopped) => match session.fault.take() {
+                    Some(err) => self.stopped("exception", Some(err), &[])?,
+                    None => self.stopped("step", None, &[])?,
+                },
+                ("stepIn" | "next", State::Exited) => self.exited()?,
+                _ => (),
+            }
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let path = args["program"]
+            .as_str()
+            .ok_or("missing 'program' argument")?;
+        let program = fs::read(path).map_err(|err| format!("cannot read {path}: {err}"))?;
+        let platform = match args["quirks"].as_str() {
+            Some(name) => {
+                Platform::from_name(name).ok_or(format!("unknown quirks preset '{name}'"))?
+            }
+            None => Platform::Chip8,
+        };
+
+        let mut config = Chip8Config::new().platform(platform);
+        if let Some(addr) = args["loadAddress"].as_u64() {
+            let addr = u16::try_from(addr).map_err(|_| format!("invalid load address {addr}"))?;
+            config = config.load_address(addr);
+        }
+        let mut chip8 = config.build().map_err(|err| err.to_string())?;
+        chip8
+            .load_program(&program)
+            .map_err(|err| err.to_string())?;
+        let load_address = chip8.load_address();
+        let name = Path::new(path)
+            .file_name()
+            .map_or(path.into(), |name| name.to_string_lossy());
+
+        self.session = Some(Session {
+            scheduler: Scheduler::new(chip8.speed()),
+            chip8,
+            breakpoints: Breakpoints::new(),
+            line_breakpoints: Vec::new(),
+            instruction_breakpoints: Vec::new(),
+            name: format!("{name}.s"),
+            load_address,
+            len: program.len(),
+            stop_on_entry: args["stopOnEntry"].as_bool().unwrap_or(false),
+            state: State::Configuring,
+            fault: None,
+        });
+        Ok(Value::Null)
+    }
+
+    /// Runs a frame of the program, reporting why it stopped if it did.
+    fn run_frame(&mut self) -> io::Result<()> {
+        let Some(session) = self.session.as_mut() else {
+            return Ok(());
+        };
+        let (chip8, scheduler) = (&mut session.chip8, &mut session.scheduler);
+        let breakpoints = &mut session.breakpoints;
+        let state = &session.state;
+
+        let mut hits = Vec::new();
+        let mut done = false;
+        let result = scheduler.run_frame_until(chip8, |chip8, step| {
+            hits = breakpoints.check(chip8, step);
+            done = match *state {
+                State::StepOver { ret, depth } => chip8.pc() == ret && chip8.call_depth() <= depth,
+                State::StepOut { depth } => chip8.call_depth() < depth,
+                _ => false,
+            };
+            done || !hits.is_empty()
+        });
+
+        match result {
+            Err(err) => {
+                session.state = State::Stopped;
+                self.stopped("exception", Some(err.to_string()), &[])
+            }
+            Ok(Some(_)) => {
+                session.state = State::Stopped;
+                if hits.is_empty() {
+                    self.stopped("step", None, &[])
+                } else {
+                    self.stopped("breakpoint", None, &hits)
+                }
+            }
+            Ok(None) if session.chip8.is_halted() => {
+                session.state = State::Exited;
+                self.exited()
+            }
+            Ok(None) => {
+                session.scheduler.wait_for_next_frame();
+                Ok(())
+            }
+        }
+    }
+
+    fn stopped(
+        &mut self,
+        reason: &str,
+        description: Option<String>,
+        hits: &[Hit],
+    ) -> io::Result<()> {
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        if !hits.is_empty() {
+            let ids: Vec<_> = hits.iter().map(|hit| hit.id).collect();
+            let text: Vec<_> = hits.iter().map(Hit::to_string).collect();
+            body["hitBreakpointIds"] = json!(ids);
+            body["description"] = json!(text.join("; "));
+        }
+        if let Some(description) = description {
+            body["description"] = json!(description.clone());
+            body["text"] = json!(description);
+        }
+        self.event("stopped", body)
+    }
+
+    fn exited(&mut self) -> io::Result<()> {
+        self.event("exited", json!({ "exitCode": 0 }))?;
+        self.event("terminated", json!({}))
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(Value::Null) => (),
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        let content = message.to_string();
+        write!(
+            self.output,
+            "Content-Length: {}\r\n\r\n{content}",
+            content.len()
+        )?;
+        self.output.flush()
+    }
+}
+
+impl Session {
+    fn handle(&mut self, command: &str, args: &Value) -> Result<Value, String> {
+        match command {
+            "configurationDone" => Ok(Value::Null),
+            "setBreakpoints" => Ok(self.set_line_breakpoints(args)),
+            "setInstructionBreakpoints" => Ok(self.set_instruction_breakpoints(args)),
+            "setExceptionBreakpoints" => Ok(json!({ "breakpoints": [] })),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "CHIP-8" }] })),
+            "stackTrace" => Ok(self.stack_trace()),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+                { "name": "Timers", "variablesReference": TIMERS_REFERENCE, "expensive": false },
+            ]})),
+            "variables" => Ok(self.variables(args["variablesReference"].as_i64())),
+            "source" => Ok(json!({ "content": self.listing(), "mimeType": "text/x-asm" })),
+            "readMemory" => self.read_memory(args),
+            "disassemble" => self.disassemble(args),
+            "continue" => {
+                self.require_stopped()?;
+                self.resume(State::Running);
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" => {
+                self.require_stopped()?;
+                let pc = self.chip8.pc();
+                match self.chip8.instruction_at(pc) {
+                    Ok(op @ Op::Call(_)) => self.resume(State::StepOver {
+                        ret: pc.wrapping_add(op.size()),
+                        depth: self.chip8.call_depth(),
+                    }),
+                    _ => self.step(),
+                }
+                Ok(Value::Null)
+            }
+            "stepIn" => {
+                self.require_stopped()?;
+                self.step();
+                Ok(Value::Null)
+            }
+            "stepOut" => {
+                self.require_stopped()?;
+                let depth = self.chip8.call_depth();
+                if depth == 0 {
+                    return Err(String::from("not inside a call"));
+                }
+                self.resume(State::StepOut { depth });
+                Ok(Value::Null)
+            }
+            "pause" => {
+                if self.state.is_running() {
+                    self.state = State::Stopped;
+                }
+                Ok(Value::Null)
+            }
+            _ => Err(format!("unsupported request '{command}'")),
+        }
+    }
+
+    fn require_stopped(&self) -> Result<(), String> {
+        match self.state {
+            State::Stopped => Ok(()),
+            State::Exited => Err(String::from("the program has exited")),
+            _ => Err(String::from("the program is not stopped")),
+        }
+    }
+
+    fn resume(&mut self, state: State) {
+        self.breakpoints.sync(&self.chip8);
+        self.scheduler.reset_clock();
+        self.state = state;
+    }
+
+    /// Executes one instruction, leaving the session stopped unless the
+    /// program exits.
+    fn step(&mut self) {
+        self.state = match self.scheduler.step(&mut self.chip8) {
+            Ok(Some(_)) if !self.chip8.is_halted() => State::Stopped,
+            Ok(_) => State::Exited,
+            Err(err) => {
+                self.fault = Some(err.to_string());
+                State::Stopped
+            }
+        };
+    }
+
+    fn set_line_breakpoints(&mut self, args: &Value) -> Value {
+        for id in self.line_breakpoints.drain(..) {
+            self.breakpoints.remove(id);
+        }
+        let requested = args["breakpoints"].as_array().cloned().unwrap_or_default();
+        let mut results = Vec::new();
+        for breakpoint in requested {
+            let line = breakpoint["line"].as_i64().unwrap_or(0);
+            let addr = self.line_address(line);
+            let result = self.add_breakpoint(addr, &breakpoint["condition"], line);
+            if let Some(id) = result["id"].as_u64() {
+                self.line_breakpoints.push(id as usize);
+            }
+            results.push(result);
+        }
+        json!({ "breakpoints": results })
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> Value {
+        for id in self.instruction_breakpoints.drain(..) {
+            self.breakpoints.remove(id);
+        }
+        let requested = args["breakpoints"].as_array().cloned().unwrap_or_default();
+        let mut results = Vec::new();
+        for breakpoint in requested {
+            let reference = breakpoint["instructionReference"]
+                .as_str()
+                .unwrap_or_default();
+            let offset = breakpoint["offset"].as_i64().unwrap_or(0);
+            let addr = parse_reference(reference).and_then(|addr| addr.checked_add(offset));
+            let line = addr.map_or(0, |addr| self.address_line(addr));
+            let result = self.add_breakpoint(addr, &breakpoint["condition"], line);
+            if let Some(id) = result["id"].as_u64() {
+                self.instruction_breakpoints.push(id as usize);
+            }
+            results.push(result);
+        }
+        json!({ "breakpoints": results })
+    }
+
+    fn add_breakpoint(&mut self, addr: Option<i64>, condition: &Value, line: i64) -> Value {
+        let Some(addr) = addr.and_then(|addr| u16::try_from(addr).ok()) else {
+            return json!({ "verified": false, "message": "not an address in memory" });
+        };
+        let condition = match condition
+            .as_str()
+            .filter(|source| !source.trim().is_empty())
+        {
+            Some(source) => match Condition::parse(source) {
+                Ok(condition) => Some(condition),
+                Err(err) => {
+                    return json!({ "verified": false, "message": format!("invalid condition: {err}") })
+                }
+            },
+            None => None,
+        };
+        let id = self.breakpoints.add(Watch::Address(addr), condition);
+        json!({
+            "id": id,
+            "verified": true,
+            "line": line,
+            "instructionReference": format!("{addr:#06x}"),
+        })
+    }
+
+    /// Address of a line of the listing, or `None` past the program.
+    fn line_address(&self, line: i64) -> Option<i64> {
+        let offset = line.checked_sub(1)?.checked_mul(2)?;
+        (0..self.len as i64)
+            .contains(&offset)
+            .then_some(self.load_address as i64 + offset)
+    }
+
+    /// Line of the listing showing `addr`, or 0 outside of the program.
+    fn address_line(&self, addr: i64) -> i64 {
+        let offset = addr - self.load_address as i64;
+        if (0..self.len as i64).contains(&offset) {
+            offset / 2 + 1
+        } else {
+            0
+        }
+    }
+
+    fn source(&self) -> Value {
+        json!({ "name": self.name, "sourceReference": SOURCE_REFERENCE })
+    }
+
+    /// The program as one line per two-byte word.
+    fn listing(&self) -> String {
+        let start = self.load_address as usize;
+        let mem = self.chip8.memory();
+        let mut listing = String::new();
+        for addr in (start..start + self.len).step_by(2) {
+            let word = mem.get(addr..addr + 2).unwrap_or(&[0, 0]);
+            let op = Op::decode(word).map_or(String::new(), |op| op.to_string());
+            listing += &format!("{addr:04X}  {:02X}{:02X}  {op}\n", word[0], word[1]);
+        }
+        listing
+    }
+
+    /// The current instruction, followed by the calls leading to it.
+    fn stack_trace(&self) -> Value {
+        let pc = self.chip8.pc();
+        // Calls are shown at the call instruction, two bytes before the
+        // return address
+        let calls = self.chip8.call_stack();
+        let locations =
+            std::iter::once(pc).chain(calls.iter().rev().map(|ret| ret.wrapping_sub(2)));
+
+        let frames: Vec<_> = locations
+            .enumerate()
+            .map(|(id, addr)| {
+                let op = self
+                    .chip8
+                    .instruction_at(addr)
+                    .map_or(String::from("??"), |op| op.to_string());
+                let line = self.address_line(addr as i64);
+                let mut frame = json!({
+                    "id": id,
+                    "name": format!("{addr:04X}  {op}"),
+                    "line": line,
+                    "column": 0,
+                    "instructionPointerReference": format!("{addr:#06x}"),
+                });
+                if line > 0 {
+                    frame["source"] = self.source();
+                }
+                frame
+            })
+            .collect();
+        json!({ "stackFrames": frames, "totalFrames": frames.len() })
+    }
+
+    fn variables(&self, reference: Option<i64>) -> Value {
+        let chip8 = &self.chip8;
+        let byte = |name: String, value: u8| json!({ "name": name, "value": format!("{value:#04x}"), "variablesReference": 0 });
+        let variables: Vec<_> = match reference {
+            Some(REGISTERS_REFERENCE) => {
+                let mut variables: Vec<_> = chip8
+                    .registers()
+                    .iter()
+                    .enumerate()
+                    .map(|(x, &value)| byte(format!("V{x:X}"), value))
+                    .collect();
+                variables.push(json!({
+                    "name": "I",
+                    "value": format!("{:#06x}", chip8.ireg()),
+                    "variablesReference": 0,
+                    "memoryReference": format!("{:#06x}", chip8.ireg()),
+                }));
+                variables.push(json!({
+                    "name": "PC",
+                    "value": format!("{:#06x}", chip8.pc()),
+                    "variablesReference": 0,
+                    "memoryReference": format!("{:#06x}", chip8.pc()),
+                }));
+                variables
+            }
+            Some(TIMERS_REFERENCE) => vec![
+                byte(String::from("DT"), chip8.delay_timer()),
+                byte(String::from("ST"), chip8.sound_timer()),
+            ],
+            _ => Vec::new(),
+        };
+        json!({ "variables": variables })
+    }
+
+    fn read_memory(&self, args: &Value) -> Result<Value, String> {
+        let reference = args["memoryReference"].as_str().unwrap_or_default();
+        let start = parse_reference(reference)
+            .and_then(|start| start.checked_add(args["offset"].as_i64().unwrap_or(0)))
+            .ok_or("invalid memory reference")?;
+        let count = args["count"].as_i64().unwrap_or(0).max(0);
+        let end = start.checked_add(count).ok_or("invalid memory range")?;
+
+        let mem = self.chip8.memory();
+        let first = start.clamp(0, mem.len() as i64) as usize;
+        let end = end.clamp(0, mem.len() as i64) as usize;
+        let bytes = mem.get(first..end.max(first)).unwrap_or_default();
+        Ok(json!({
+            "address": format!("{start:#06x}"),
+            "data": base64(bytes),
+            "unreadableBytes": count - bytes.len() as i64,
+        }))
+    }
+
+    fn disassemble(&self, args: &Value) -> Result<Value, String> {
+        let reference = args["memoryReference"].as_str().unwrap_or_default();
+        let start = parse_reference(reference)
+            .and_then(|start| start.checked_add(args["offset"].as_i64().unwrap_or(0)))
+            .and_then(|start| {
+                let offset = args["instructionOffset"].as_i64().unwrap_or(0);
+                start.checked_add(offset.checked_mul(2)?)
+            })
+            .ok_or("invalid memory reference")?;
+        let count = args["instructionCount"].as_i64().unwrap_or(0).max(0);
+        // Instructions are at most four bytes long
+        count
+            .checked_mul(4)
+            .and_then(|len| start.checked_add(len))
+            .ok_or("invalid memory range")?;
+
+        let mem = self.chip8.memory();
+        let mut instructions = Vec::new();
+        let mut addr = start;
+        for _ in 0..count {
+            let decoded = usize::try_from(addr)
+                .ok()
+                .and_then(|addr| Some((mem.get(addr..)?, Op::decode(mem.get(addr..)?)?)));
+            let Some((bytes, op)) = decoded else {
+                instructions.push(json!({ "address": format!("{addr:#06x}"), "instruction": "??", "presentationHint": "invalid" }));
+                addr += 2;
+                continue;
+            };
+            let size = op.size() as usize;
+            let hex: String = bytes[..size]
+                .iter()
+                .map(|byte| format!("{byte:02X}"))
+                .collect();
+            let mut instruction = json!({
+                "address": format!("{addr:#06x}"),
+                "instructionBytes": hex,
+                "instruction": op.to_string(),
+            });
+            let line = self.address_line(addr);
+            if line > 0 {
+                instruction["location"] = self.source();
+                instruction["line"] = json!(line);
+            }
+            instructions.push(instruction);
+            addr += size as i64;
+        }
+        Ok(json!({ "instructions": instructions }))
+    }
+}
+
+/// Parses a memory or instruction reference, `0x`-prefixed hexadecimal as
+/// produced by the server, or decimal.
+fn parse_reference(reference: &str) -> Option<i64> {
+    match reference.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok(),
+        None => reference.parse().ok(),
+    }
+}
+
+/// Reads messages on a separate thread, so they can be received while the
+/// program runs.
+fn spawn_reader(mut input: impl BufRead + Send + 'static) -> Receiver<Value> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        while let Ok(Some(message)) = read_message(&mut input) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// Reads a message framed by a `Content-Length` header, or `None` at the end
+/// of the input.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut content_length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            if content_length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let mut content = vec![0; content_length.unwrap_or_default()];
+    input.read_exact(&mut content)?;
+    serde_json::from_slice(&content)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &byte)| n | (byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::env;
+    use std::io::{BufReader, PipeReader, PipeWriter};
+    use std::thread::JoinHandle;
+
+    use super::*;
+
+    /// A client talking to a server on another thread through pipes.
+    struct Client {
+        input: PipeWriter,
+        output: BufReader<PipeReader>,
+        seq: i64,
+        /// Events received while waiting for a response.
+        events: VecDeque<Value>,
+        server: JoinHandle<io::Result<()>>,
+    }
+
+    impl Client {
+        fn new() -> Self {
+            let (request_reader, input) = io::pipe().unwrap();
+            let (output, response_writer) = io::pipe().unwrap();
+            let server = thread::spawn(move || {
+                DapServer::new(response_writer).run(BufReader::new(request_reader))
+            });
+            Client {
+                input,
+                output: BufReader::new(output),
+                seq: 1,
+                events: VecDeque::new(),
+                server,
+            }
+        }
+
+        fn receive(&mut self) -> Value {
+            read_message(&mut self.output)
+                .unwrap()
+                .expect("server hung up")
+        }
+
+        /// Sends a request and returns its response, successful or not.
+        fn try_request(&mut self, command: &str, args: Value) -> Value {
+            let request = json!({
+                "seq": self.seq,
+                "type": "request",
+                "command": command,
+                "arguments": args,
+            })
+            .to_string();
+            write!(
+                self.input,
+                "Content-Length: {}\r\n\r\n{request}",
+                request.len()
+            )
+            .unwrap();
+            self.seq += 1;
+            loop {
+                let message = self.receive();
+                if message["type"] == "response" {
+                    assert_eq!(message["command"], command);
+                    return message;
+                }
+                self.events.push_back(message);
+            }
+        }
+
+        fn request(&mut self, command: &str, args: Value) -> Value {
+            let response = self.try_request(command, args);
+            assert_eq!(response["success"], true, "{response}");
+            response["body"].clone()
+        }
+
+        /// Waits for the next event, which must be `event`.
+        fn event(&mut self, event: &str) -> Value {
+            let message = match self.events.pop_front() {
+                Some(message) => message,
+                None => self.receive(),
+            };
+            assert_eq!(message["event"], event, "{message}");
+            message["body"].clone()
+        }
+
+        fn launch(&mut self, program: &[u8]) {
+            let path = env::temp_dir().join(format!(
+                "chip8-dap-{}-{:?}.ch8",
+                std::process::id(),
+                thread::current().id()
+            ));
+            fs::write(&path, program).unwrap();
+            self.request("initialize", json!({ "adapterID": "chip8" }));
+            self.event("initialized");
+            self.request(
+                "launch",
+                json!({ "program": path.to_str(), "stopOnEntry": true }),
+            );
+            fs::remove_file(path).unwrap();
+        }
+
+        fn pc(&mut self) -> Value {
+            let body = self.request("stackTrace", json!({ "threadId": THREAD_ID }));
+            body["stackFrames"][0]["instructionPointerReference"].clone()
+        }
+
+        fn disconnect(mut self) {
+            self.request("disconnect", json!({}));
+            self.event("terminated");
+            self.server.join().unwrap().unwrap();
+        }
+    }
+
+    /// LD V0, 5; CALL sub; ADD V0, 1; loop: JP loop; sub: LD I, 0x300; RET
+    const PROGRAM: &[u8] = &[
+        0x60, 0x05, 0x22, 0x08, 0x70, 0x01, 0x12, 0x06, 0xA3, 0x00, 0x00, 0xEE,
+    ];
+
+    #[test]
+    fn session() {
+        let mut client = Client::new();
+        client.launch(PROGRAM);
+        let body = client.request(
+            "setBreakpoints",
+            json!({ "source": { "sourceReference": SOURCE_REFERENCE }, "breakpoints": [{ "line": 5 }] }),
+        );
+        assert_eq!(body["breakpoints"][0]["verified"], true);
+        assert_eq!(body["breakpoints"][0]["instructionReference"], "0x0208");
+
+        client.request("configurationDone", json!({}));
+        assert_eq!(client.event("stopped")["reason"], "entry");
+        assert_eq!(client.pc(), "0x0200");
+
+        client.request("next", json!({ "threadId": THREAD_ID }));
+        assert_eq!(client.event("stopped")["reason"], "step");
+        assert_eq!(client.pc(), "0x0202");
+
+        client.request("continue", json!({ "threadId": THREAD_ID }));
+        assert_eq!(client.event("stopped")["reason"], "breakpoint");
+        let body = client.request("stackTrace", json!({ "threadId": THREAD_ID }));
+        assert_eq!(body["totalFrames"], 2);
+        assert_eq!(body["stackFrames"][0]["line"], 5);
+        assert_eq!(
+            body["stackFrames"][1]["instructionPointerReference"],
+            "0x0202"
+        );
+
+        let body = client.request("scopes", json!({ "frameId": 0 }));
+        let reference = body["scopes"][0]["variablesReference"].clone();
+        let body = client.request("variables", json!({ "variablesReference": reference }));
+        assert_eq!(body["variables"][0]["name"], "V0");
+        assert_eq!(body["variables"][0]["value"], "0x05");
+
+        let body = client.request(
+            "readMemory",
+            json!({ "memoryReference": "0x0200", "offset": 2, "count": 4 }),
+        );
+        assert_eq!(body["address"], "0x0202");
+        assert_eq!(body["data"], "IghwAQ==");
+        assert_eq!(body["unreadableBytes"], 0);
+
+        // Stepping over the return lands after the call
+        client.request("next", json!({ "threadId": THREAD_ID }));
+        client.event("stopped");
+        client.request("next", json!({ "threadId": THREAD_ID }));
+        client.event("stopped");
+        assert_eq!(client.pc(), "0x0204");
+
+        client.request("continue", json!({ "threadId": THREAD_ID }));
+        client.request("pause", json!({ "threadId": THREAD_ID }));
+        assert_eq!(client.event("stopped")["reason"], "pause");
+        // Depending on how soon the pause arrived
+        let pc = client.pc();
+        assert!(pc == "0x0204" || pc == "0x0206", "{pc}");
+
+        client.disconnect();
+    }
+
+    #[test]
+    fn step_into_fault() {
+        let mut client = Client::new();
+        // RET with nothing to return to
+        client.launch(&[0x00, 0xEE]);
+        client.request("configurationDone", json!({}));
+        client.event("stopped");
+
+        client.request("stepIn", json!({ "threadId": THREAD_ID }));
+        let body = client.event("stopped");
+        assert_eq!(body["reason"], "exception");
+        assert_eq!(body["text"], "stack underflow at 0x200");
+        assert_eq!(client.pc(), "0x0200");
+
+        client.disconnect();
+    }
+
+    #[test]
+    fn pause_while_stopped() {
+        let mut client = Client::new();
+        client.launch(PROGRAM);
+        client.request("configurationDone", json!({}));
+        client.event("stopped");
+
+        client.request("pause", json!({ "threadId": THREAD_ID }));
+        client.request("threads", json!({}));
+        assert!(client.events.is_empty(), "{:?}", client.events);
+
+        client.disconnect();
+    }
+
+    #[test]
+    fn read_memory_out_of_range() {
+        let mut client = Client::new();
+        client.launch(PROGRAM);
+
+        let body = client.request(
+            "readMemory",
+            json!({ "memoryReference": "0x10000", "count": 4 }),
+        );
+        assert_eq!(body["unreadableBytes"], 4);
+        let response = client.try_request(
+            "readMemory",
+            json!({ "memoryReference": "0x7FFFFFFFFFFFFFFF", "count": 1 }),
+        );
+        assert_eq!(response["success"], false);
+
+        client.disconnect();
+    }
+}
@@ -0,0 +1,841 @@
+use std::ops::RangeInclusive;
+use std::{error, fmt};
+
+use crate::emulator::{AccessKind, Chip8, Step};
+
+/// Something that pauses execution when it happens.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Watch {
+    /// Reaching the instruction at this address, before executing it.
+    Address(u16),
+    /// An instruction reading or writing any byte of the range, as selected
+    /// by `read` and `write`.
+    Memory {
+        range: RangeInclusive<u16>,
+        read: bool,
+        write: bool,
+    },
+    /// An instruction changing the register.
+    Register(Register),
+    /// The condition becoming true, after having been false.
+    Condition,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    V(u8),
+    I,
+    /// Also changes on its own, at every 60 Hz timer period.
+    DelayTimer,
+    SoundTimer,
+}
+
+impl Register {
+    fn read(self, chip8: &Chip8) -> u16 {
+        match self {
+            Register::V(x) => chip8.registers()[x as usize & 0xF] as u16,
+            Register::I => chip8.ireg(),
+            Register::DelayTimer => chip8.delay_timer() as u16,
+            Register::SoundTimer => chip8.sound_timer() as u16,
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Register::V(x) => write!(f, "V{x:X}"),
+            Register::I => write!(f, "I"),
+            Register::DelayTimer => write!(f, "DT"),
+            Register::SoundTimer => write!(f, "ST"),
+        }
+    }
+}
+
+/// A [`Watch`], optionally only firing while a condition holds.
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    pub id: usize,
+    pub watch: Watch,
+    /// Required by [`Watch::Condition`], which fires when it becomes true.
+    pub condition: Option<Condition>,
+    /// The value of a watched register, or whether the condition of a
+    /// [`Watch::Condition`] held, as of the last check.
+    last: Option<u16>,
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.watch {
+            Watch::Address(addr) => write!(f, "break {addr:#05x}")?,
+            Watch::Memory { range, read, write } => {
+                let kind = match (read, write) {
+                    (true, false) => "rwatch",
+                    (false, true) => "watch",
+                    _ => "awatch",
+                };
+                write!(f, "{kind} {:#05x}..={:#05x}", range.start(), range.end())?;
+            }
+            Watch::Register(register) => write!(f, "watch {register}")?,
+            Watch::Condition => {}
+        }
+        match (&self.watch, &self.condition) {
+            (Watch::Condition, Some(condition)) => write!(f, "when {condition}"),
+            (_, Some(condition)) => write!(f, " if {condition}"),
+            (_, None) => Ok(()),
+        }
+    }
+}
+
+/// Why execution paused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hit {
+    /// The breakpoint that fired.
+    pub id: usize,
+    pub cause: Cause,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Cause {
+    Address(u16),
+    Memory {
+        kind: AccessKind,
+        /// Address of the instruction making the access.
+        pc: u16,
+        start: usize,
+        len: usize,
+    },
+    Register {
+        register: Register,
+        old: u16,
+        new: u16,
+    },
+    Condition(String),
+}
+
+impl fmt::Display for Hit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "breakpoint {}: ", self.id)?;
+        match &self.cause {
+            Cause::Address(addr) => write!(f, "reached {addr:#05x}"),
+            Cause::Memory {
+                kind,
+                pc,
+                start,
+                len,
+            } => {
+                let kind = match kind {
+                    AccessKind::Read => "read",
+                    AccessKind::Write => "write",
+                };
+                write!(f, "{kind} of {len} bytes at {start:#05x} by {pc:#05x}")
+            }
+            Cause::Register { register, old, new } => {
+                write!(f, "{register} changed from {old:#04x} to {new:#04x}")
+            }
+            Cause::Condition(condition) => write!(f, "{condition} became true"),
+        }
+    }
+}
+
+/// The breakpoints of a debugging session, checked after every step.
+///
+/// ```
+/// use chip8::debug::{Breakpoints, Condition, Watch};
+/// use chip8::{Chip8, Quirks};
+///
+/// let mut chip8 = Chip8::new(Quirks::CHIP8);
+/// chip8.load_program(&[0x63, 0x10, 0xA4, 0x00, 0x12, 0x04]).unwrap();
+///
+/// let mut breakpoints = Breakpoints::new();
+/// let condition = Condition::parse("v3 == 0x10 && i > 0x300").unwrap();
+/// let id = breakpoints.add(Watch::Condition, Some(condition)).unwrap();
+///
+/// let (_, hits) = breakpoints.run(&mut chip8);
+/// assert_eq!(hits[0].id, id);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Breakpoints {
+    list: Vec<Breakpoint>,
+    next_id: usize,
+}
+
+impl Breakpoints {
+    pub fn new() -> Self {
+        Breakpoints {
+            list: Vec::new(),
+            next_id: 1,
+        }
+    }
+
+    /// Adds a breakpoint and returns its id. Condition watches without a
+    /// condition are rejected.
+    pub fn add(&mut self, watch: Watch, condition: Option<Condition>) -> Option<usize> {
+        if watch == Watch::Condition && condition.is_none() {
+            return None;
+        }
+        let id = self.next_id.max(1);
+        self.next_id = id + 1;
+        self.list.push(Breakpoint {
+            id,
+            watch,
+            condition,
+            last: None,
+        });
+        Some(id)
+    }
+
+    /// Returns whether there was a breakpoint with this id.
+    pub fn remove(&mut self, id: usize) -> bool {
+        let len = self.list.len();
+        self.list.retain(|breakpoint| breakpoint.id != id);
+        self.list.len() != len
+    }
+
+    pub fn clear(&mut self) {
+        self.list.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.list.iter()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// Whether an execution breakpoint is set at `addr`.
+    pub fn has_address(&self, addr: u16) -> bool {
+        self.list
+            .iter()
+            .any(|breakpoint| breakpoint.watch == Watch::Address(addr))
+    }
+
+    /// Records the current values of watched registers and conditions, so
+    /// that only later changes fire. Call this before resuming execution.
+    pub fn sync(&mut self, chip8: &Chip8) {
+        for breakpoint in self.list.iter_mut() {
+            breakpoint.last = match (&breakpoint.watch, &breakpoint.condition) {
+                (Watch::Register(register), _) => Some(register.read(chip8)),
+                (Watch::Condition, Some(condition)) => Some(condition.eval(chip8) as u16),
+                _ => None,
+            };
+        }
+    }
+
+    /// Checks the breakpoints against the state after `step`, returning the
+    /// ones that fired.
+    pub fn check(&mut self, chip8: &Chip8, step: &Step) -> Vec<Hit> {
+        let mut hits = Vec::new();
+        for breakpoint in self.list.iter_mut() {
+            if let Some(cause) = breakpoint.check(chip8, step) {
+                hits.push(Hit {
+                    id: breakpoint.id,
+                    cause,
+                });
+            }
+        }
+        hits
+    }
+
+    /// Steps until a breakpoint fires, or until the program halts, faults or
+    /// blocks on a key press.
+    pub fn run(&mut self, chip8: &mut Chip8) -> (Step, Vec<Hit>) {
+        self.sync(chip8);
+        let mut hits = Vec::new();
+        let step = chip8.run_until(|chip8, step| {
+            hits = self.check(chip8, step);
+            !hits.is_empty()
+        });
+        (step, hits)
+    }
+}
+
+impl Breakpoint {
+    fn check(&mut self, chip8: &Chip8, step: &Step) -> Option<Cause> {
+        let holds = |condition: &Option<Condition>| {
+            condition
+                .as_ref()
+                .is_none_or(|condition| condition.eval(chip8))
+        };
+
+        match &self.watch {
+            Watch::Address(addr) => {
+                (chip8.pc() == *addr && holds(&self.condition)).then_some(Cause::Address(*addr))
+            }
+            Watch::Memory { range, read, write } => {
+                let access = step.access.as_ref()?;
+                let wanted = match access.kind {
+                    AccessKind::Read => *read,
+                    AccessKind::Write => *write,
+                };
+                let overlaps = !access.range.is_empty()
+                    && access.range.start <= *range.end() as usize
+                    && (*range.start() as usize) < access.range.end;
+                (wanted && overlaps && holds(&self.condition)).then(|| Cause::Memory {
+                    kind: access.kind,
+                    pc: step.addr,
+                    start: access.range.start,
+                    len: access.range.len(),
+                })
+            }
+            Watch::Register(register) => {
+                let new = register.read(chip8);
+                let old = self.last.replace(new)?;
+                (old != new && holds(&self.condition)).then_some(Cause::Register {
+                    register: *register,
+                    old,
+                    new,
+                })
+            }
+            Watch::Condition => {
+                let condition = self.condition.as_ref()?;
+                let now = condition.eval(chip8);
+                let before = self.last.replace(now as u16) == Some(1);
+                (now && !before).then(|| Cause::Condition(condition.to_string()))
+            }
+        }
+    }
+}
+
+/// An expression over the machine state such as `v3 == 0x10 && i > 0x300`.
+///
+/// Operands are the registers `v0` to `vf`, `i`, `pc`, `dt`, `st`, the call
+/// depth `sp`, memory bytes `mem[addr]` and numbers, written in decimal or in
+/// hexadecimal with a `0x` or `#` prefix. From lowest to highest precedence,
+/// the operators are `||`, `&&`, the comparisons `== != < <= > >=`, `+ -`
+/// and the unary `! -`. Nonzero values count as true.
+#[derive(Debug, Clone)]
+pub struct Condition {
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Number(i64),
+    Variable(Variable),
+    Memory(Box<Expr>),
+    Not(Box<Expr>),
+    Negate(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Variable {
+    V(u8),
+    I,
+    Pc,
+    DelayTimer,
+    SoundTimer,
+    CallDepth,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+}
+
+impl Condition {
+    pub fn parse(source: &str) -> Result<Condition, ConditionError> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            end: source.len(),
+        };
+        let expr = parser.or()?;
+        if let Some(&(offset, _)) = parser.tokens.get(parser.pos) {
+            return Err(ConditionError::new(offset, "expected an operator"));
+        }
+        Ok(Condition {
+            source: source.trim().to_string(),
+            expr,
+        })
+    }
+
+    pub fn eval(&self, chip8: &Chip8) -> bool {
+        self.expr.eval(chip8) != 0
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Expr {
+    fn eval(&self, chip8: &Chip8) -> i64 {
+        match self {
+            Expr::Number(n) => *n,
+            Expr::Variable(variable) => match *variable {
+                Variable::V(x) => chip8.registers()[x as usize] as i64,
+                Variable::I => chip8.ireg() as i64,
+                Variable::Pc => chip8.pc() as i64,
+                Variable::DelayTimer => chip8.delay_timer() as i64,
+                Variable::SoundTimer => chip8.sound_timer() as i64,
+                Variable::CallDepth => chip8.call_depth() as i64,
+            },
+            // Reading outside of memory gives zero rather than an error
+            Expr::Memory(addr) => usize::try_from(addr.eval(chip8))
+                .ok()
+                .and_then(|addr| chip8.memory().get(addr))
+                .map_or(0, |&byte| byte as i64),
+            Expr::Not(expr) => (expr.eval(chip8) == 0) as i64,
+            Expr::Negate(expr) => expr.eval(chip8).wrapping_neg(),
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(chip8);
+                // `||` and `&&` short-circuit
+                match op {
+                    BinaryOp::Or if lhs != 0 => return 1,
+                    BinaryOp::And if lhs == 0 => return 0,
+                    _ => (),
+                }
+                let rhs = rhs.eval(chip8);
+                match op {
+                    BinaryOp::Or | BinaryOp::And => (rhs != 0) as i64,
+                    BinaryOp::Eq => (lhs == rhs) as i64,
+                    BinaryOp::Ne => (lhs != rhs) as i64,
+                    BinaryOp::Lt => (lhs < rhs) as i64,
+                    BinaryOp::Le => (lhs <= rhs) as i64,
+                    BinaryOp::Gt => (lhs > rhs) as i64,
+                    BinaryOp::Ge => (lhs >= rhs) as i64,
+                    BinaryOp::Add => lhs.wrapping_add(rhs),
+                    BinaryOp::Sub => lhs.wrapping_sub(rhs),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Ident(String),
+    Op(&'static str),
+}
+
+/// Bounds the nesting of conditions, which are parsed and evaluated
+/// recursively.
+const MAX_TOKENS: usize = 128;
+
+const OPERATORS: &[&str] = &[
+    "||", "&&", "==", "!=", "<=", ">=", "<", ">", "+", "-", "!", "(", ")", "[", "]",
+];
+
+/// Splits the source into tokens, each paired with its byte offset.
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, ConditionError> {
+    let mut tokens = Vec::new();
+    let mut rest = source;
+    while let Some(start) = rest.find(|c: char| !c.is_whitespace()) {
+        rest = &rest[start..];
+        let offset = source.len() - rest.len();
+        if tokens.len() == MAX_TOKENS {
+            return Err(ConditionError::new(offset, "condition is too long"));
+        }
+
+        if let Some(&op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push((offset, Token::Op(op)));
+            rest = &rest[op.len()..];
+            continue;
+        }
+
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '#'))
+            .unwrap_or(rest.len());
+        if len == 0 {
+            return Err(ConditionError::new(offset, "unexpected character"));
+        }
+        let word = &rest[..len];
+        let token = if word.starts_with(|c: char| c.is_ascii_digit() || c == '#') {
+            Token::Number(
+                parse_number(word).ok_or_else(|| ConditionError::new(offset, "invalid number"))?,
+            )
+        } else {
+            Token::Ident(word.to_ascii_lowercase())
+        };
+        tokens.push((offset, token));
+        rest = &rest[len..];
+    }
+    Ok(tokens)
+}
+
+/// Parses a number in decimal, or in hexadecimal with a `0x` or `#` prefix.
+pub fn parse_number(word: &str) -> Option<i64> {
+    let hex = word
+        .strip_prefix("0x")
+        .or_else(|| word.strip_prefix("0X"))
+        .or_else(|| word.strip_prefix('#'));
+    match hex {
+        Some(digits) => i64::from_str_radix(digits, 16).ok(),
+        None => word.parse().ok(),
+    }
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    /// Length of the source.
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+
+    /// Offset of the next token, or the end of the source.
+    fn offset(&self) -> usize {
+        match self.tokens.get(self.pos) {
+            Some(&(offset, _)) => offset,
+            None => self.end,
+        }
+    }
+
+    fn eat(&mut self, op: &'static str) -> bool {
+        if self.peek() == Some(&Token::Op(op)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, op: &'static str) -> Result<(), ConditionError> {
+        if self.eat(op) {
+            Ok(())
+        } else {
+            Err(ConditionError::new(
+                self.offset(),
+                format!("expected '{op}'"),
+            ))
+        }
+    }
+
+    /// Parses a chain of operations of one precedence level.
+    fn binary(
+        &mut self,
+        ops: &[(&'static str, BinaryOp)],
+        operand: fn(&mut Self) -> Result<Expr, ConditionError>,
+    ) -> Result<Expr, ConditionError> {
+        let mut lhs = operand(self)?;
+        'outer: loop {
+            for &(token, op) in ops {
+                if self.eat(token) {
+                    let rhs = operand(self)?;
+                    lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+                    continue 'outer;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, ConditionError> {
+        self.binary(&[("||", BinaryOp::Or)], Self::and)
+    }
+
+    fn and(&mut self) -> Result<Expr, ConditionError> {
+        self.binary(&[("&&", BinaryOp::And)], Self::comparison)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, ConditionError> {
+        let ops = [
+            ("==", BinaryOp::Eq),
+            ("!=", BinaryOp::Ne),
+            ("<=", BinaryOp::Le),
+            (">=", BinaryOp::Ge),
+            ("<", BinaryOp::Lt),
+            (">", BinaryOp::Gt),
+        ];
+        self.binary(&ops, Self::sum)
+    }
+
+    fn sum(&mut self) -> Result<Expr, ConditionError> {
+        self.binary(&[("+", BinaryOp::Add), ("-", BinaryOp::Sub)], Self::unary)
+    }
+
+    fn unary(&mut self) -> Result<Expr, ConditionError> {
+        if self.eat("!") {
+            Ok(Expr::Not(Box::new(self.unary()?)))
+        } else if self.eat("-") {
+            Ok(Expr::Negate(Box::new(self.unary()?)))
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, ConditionError> {
+        let offset = self.offset();
+        let Some(token) = self.peek().cloned() else {
+            return Err(ConditionError::new(offset, "expected a value"));
+        };
+        self.pos += 1;
+        match token {
+            Token::Number(n) => Ok(Expr::Number(n)),
+            Token::Op("(") => {
+                let expr = self.or()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Token::Ident(name) if name == "mem" => {
+                self.expect("[")?;
+                let addr = self.or()?;
+                self.expect("]")?;
+                Ok(Expr::Memory(Box::new(addr)))
+            }
+            Token::Ident(name) => {
+                let variable = match name.as_str() {
+                    "i" => Variable::I,
+                    "pc" => Variable::Pc,
+                    "dt" => Variable::DelayTimer,
+                    "st" => Variable::SoundTimer,
+                    "sp" => Variable::CallDepth,
+                    _ => match parse_v_register(&name) {
+                        Some(x) => Variable::V(x),
+                        None => {
+                            let message = format!("unknown variable '{name}'");
+                            return Err(ConditionError::new(offset, message));
+                        }
+                    },
+                };
+                Ok(Expr::Variable(variable))
+            }
+            Token::Op(_) => Err(ConditionError::new(offset, "expected a value")),
+        }
+    }
+}
+
+/// Parses `v0` to `vf`, in either case.
+pub fn parse_v_register(name: &str) -> Option<u8> {
+    let digit = name.strip_prefix(['v', 'V'])?;
+    if digit.len() != 1 {
+        return None;
+    }
+    u8::from_str_radix(digit, 16).ok()
+}
+
+/// A condition that failed to parse.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConditionError {
+    /// Byte offset into the source.
+    pub offset: usize,
+    pub message: String,
+}
+
+impl ConditionError {
+    fn new(offset: usize, message: impl Into<String>) -> Self {
+        ConditionError {
+            offset,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConditionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at column {}", self.message, self.offset + 1)
+    }
+}
+
+impl error::Error for ConditionError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Chip8Error;
+    use crate::Quirks;
+
+    /// A machine looping on `ADD V0, 1; LD I, #300; LD [I], V0; JP #200`.
+    fn chip8() -> Chip8 {
+        let mut chip8 = Chip8::new(Quirks::CHIP8);
+        chip8
+            .load_program(&[0x70, 0x01, 0xA3, 0x00, 0xF0, 0x55, 0x12, 0x00])
+            .unwrap();
+        chip8
+    }
+
+    fn eval(chip8: &Chip8, source: &str) -> bool {
+        Condition::parse(source).unwrap().eval(chip8)
+    }
+
+    #[test]
+    fn precedence() {
+        let chip8 = chip8();
+        assert!(eval(&chip8, "1 || 0 && 0"));
+        assert!(!eval(&chip8, "(1 || 0) && 0"));
+        assert!(eval(&chip8, "2 - 1 - 1 == 0"));
+        assert!(eval(&chip8, "-1 + 3 == 2"));
+        assert!(!eval(&chip8, "!2 == 1"));
+        assert!(eval(&chip8, "1 < 2 == 1"));
+        assert!(eval(&chip8, "1 + 1 > 1 && 2 >= 2 && 1 <= 1 && 1 != 2"));
+    }
+
+    #[test]
+    fn operands() {
+        let mut chip8 = chip8();
+        chip8.set_register(0x3, 0x10);
+        chip8.set_register(0xF, 5);
+        chip8.set_ireg(0x300);
+        chip8.write_memory(0x300, &[0xAB]).unwrap();
+        chip8.set_delay_timer(7);
+
+        assert!(eval(&chip8, "v3 == 0x10 && VF == #5 && v0 == 0"));
+        assert!(eval(
+            &chip8,
+            "i == 768 && mem[i] == 0xab && mem[I + 1] == 0"
+        ));
+        assert!(eval(&chip8, "pc == 0x200 && dt == 7 && st == 0 && sp == 0"));
+        // Bytes outside of memory read as zero
+        assert!(eval(&chip8, "mem[0x10000] == 0 && mem[-1] == 0"));
+        assert!(eval(&chip8, "v3"));
+        assert!(!eval(&chip8, "v1"));
+    }
+
+    #[test]
+    fn rejects_malformed_conditions() {
+        let errors = [
+            ("", 0, "expected a value"),
+            ("v3 ==", 5, "expected a value"),
+            ("(v3", 3, "expected ')'"),
+            ("mem v3", 4, "expected '['"),
+            ("mem[v3", 6, "expected ']'"),
+            ("v3 v4", 3, "expected an operator"),
+            ("v3 == )", 6, "expected a value"),
+            ("vg == 1", 0, "unknown variable 'vg'"),
+            ("v3 == 0xZZ", 6, "invalid number"),
+            ("v3 == 99999999999999999999", 6, "invalid number"),
+            ("v3 = 1", 3, "unexpected character"),
+            ("é", 0, "unexpected character"),
+        ];
+        for (source, offset, message) in errors {
+            let err = Condition::parse(source).unwrap_err();
+            assert_eq!(
+                (err.offset, err.message.as_str()),
+                (offset, message),
+                "{source}"
+            );
+        }
+
+        // Too deep to parse or evaluate recursively
+        for source in ["(".repeat(100_000), "1 + ".repeat(100_000) + "1"] {
+            let err = Condition::parse(&source).unwrap_err();
+            assert_eq!(err.message, "condition is too long");
+        }
+    }
+
+    #[test]
+    fn register_watch() {
+        let mut chip8 = chip8();
+        let mut breakpoints = Breakpoints::new();
+        let id = breakpoints.add(Watch::Register(Register::V(0)), None);
+        let (step, hits) = breakpoints.run(&mut chip8);
+        assert_eq!(step.addr, 0x200);
+        let cause = Cause::Register {
+            register: Register::V(0),
+            old: 0,
+            new: 1,
+        };
+        assert_eq!(
+            hits,
+            [Hit {
+                id: id.unwrap(),
+                cause
+            }]
+        );
+    }
+
+    #[test]
+    fn memory_watch() {
+        let mut chip8 = chip8();
+        let mut breakpoints = Breakpoints::new();
+        let watch = |read, write| Watch::Memory {
+            range: 0x2FF..=0x300,
+            read,
+            write,
+        };
+        breakpoints.add(watch(true, false), None);
+        let id = breakpoints.add(
+            watch(false, true),
+            Some(Condition::parse("v0 == 2").unwrap()),
+        );
+
+        // The read watch ignores writes, and the write watch waits for V0
+        let (step, hits) = breakpoints.run(&mut chip8);
+        assert_eq!(step.addr, 0x204);
+        assert_eq!(chip8.registers()[0], 2);
+        let cause = Cause::Memory {
+            kind: AccessKind::Write,
+            pc: 0x204,
+            start: 0x300,
+            len: 1,
+        };
+        assert_eq!(
+            hits,
+            [Hit {
+                id: id.unwrap(),
+                cause
+            }]
+        );
+    }
+
+    #[test]
+    fn condition_watch_fires_when_it_becomes_true() {
+        let mut chip8 = chip8();
+        let mut breakpoints = Breakpoints::new();
+        let condition = Condition::parse("v0 >= 2 && v0 < 4 || v0 == 6").unwrap();
+        breakpoints.add(Watch::Condition, Some(condition));
+
+        let mut stops = Vec::new();
+        for _ in 0..2 {
+            let (_, hits) = breakpoints.run(&mut chip8);
+            assert_eq!(hits.len(), 1);
+            stops.push(chip8.registers()[0]);
+        }
+        assert_eq!(stops, [2, 6]);
+
+        // Next after V0 wraps around
+        let (_, hits) = breakpoints.run(&mut chip8);
+        assert_eq!(chip8.registers()[0], 2);
+        assert_eq!(
+            hits[0].to_string(),
+            "breakpoint 1: v0 >= 2 && v0 < 4 || v0 == 6 became true"
+        );
+    }
+
+    #[test]
+    fn address_breakpoint_with_condition() {
+        let mut chip8 = chip8();
+        let mut breakpoints = Breakpoints::new();
+        let condition = Condition::parse("mem[0x300] == 2").unwrap();
+        breakpoints.add(Watch::Address(0x200), Some(condition));
+        let (step, hits) = breakpoints.run(&mut chip8);
+        assert_eq!(step.addr, 0x206);
+        assert_eq!(chip8.pc(), 0x200);
+        assert_eq!(chip8.registers()[0], 2);
+        assert_eq!(hits[0].cause, Cause::Address(0x200));
+    }
+
+    #[test]
+    fn faults_stop_the_run() {
+        let mut chip8 = Chip8::new(Quirks::CHIP8);
+        chip8.load_program(&[0x00, 0xEE]).unwrap();
+        let (step, hits) = Breakpoints::new().run(&mut chip8);
+        assert!(hits.is_empty());
+        assert!(matches!(
+            step.outcome,
+            crate::StepOutcome::Fault(Chip8Error::StackUnderflow { addr: 0x200 })
+        ));
+    }
+}
@@ -0,0 +1,565 @@
+//! The binary's debugger mode: pauses the emulator and shows its state below
+//! the game screen.
+
+use std::io::{self, Write};
+
+use chip8::debug::{self, Breakpoints, Condition, Hit, Register, Watch};
+use chip8::rewind::Rewind;
+use chip8::{Chip8, Scheduler};
+
+use crossterm::event::KeyCode;
+use crossterm::{cursor, style, QueueableCommand};
+
+/// Terminal rows taken by the debugger, below the game screen.
+pub const DEBUGGER_ROWS: u16 = 18;
+
+const HELP: &str =
+    "F5 continue/pause  F4 back  F7 step  F6 step back  F8 step over  PgUp/PgDn memory  Home memory at I  : command  Esc quit";
+
+const COMMANDS: &str = "commands: break ADDR, watch/rwatch/awatch ADDR[..END] or REG, when COND, delete [ID], rewind FRAMES; append 'if COND' to break and watch";
+
+/// Instructions shown in the disassembly, and lines of the other panels.
+const PANEL_LINES: usize = 16;
+/// Instructions shown before the one at PC.
+const LINES_BEFORE_PC: u16 = 5;
+const MEMORY_ROW_SIZE: u16 = 8;
+
+const DISASSEMBLY_COLUMN: u16 = 0;
+const REGISTERS_COLUMN: u16 = 30;
+const STACK_COLUMN: u16 = 60;
+const MEMORY_COLUMN: u16 = 74;
+
+enum Mode {
+    Paused,
+    Running,
+    /// Running until the call at the paused instruction returns.
+    StepOver {
+        ret: u16,
+        depth: usize,
+    },
+}
+
+/// An error execution stopped at, identified by the PC and cycle count at
+/// the time so the debugger can tell when the machine has moved on.
+struct Fault {
+    pc: u16,
+    cycles: u64,
+    message: String,
+}
+
+pub struct Debugger {
+    mode: Mode,
+    /// First address of the memory view, following I when not set.
+    memory_view: Option<u16>,
+    /// Why execution last stopped.
+    message: String,
+    /// Set while paused at an error, which is not run into again.
+    fault: Option<Fault>,
+    breakpoints: Breakpoints,
+    /// The command being typed after pressing `:`.
+    prompt: Option<String>,
+}
+
+impl Debugger {
+    /// A debugger that starts out paused before the first instruction.
+    pub fn new() -> Self {
+        Debugger {
+            mode: Mode::Paused,
+            memory_view: None,
+            message: String::from("paused"),
+            fault: None,
+            breakpoints: Breakpoints::new(),
+            prompt: None,
+        }
+    }
+
+    /// Runs a frame unless paused, pausing again when the program stops or
+    /// a step over is complete.
+    pub fn run_frame(&mut self, scheduler: &mut Scheduler, chip8: &mut Chip8) {
+        let returned_to = match self.mode {
+            Mode::Paused => return,
+            Mode::Running => None,
+            Mode::StepOver { ret, depth } => Some((ret, depth)),
+        };
+
+        let breakpoints = &mut self.breakpoints;
+        let mut hits = Vec::new();
+        let result = scheduler.run_frame_until(chip8, |chip8, step| {
+            hits = breakpoints.check(chip8, step);
+            let returned = returned_to
+                .is_some_and(|(ret, depth)| chip8.pc() == ret && chip8.call_depth() <= depth);
+            returned || !hits.is_empty()
+        });
+        match result {
+            Ok(Some(_)) => self.pause(describe_hits(&hits)),
+            Ok(None) => (),
+            Err(err) => self.pause_at_fault(chip8, format!("error: {err}")),
+        }
+        if chip8.is_halted() {
+            self.pause("program exited");
+        }
+    }
+
+    /// Reacts to a debugger key, returning false for keys meant for the
+    /// program.
+    pub fn handle_key(
+        &mut self,
+        code: KeyCode,
+        scheduler: &mut Scheduler,
+        chip8: &mut Chip8,
+        rewind: Option<&mut Rewind>,
+    ) -> bool {
+        if let Some(prompt) = self.prompt.as_mut() {
+            match code {
+                KeyCode::Char(c) => prompt.push(c),
+                KeyCode::Backspace => {
+                    prompt.pop();
+                }
+                KeyCode::Enter => {
+                    let command = self.prompt.take().unwrap_or_default();
+                    self.message = match self.run_command(&command, scheduler, chip8, rewind) {
+                        Ok(message) | Err(message) => message,
+                    };
+                }
+                KeyCode::Esc => self.prompt = None,
+                _ => (),
+            }
+            return true;
+        }
+
+        match code {
+            KeyCode::Char(':') => self.prompt = Some(String::new()),
+            KeyCode::F(5 | 7 | 8) if self.at_fault(chip8) => (),
+            KeyCode::F(5) => match self.mode {
+                Mode::Paused if !chip8.is_halted() => self.resume(Mode::Running, scheduler, chip8),
+                Mode::Paused => (),
+                _ => self.pause("paused"),
+            },
+            KeyCode::F(7) => self.step(scheduler, chip8),
+            KeyCode::F(6) => self.step_back(scheduler, chip8, rewind),
+            KeyCode::F(4) => self.continue_back(scheduler, chip8, rewind),
+            KeyCode::F(8) => match chip8.instruction_at(chip8.pc()) {
+                Ok(op @ chip8::Op::Call(_)) if matches!(self.mode, Mode::Paused) => {
+                    let step_over = Mode::StepOver {
+                        ret: chip8.pc().wrapping_add(op.size()),
+                        depth: chip8.call_depth(),
+                    };
+                    self.resume(step_over, scheduler, chip8);
+                }
+                _ => self.step(scheduler, chip8),
+            },
+            KeyCode::PageUp => self.scroll_memory(chip8, -(PANEL_LINES as i32)),
+            KeyCode::PageDown => self.scroll_memory(chip8, PANEL_LINES as i32),
+            KeyCode::Home => self.memory_view = None,
+            _ => return false,
+        }
+        true
+    }
+
+    fn pause(&mut self, message: impl Into<String>) {
+        self.mode = Mode::Paused;
+        self.message = message.into();
+    }
+
+    fn pause_at_fault(&mut self, chip8: &Chip8, message: String) {
+        self.fault = Some(Fault {
+            pc: chip8.pc(),
+            cycles: chip8.cycles(),
+            message: message.clone(),
+        });
+        self.pause(message);
+    }
+
+    /// Whether the machine is still paused at the error it last stopped at,
+    /// which would only happen again when continuing or stepping. Stepping
+    /// back or rewinding moves on from it.
+    fn at_fault(&mut self, chip8: &Chip8) -> bool {
+        let stuck = self.fault.as_ref().is_some_and(|fault| {
+            matches!(self.mode, Mode::Paused)
+                && fault.pc == chip8.pc()
+                && fault.cycles == chip8.cycles()
+        });
+        match &self.fault {
+            Some(fault) if stuck => {
+                self.message = format!("{}; step back or rewind to get past it", fault.message);
+            }
+            _ => self.fault = None,
+        }
+        stuck
+    }
+
+    fn resume(&mut self, mode: Mode, scheduler: &mut Scheduler, chip8: &Chip8) {
+        self.mode = mode;
+        self.message = String::from("running");
+        self.breakpoints.sync(chip8);
+        scheduler.reset_clock();
+    }
+
+    /// Executes a single instruction, keeping the timers in step with the
+    /// instructions as when running.
+    fn step(&mut self, scheduler: &mut Scheduler, chip8: &mut Chip8) {
+        if !matches!(self.mode, Mode::Paused) {
+            return;
+        }
+        self.breakpoints.sync(chip8);
+        match scheduler.step(chip8) {
+            Ok(Some(step)) if !chip8.is_halted() => {
+                let hits = self.breakpoints.check(chip8, &step);
+                self.pause(describe_hits(&hits));
+            }
+            Ok(_) => self.pause("program exited"),
+            Err(err) => self.pause_at_fault(chip8, format!("error: {err}")),
+        }
+    }
+
+    /// Undoes the last instruction by re-executing up to it from a snapshot.
+    fn step_back(
+        &mut self,
+        scheduler: &mut Scheduler,
+        chip8: &mut Chip8,
+        rewind: Option<&mut Rewind>,
+    ) {
+        if !matches!(self.mode, Mode::Paused) {
+            return;
+        }
+        let Some(rewind) = rewind else {
+            return self.pause("rewinding is disabled");
+        };
+        if rewind.step_back(chip8, scheduler) {
+            self.pause("stepped back");
+        } else {
+            self.pause("no history to step back into");
+        }
+    }
+
+    /// Goes back to the last breakpoint hit, or as far as the snapshots
+    /// reach.
+    fn continue_back(
+        &mut self,
+        scheduler: &mut Scheduler,
+        chip8: &mut Chip8,
+        rewind: Option<&mut Rewind>,
+    ) {
+        if !matches!(self.mode, Mode::Paused) {
+            return;
+        }
+        let Some(rewind) = rewind else {
+            return self.pause("rewinding is disabled");
+        };
+        let hits = rewind.continue_back(chip8, scheduler, &mut self.breakpoints);
+        if hits.is_empty() {
+            self.pause("reached the start of the history");
+        } else {
+            self.pause(describe_hits(&hits));
+        }
+    }
+
+    /// Runs a command typed at the prompt, returning a message to show.
+    fn run_command(
+        &mut self,
+        command: &str,
+        scheduler: &mut Scheduler,
+        chip8: &mut Chip8,
+        rewind: Option<&mut Rewind>,
+    ) -> Result<String, String> {
+        let command = command.trim();
+        let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+        let args = args.trim();
+        // A condition may follow the arguments of `break` and `watch`
+        let (target, condition) = match args.split_once(" if ") {
+            Some((target, condition)) => (target.trim(), Some(parse_condition(condition)?)),
+            None => (args, None),
+        };
+
+        let watch = match name {
+            "" => return Ok(String::from(COMMANDS)),
+            "break" | "b" => Watch::Address(parse_address(target)?),
+            "watch" | "rwatch" | "awatch" => {
+                let (read, write) = match name {
+                    "rwatch" => (true, false),
+                    "awatch" => (true, true),
+                    _ => (false, true),
+                };
+                match parse_register(target) {
+                    Some(register) if name == "watch" => Watch::Register(register),
+                    Some(_) => {
+                        return Err(String::from("registers can only be watched for changes"))
+                    }
+                    None => Watch::Memory {
+                        range: parse_range(target)?,
+                        read,
+                        write,
+                    },
+                }
+            }
+            "when" => {
+                let condition = parse_condition(args)?;
+                let id = self.breakpoints.add(Watch::Condition, Some(condition));
+                return Ok(format!("added breakpoint {}", id.unwrap_or_default()));
+            }
+            "rewind" => {
+                let frames = args
+                    .parse()
+                    .map_err(|_| format!("invalid number of frames '{args}'"))?;
+                let rewind = rewind.ok_or("rewinding is disabled")?;
+                self.mode = Mode::Paused;
+                let rewound = rewind.rewind(chip8, scheduler, frames);
+                return Ok(format!("rewound {rewound} frames"));
+            }
+            "delete" | "d" if args.is_empty() => {
+                self.breakpoints.clear();
+                return Ok(String::from("deleted all breakpoints"));
+            }
+            "delete" | "d" => {
+                let id = args
+                    .parse()
+                    .map_err(|_| format!("invalid breakpoint '{args}'"))?;
+                return match self.breakpoints.remove(id) {
+                    true => Ok(format!("deleted breakpoint {id}")),
+                    false => Err(format!("no breakpoint {id}")),
+                };
+            }
+            _ => return Err(format!("unknown command '{name}'; {COMMANDS}")),
+        };
+        let id = self.breakpoints.add(watch, condition);
+        Ok(format!("added breakpoint {}", id.unwrap_or_default()))
+    }
+
+    fn scroll_memory(&mut self, chip8: &Chip8, rows: i32) {
+        let start = self.memory_start(chip8) as i32 + rows * MEMORY_ROW_SIZE as i32;
+        let last_row = chip8.memory().len() as i32 - MEMORY_ROW_SIZE as i32;
+        self.memory_view = Some(start.clamp(0, last_row.max(0)) as u16);
+    }
+
+    fn memory_start(&self, chip8: &Chip8) -> u16 {
+        self.memory_view
+            .unwrap_or(chip8.ireg() / MEMORY_ROW_SIZE * MEMORY_ROW_SIZE)
+    }
+
+    /// Draws the panels starting at terminal row `top`.
+    pub fn draw(&self, chip8: &Chip8, out: &mut impl Write, top: u16) -> io::Result<()> {
+        out.queue(style::ResetColor)?;
+
+        let status = match &self.prompt {
+            Some(prompt) => format!(":{prompt}"),
+            None => format!("[{}] {HELP}", self.message),
+        };
+        print_at(out, 0, top, &status, 128)?;
+
+        let top = top + 2;
+        let lines = disassemble_around_pc(chip8, &self.breakpoints);
+        for row in 0..PANEL_LINES {
+            let line = lines.get(row).map_or("", String::as_str);
+            print_at(out, DISASSEMBLY_COLUMN, top + row as u16, line, 28)?;
+        }
+
+        let v = chip8.registers();
+        let mut registers: Vec<String> = v
+            .chunks(4)
+            .enumerate()
+            .map(|(row, regs)| {
+                let regs = regs
+                    .iter()
+                    .enumerate()
+                    .map(|(i, value)| format!("V{:X} {value:02X}", row * 4 + i));
+                regs.collect::<Vec<_>>().join("  ")
+            })
+            .collect();
+        registers.push(String::new());
+        registers.push(format!("PC {:04X}  I  {:04X}", chip8.pc(), chip8.ireg()));
+        registers.push(format!(
+            "DT {:02X}    ST {:02X}",
+            chip8.delay_timer(),
+            chip8.sound_timer()
+        ));
+        registers.push(String::new());
+        registers.extend(
+            self.breakpoints
+                .iter()
+                .map(|breakpoint| format!("{} {breakpoint}", breakpoint.id)),
+        );
+        for row in 0..PANEL_LINES {
+            let line = registers.get(row).map_or("", String::as_str);
+            print_at(out, REGISTERS_COLUMN, top + row as u16, line, 28)?;
+        }
+
+        // Innermost call first, as far as it fits
+        let mut stack = vec![String::from("Stack")];
+        stack.extend(
+            chip8
+                .call_stack()
+                .iter()
+                .rev()
+                .map(|addr| format!("{addr:04X}")),
+        );
+        for row in 0..PANEL_LINES {
+            let line = stack.get(row).map_or("", String::as_str);
+            print_at(out, STACK_COLUMN, top + row as u16, line, 12)?;
+        }
+
+        let mem = chip8.memory();
+        let start = self.memory_start(chip8) as usize;
+        for row in 0..PANEL_LINES {
+            let addr = start + row * MEMORY_ROW_SIZE as usize;
+            let bytes = mem
+                .get(addr..(addr + MEMORY_ROW_SIZE as usize).min(mem.len()))
+                .unwrap_or_default();
+            let line = if bytes.is_empty() {
+                String::new()
+            } else {
+                let hex: Vec<_> = bytes.iter().map(|byte| format!("{byte:02X}")).collect();
+                let ascii: String = bytes
+                    .iter()
+                    .map(|&byte| {
+                        if byte.is_ascii_graphic() {
+                            byte as char
+                        } else {
+                            '.'
+                        }
+                    })
+                    .collect();
+                format!("{addr:04X}  {}  {ascii}", hex.join(" "))
+            };
+            print_at(out, MEMORY_COLUMN, top + row as u16, &line, 54)?;
+        }
+
+        out.flush()
+    }
+}
+
+/// Lists the instructions around PC, decoding forward from a few
+/// instructions before it.
+fn disassemble_around_pc(chip8: &Chip8, breakpoints: &Breakpoints) -> Vec<String> {
+    let pc = chip8.pc();
+    let mut addr = pc.saturating_sub(2 * LINES_BEFORE_PC);
+    let mut lines = Vec::new();
+    let mut seen_pc = false;
+    while lines.len() < PANEL_LINES {
+        // A four-byte instruction may straddle PC, so realign on it
+        if !seen_pc && addr > pc {
+            lines.clear();
+            addr = pc;
+        }
+        seen_pc |= addr == pc;
+        let Ok(op) = chip8.instruction_at(addr) else {
+            break;
+        };
+        let marker = if addr == pc { '>' } else { ' ' };
+        let breakpoint = if breakpoints.has_address(addr) {
+            '*'
+        } else {
+            ' '
+        };
+        let mem = chip8.memory();
+        let opcode = u16::from_be_bytes([mem[addr as usize], mem[addr as usize + 1]]);
+        lines.push(format!(
+            "{marker}{breakpoint}{addr:04X}  {opcode:04X}  {op}"
+        ));
+        match addr.checked_add(op.size()) {
+            Some(next) => addr = next,
+            None => break,
+        }
+    }
+    lines
+}
+
+/// Prints `text` at the given position, padded or cut to `width` columns.
+fn print_at(
+    out: &mut impl Write,
+    column: u16,
+    row: u16,
+    text: &str,
+    width: usize,
+) -> io::Result<()> {
+    let text: String = text.chars().take(width).collect();
+    out.queue(cursor::MoveTo(column, row))?
+        .queue(style::Print(format!("{text:<width$}")))?;
+    Ok(())
+}
+
+fn describe_hits(hits: &[Hit]) -> String {
+    if hits.is_empty() {
+        return String::from("paused");
+    }
+    let hits: Vec<_> = hits.iter().map(Hit::to_string).collect();
+    hits.join("; ")
+}
+
+fn parse_condition(source: &str) -> Result<Condition, String> {
+    Condition::parse(source).map_err(|err| format!("invalid condition: {err}"))
+}
+
+fn parse_address(word: &str) -> Result<u16, String> {
+    debug::parse_number(word)
+        .and_then(|addr| u16::try_from(addr).ok())
+        .ok_or_else(|| format!("invalid address '{word}'"))
+}
+
+/// Parses `ADDR`, `START..END` or `START..=END`.
+fn parse_range(word: &str) -> Result<std::ops::RangeInclusive<u16>, String> {
+    let range = if let Some((start, end)) = word.split_once("..=") {
+        parse_address(start)?..=parse_address(end)?
+    } else if let Some((start, end)) = word.split_once("..") {
+        let end = parse_address(end)?;
+        if end == 0 {
+            return Err(format!("empty range '{word}'"));
+        }
+        parse_address(start)?..=end - 1
+    } else {
+        let addr = parse_address(word)?;
+        addr..=addr
+    };
+    if range.is_empty() {
+        return Err(format!("empty range '{word}'"));
+    }
+    Ok(range)
+}
+
+fn parse_register(word: &str) -> Option<Register> {
+    match word.to_ascii_lowercase().as_str() {
+        "i" => Some(Register::I),
+        "dt" => Some(Register::DelayTimer),
+        "st" => Some(Register::SoundTimer),
+        name => debug::parse_v_register(name).map(Register::V),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chip8::Quirks;
+
+    use super::*;
+
+    #[test]
+    fn does_not_run_into_a_fault_again() {
+        let mut chip8 = Chip8::new(Quirks::CHIP8);
+        // LD V0, 1; RET with nothing to return to
+        chip8.load_program(&[0x60, 0x01, 0x00, 0xEE]).unwrap();
+        let mut scheduler = Scheduler::new(chip8.speed());
+        let mut rewind = Rewind::new(60);
+        rewind.record(&chip8, &scheduler);
+        let mut debugger = Debugger::new();
+
+        debugger.handle_key(KeyCode::F(5), &mut scheduler, &mut chip8, None);
+        debugger.run_frame(&mut scheduler, &mut chip8);
+        assert_eq!(debugger.message, "error: stack underflow at 0x202");
+        let cycles = chip8.cycles();
+
+        for key in [5, 7, 8] {
+            debugger.handle_key(KeyCode::F(key), &mut scheduler, &mut chip8, None);
+            assert!(matches!(debugger.mode, Mode::Paused));
+            assert_eq!(chip8.cycles(), cycles);
+            assert!(debugger
+                .message
+                .ends_with("step back or rewind to get past it"));
+        }
+
+        let rewind = Some(&mut rewind);
+        debugger.handle_key(KeyCode::F(6), &mut scheduler, &mut chip8, rewind);
+        assert_eq!(chip8.pc(), 0x200);
+        for _ in 0..2 {
+            debugger.handle_key(KeyCode::F(7), &mut scheduler, &mut chip8, None);
+        }
+        assert_eq!(debugger.message, "error: stack underflow at 0x202");
+    }
+}
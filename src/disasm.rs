@@ -0,0 +1,433 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::Op;
+
+/// A listing of a program, telling code from data by following every path
+/// of execution from the load address.
+///
+/// Jump, call and index targets get labels, sprites drawn from a known
+/// index are shown as bitmaps, and the listing warns about code that
+/// overlaps other instructions or starts at an odd address. Bytes that are
+/// neither reached nor referenced are marked unreachable. Computed jumps
+/// (`JP V0, addr`) are only followed to their base address. The listing
+/// assembles back into the same program with [`crate::asm::assemble`].
+///
+/// ```
+/// use chip8::disasm::Disassembly;
+///
+/// let program = [0xA2, 0x06, 0xD0, 0x11, 0x12, 0x04, 0xF0];
+/// let listing = Disassembly::new(&program, 0x200).to_string();
+/// assert!(listing.contains("LD I, sprite_206"));
+/// assert!(listing.contains("JP label_204"));
+/// ```
+pub struct Disassembly<'a> {
+    program: &'a [u8],
+    load_address: u16,
+    /// What each byte of the program was found to be.
+    kinds: Vec<Kind>,
+    labels: BTreeMap<u16, LabelKind>,
+    warnings: BTreeMap<u16, Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Unknown,
+    /// The first byte of an instruction.
+    Instruction,
+    /// The remaining bytes of an instruction.
+    Operand,
+    /// A row of a sprite, 16 pixels wide for the first of two bytes.
+    Sprite {
+        wide: bool,
+    },
+}
+
+/// Why an address is labelled, the later variants taking precedence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum LabelKind {
+    Data,
+    Sprite,
+    Jump,
+    Subroutine,
+    Start,
+}
+
+/// Rows of a sprite drawn with `DXY0`, two bytes each.
+const BIG_SPRITE_ROWS: usize = 16;
+/// Bytes per `db` line of data.
+const DATA_LINE_SIZE: usize = 4;
+
+impl<'a> Disassembly<'a> {
+    pub fn new(program: &'a [u8], load_address: u16) -> Self {
+        // Bytes past the end of the address space could never be loaded
+        let program = &program[..program.len().min(0x10000 - load_address as usize)];
+        let mut disassembly = Disassembly {
+            program,
+            load_address,
+            kinds: vec![Kind::Unknown; program.len()],
+            labels: BTreeMap::new(),
+            warnings: BTreeMap::new(),
+        };
+        disassembly.labels.insert(load_address, LabelKind::Start);
+        disassembly.trace();
+        disassembly.check_alignment();
+        disassembly
+    }
+
+    /// Offset of `addr` within the program.
+    fn offset(&self, addr: u16) -> Option<usize> {
+        let offset = (addr as usize).checked_sub(self.load_address as usize)?;
+        (offset < self.program.len()).then_some(offset)
+    }
+
+    fn label(&mut self, addr: u16, kind: LabelKind) {
+        if self.offset(addr).is_some() {
+            let label = self.labels.entry(addr).or_insert(kind);
+            *label = (*label).max(kind);
+        }
+    }
+
+    fn warn(&mut self, addr: u16, warning: impl Into<String>) {
+        self.warnings.entry(addr).or_default().push(warning.into());
+    }
+
+    /// Follows every path from the load address, keeping track of `I` to
+    /// find the sprites that are drawn.
+    fn trace(&mut self) {
+        let mut paths = vec![(self.load_address, None::<u16>)];
+        while let Some((addr, mut index)) = paths.pop() {
+            // Jumps into the interpreter or past the end are not followed
+            let Some(offset) = self.offset(addr) else {
+                continue;
+            };
+            match self.kinds[offset] {
+                Kind::Instruction => continue,
+                Kind::Operand => {
+                    self.warn(addr, "jump into the middle of an instruction");
+                    continue;
+                }
+                _ => (),
+            }
+            let Some(op) = Op::decode(&self.program[offset..]) else {
+                self.warn(addr, "instruction runs past the end of the program");
+                continue;
+            };
+            if let Op::Unknown(opcode) = op {
+                self.warn(addr, format!("unknown opcode {opcode:04X} is reached"));
+                continue;
+            }
+            let size = op.size() as usize;
+            if self.kinds[offset + 1..offset + size].contains(&Kind::Instruction) {
+                self.warn(addr, "instruction overlaps the next one");
+                continue;
+            }
+            self.kinds[offset] = Kind::Instruction;
+            self.kinds[offset + 1..offset + size].fill(Kind::Operand);
+
+            let next = addr.wrapping_add(op.size());
+            match op {
+                Op::Return | Op::Exit => continue,
+                Op::AbsJump(target) | Op::OffsetJump(target) => {
+                    self.label(target, LabelKind::Jump);
+                    paths.push((target, None));
+                    continue;
+                }
+                Op::Call(target) => {
+                    self.label(target, LabelKind::Subroutine);
+                    paths.push((target, None));
+                    index = None;
+                }
+                Op::SkipEqVal(..)
+                | Op::SkipNeqVal(..)
+                | Op::SkipEqReg(..)
+                | Op::SkipNeqReg(..)
+                | Op::SkipKey(_)
+                | Op::SkipNoKey(_) => {
+                    let skipped = self
+                        .offset(next)
+                        .and_then(|offset| Op::decode(&self.program[offset..]))
+                        .map_or(2, |op| op.size());
+                    paths.push((next.wrapping_add(skipped), index));
+                }
+                Op::SetIndex(target) | Op::LongIndex(target) => {
+                    self.label(target, LabelKind::Data);
+                    index = Some(target);
+                }
+                Op::Draw(_, _, rows) => {
+                    if let Some(sprite) = index {
+                        self.mark_sprite(sprite, rows);
+                    }
+                }
+                Op::IncrIndex(_)
+                | Op::SetSpriteI(_)
+                | Op::SetBigSpriteI(_)
+                | Op::DumpRegisters(_)
+                | Op::LoadRegisters(_) => index = None,
+                _ => (),
+            }
+            paths.push((next, index));
+        }
+    }
+
+    /// Warns where runs of instructions at odd addresses start.
+    fn check_alignment(&mut self) {
+        let mut misaligned_end = None;
+        for offset in 0..self.program.len() {
+            let addr = self.load_address + offset as u16;
+            if self.kinds[offset] != Kind::Instruction || addr.is_multiple_of(2) {
+                continue;
+            }
+            if misaligned_end != Some(offset) {
+                self.warn(addr, "code at an odd address");
+            }
+            let size = Op::decode(&self.program[offset..]).map_or(2, |op| op.size());
+            misaligned_end = Some(offset + size as usize);
+        }
+    }
+
+    fn mark_sprite(&mut self, addr: u16, rows: u8) {
+        let Some(start) = self.offset(addr) else {
+            return;
+        };
+        let (len, wide) = match rows {
+            0 => (2 * BIG_SPRITE_ROWS, true),
+            rows => (rows as usize, false),
+        };
+        let end = (start + len).min(self.program.len());
+        if self.kinds[start..end]
+            .iter()
+            .any(|&kind| kind != Kind::Unknown)
+        {
+            return;
+        }
+        self.label(addr, LabelKind::Sprite);
+        for (i, kind) in self.kinds[start..end].iter_mut().enumerate() {
+            *kind = Kind::Sprite {
+                wide: wide && i % 2 == 0,
+            };
+        }
+    }
+
+    /// Whether the byte at `offset` starts a line of the listing, rather
+    /// than being in the middle of an instruction or of a wide sprite row.
+    fn starts_line(&self, offset: usize) -> bool {
+        match self.kinds[offset] {
+            Kind::Operand => false,
+            Kind::Sprite { wide: false } => {
+                offset == 0 || self.kinds[offset - 1] != Kind::Sprite { wide: true }
+            }
+            _ => true,
+        }
+    }
+
+    /// The label of `addr`, unless the listing cannot define one there.
+    fn label_name(&self, addr: u16) -> Option<String> {
+        if !self.starts_line(self.offset(addr)?) {
+            return None;
+        }
+        let prefix = match self.labels.get(&addr)? {
+            LabelKind::Start => return Some(String::from("start")),
+            LabelKind::Subroutine => "sub",
+            LabelKind::Jump => "label",
+            LabelKind::Sprite => "sprite",
+            LabelKind::Data => "data",
+        };
+        Some(format!("{prefix}_{addr:03X}"))
+    }
+
+    /// The instruction in assembler syntax, with labels for its target.
+    fn format_op(&self, op: Op) -> String {
+        let (mnemonic, target) = match op {
+            Op::AbsJump(target) => ("JP", target),
+            Op::Call(target) => ("CALL", target),
+            Op::OffsetJump(target) => ("JP V0,", target),
+            Op::SetIndex(target) => ("LD I,", target),
+            Op::LongIndex(target) => ("LD I, LONG", target),
+            _ => return op.to_string(),
+        };
+        match self.label_name(target) {
+            Some(label) => format!("{mnemonic} {label}"),
+            None => op.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Disassembly<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let warnings: usize = self.warnings.values().map(Vec::len).sum();
+        writeln!(
+            f,
+            "; {} bytes loaded at #{:03X}, {warnings} warnings",
+            self.program.len(),
+            self.load_address
+        )?;
+        writeln!(f, "    ORG #{:03X}", self.load_address)?;
+
+        let mut offset = 0;
+        while offset < self.program.len() {
+            let addr = self.load_address + offset as u16;
+            let bytes = &self.program[offset..];
+            let len = match self.kinds[offset] {
+                Kind::Instruction | Kind::Operand => {
+                    Op::decode(bytes).map_or(bytes.len().min(2), |op| op.size() as usize)
+                }
+                Kind::Sprite { wide: true } => 2.min(bytes.len()),
+                Kind::Sprite { wide: false } => 1,
+                // Up to the next byte that is known, labelled or warned about
+                Kind::Unknown => (1..bytes.len())
+                    .find(|&i| {
+                        let addr = addr + i as u16;
+                        self.kinds[offset + i] != Kind::Unknown
+                            || self.labels.contains_key(&addr)
+                            || self.warnings.contains_key(&addr)
+                    })
+                    .unwrap_or(bytes.len()),
+            };
+            let bytes = &bytes[..len];
+
+            if let Some(label) = self.label_name(addr) {
+                writeln!(f, "\n{label}:")?;
+            }
+            // Warnings about bytes within the line name their address
+            let end = addr + (len - 1) as u16;
+            for (&warning_addr, warnings) in self.warnings.range(addr..=end) {
+                for warning in warnings {
+                    match warning_addr == addr {
+                        true => writeln!(f, "    ; warning: {warning}")?,
+                        false => writeln!(f, "    ; warning: #{warning_addr:03X}: {warning}")?,
+                    }
+                }
+            }
+
+            match self.kinds[offset] {
+                Kind::Instruction | Kind::Operand => {
+                    let op = Op::decode(bytes).unwrap_or(Op::Unknown(0));
+                    let hex: String = bytes.iter().map(|b| format!("{b:02X}")).collect();
+                    writeln!(f, "    {:<28}; {addr:04X}  {hex}", self.format_op(op))?;
+                }
+                Kind::Sprite { .. } => {
+                    let data: Vec<_> = bytes.iter().map(|b| format!("#{b:02X}")).collect();
+                    let pixels: String = bytes
+                        .iter()
+                        .flat_map(|b| (0..8).rev().map(move |bit| b >> bit & 1))
+                        .map(|pixel| if pixel == 1 { '█' } else { '.' })
+                        .collect();
+                    writeln!(f, "    db {:<25}; {addr:04X}  {pixels}", data.join(", "))?;
+                }
+                Kind::Unknown => {
+                    // Runs start at a label when they are referenced at all,
+                    // or at a warning when they are reached
+                    if !self.labels.contains_key(&addr) && !self.warnings.contains_key(&addr) {
+                        let unit = if len == 1 { "byte" } else { "bytes" };
+                        writeln!(f, "    ; unreachable: {len} {unit}")?;
+                    }
+                    for (i, line) in bytes.chunks(DATA_LINE_SIZE).enumerate() {
+                        let data: Vec<_> = line.iter().map(|b| format!("#{b:02X}")).collect();
+                        let line_addr = addr + (i * DATA_LINE_SIZE) as u16;
+                        writeln!(f, "    db {:<25}; {line_addr:04X}", data.join(", "))?;
+                    }
+                }
+            }
+            offset += len;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    fn disassemble(program: &[u8]) -> String {
+        let listing = Disassembly::new(program, 0x200).to_string();
+        let assembled = asm::assemble("listing.s", &listing).unwrap();
+        assert_eq!(assembled, program, "{listing}");
+        listing
+    }
+
+    #[test]
+    fn tells_code_from_data() {
+        #[rustfmt::skip]
+        let program = [
+            0xA2, 0x10, 0xD0, 0x12, 0x30, 0x00, 0xF0, 0x00, 0x02, 0x14, 0x22, 0x0E,
+            0x12, 0x0C, 0x00, 0xEE, 0xF0, 0x90, 0xFF, 0xFF, 0xAB, 0xCD,
+        ];
+        let expected = "\
+; 22 bytes loaded at #200, 0 warnings
+    ORG #200
+
+start:
+    LD I, sprite_210            ; 0200  A210
+    DRW V0, V1, 2               ; 0202  D012
+    SE V0, #00                  ; 0204  3000
+    LD I, LONG data_214         ; 0206  F0000214
+    CALL sub_20E                ; 020A  220E
+
+label_20C:
+    JP label_20C                ; 020C  120C
+
+sub_20E:
+    RET                         ; 020E  00EE
+
+sprite_210:
+    db #F0                      ; 0210  ████....
+    db #90                      ; 0211  █..█....
+    ; unreachable: 2 bytes
+    db #FF, #FF                 ; 0212
+
+data_214:
+    db #AB, #CD                 ; 0214
+";
+        assert_eq!(disassemble(&program), expected);
+    }
+
+    #[test]
+    fn skips_over_long_index() {
+        // SE V0, 0; LD I, LONG #1200; EXIT, where the operand reads as a jump
+        let listing = disassemble(&[0x30, 0x00, 0xF0, 0x00, 0x12, 0x00, 0x00, 0xFD]);
+        assert!(listing.contains("LD I, LONG #1200            ; 0202  F0001200"));
+        assert!(listing.contains("EXIT                        ; 0206  00FD"));
+        assert!(!listing.contains("; 0204"), "{listing}");
+        assert!(!listing.contains("; warning"), "{listing}");
+    }
+
+    #[test]
+    fn warns_about_misaligned_code() {
+        // JP #203; a stray byte; CLS; JP #203
+        let listing = disassemble(&[0x12, 0x03, 0xFF, 0x00, 0xE0, 0x12, 0x03]);
+        assert!(listing.starts_with("; 7 bytes loaded at #200, 1 warnings"));
+        assert!(listing.contains(
+            "\
+label_203:
+    ; warning: code at an odd address
+    CLS                         ; 0203  00E0
+    JP label_203                ; 0205  1203"
+        ));
+
+        // LD V0, #12; JP #201, into the middle of the first instruction
+        let listing = disassemble(&[0x60, 0x12, 0x12, 0x01]);
+        assert!(listing.contains(
+            "\
+start:
+    ; warning: #201: jump into the middle of an instruction
+    LD V0, #12                  ; 0200  6012
+    JP #201                     ; 0202  1201"
+        ));
+    }
+
+    #[test]
+    fn shows_warnings_within_sprites() {
+        // LD I, sprite; DRW V0, V1, 0; JP #209, into the second byte of a row
+        let mut program = vec![0xA2, 0x06, 0xD0, 0x10, 0x12, 0x09];
+        program.extend(0..32);
+        let listing = disassemble(&program);
+        assert!(listing.contains("JP #209                     ; 0204  1209"));
+        assert!(listing.contains(
+            "\
+    ; warning: #209: unknown opcode 0304 is reached
+    db #02, #03                 ; 0208  ......█.......██"
+        ));
+    }
+}
@@ -0,0 +1,124 @@
+use std::io;
+
+pub const LORES_WIDTH: usize = 64;
+pub const LORES_HEIGHT: usize = 32;
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
+pub(crate) const N_PIXELS: usize = HIRES_WIDTH * HIRES_HEIGHT;
+
+/// Something that can show the emulator's screen, such as a terminal or a
+/// window. The core never draws by itself; frontends pass their `Display`
+/// to [`Chip8::present`](crate::Chip8::present) whenever they want to
+/// refresh.
+pub trait Display {
+    fn present(&mut self, frame: &Framebuffer) -> io::Result<()>;
+}
+
+/// The pixels of the emulated screen, in either 64x32 or 128x64 resolution.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Framebuffer {
+    /// Each pixel holds one bit per XO-CHIP bitplane.
+    pub(crate) pixels: [u8; N_PIXELS],
+    pub(crate) hires: bool,
+    /// Bitplanes affected by drawing, clearing and scrolling.
+    pub(crate) planes: u8,
+}
+
+impl Framebuffer {
+    pub fn new() -> Self {
+        Framebuffer {
+            pixels: [0; N_PIXELS],
+            hires: false,
+            planes: 0b01,
+        }
+    }
+
+    #[inline]
+    pub fn width(&self) -> usize {
+        if self.hires {
+            HIRES_WIDTH
+        } else {
+            LORES_WIDTH
+        }
+    }
+
+    #[inline]
+    pub fn height(&self) -> usize {
+        if self.hires {
+            HIRES_HEIGHT
+        } else {
+            LORES_HEIGHT
+        }
+    }
+
+    #[inline]
+    pub fn is_hires(&self) -> bool {
+        self.hires
+    }
+
+    /// The bitplanes set at the given position, `0` being the background.
+    #[inline]
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        self.pixels[self.width() * y + x]
+    }
+    #[inline]
+    pub(crate) fn pixel_mut(&mut self, x: usize, y: usize) -> &mut u8 {
+        let width = self.width();
+        &mut self.pixels[width * y + x]
+    }
+
+    /// Rows of pixels from top to bottom.
+    pub fn rows(&self) -> impl Iterator<Item = &[u8]> {
+        let width = self.width();
+        self.pixels[..width * self.height()].chunks(width)
+    }
+
+    #[inline]
+    pub fn planes(&self) -> u8 {
+        self.planes
+    }
+
+    pub(crate) fn select_planes(&mut self, planes: u8) {
+        self.planes = planes & 0b11;
+    }
+
+    /// Switches between 64x32 and 128x64 pixels, clearing all planes.
+    pub(crate) fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.pixels.fill(0);
+    }
+
+    /// Moves the selected planes by `dx` pixels right and `dy` pixels down.
+    pub(crate) fn scroll(&mut self, dx: isize, dy: isize) {
+        let (width, height) = (self.width(), self.height());
+        let source = self.pixels;
+        for y in 0..height {
+            for x in 0..width {
+                let (src_x, src_y) = (x as isize - dx, y as isize - dy);
+                let moved = if (0..width as isize).contains(&src_x)
+                    && (0..height as isize).contains(&src_y)
+                {
+                    source[width * src_y as usize + src_x as usize] & self.planes
+                } else {
+                    0
+                };
+                let pixel = &mut self.pixels[width * y + x];
+                *pixel = (*pixel & !self.planes) | moved;
+            }
+        }
+    }
+
+    /// Clears the selected planes.
+    #[inline]
+    pub(crate) fn clear(&mut self) {
+        for pixel in self.pixels.iter_mut() {
+            *pixel &= !self.planes;
+        }
+    }
+}
+
+impl Default for Framebuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,28 +1,79 @@
-use std::io::{self, Write};
-use std::ops::RangeInclusive;
+use std::ops::Range;
+use std::rc::Rc;
 use std::time::Duration;
+use std::{error, fmt, io};
 
-use deku::{bitvec::BitView, prelude::*};
-
-use crossterm::{ExecutableCommand, QueueableCommand};
-use crossterm::event::{Event, KeyCode};
-use crossterm::{cursor, event, style, terminal};
+use deku::bitvec::BitView;
 
+use crate::audio::{self, Audio, InvalidSampleRate};
+use crate::config::{Chip8Config, BIG_FONT_CHAR_SIZE, FONT_CHAR_SIZE};
+use crate::display::{Display, Framebuffer};
 use crate::ops::Op;
+use crate::quirks::{Quirks, StackLocation};
+use crate::random::{Random, RandomKind, SeededRandom};
+use crate::savestate::{self, SaveStateError};
+use crate::scheduler::Speed;
+use crate::trace::Tracer;
 
 pub struct Chip8 {
     pc: u16,
-    mem: Box<[u8; 1024 * 4]>,
+    mem: Box<[u8]>,
     ireg: u16,
+    /// Return addresses, unless the stack lives in emulated memory.
     stack: Vec<u16>,
-    _dt: u8,
-    _st: u8,
+    /// Number of return addresses on the stack, wherever it lives.
+    stack_len: usize,
+    dt: u8,
+    st: u8,
+    /// Wall-clock time not yet consumed by the timers, in units of 1/60 ns.
+    timer_clock: u64,
     v: Registers,
-    screen: Screen,
+    keypad: Keypad,
+    /// Key pressed while blocked on `GetKey`, waiting to be released.
+    pending_key: Option<u8>,
+    /// Set on every 60 Hz timer period, consumed by `Draw` when waiting for
+    /// the display.
+    vblank: bool,
+    /// SUPER-CHIP's persistent "RPL user flags", saved with `Fx75`.
+    flags: [u8; 16],
+    /// Set by `00FD`, after which no more instructions are executed.
+    halted: bool,
+    audio: Audio,
+    rng: Box<dyn Random>,
+    config: Chip8Config,
+    screen: Framebuffer,
+    /// Whether `screen` changed since it was last presented.
+    frame_dirty: bool,
+    /// Memory read or written by the instruction being executed.
+    access: Option<MemoryAccess>,
+    tracer: Option<Tracer>,
+    /// Instructions executed since the program started.
+    cycles: u64,
+    /// Identifies the loaded program, to match save states with it.
+    program_hash: u64,
 }
 
+#[derive(Clone)]
 struct Registers([u8; 16]);
 
+/// Pressed state of the 16 keys of the hexadecimal keypad.
+#[derive(Clone)]
+struct Keypad([bool; 16]);
+
+impl Keypad {
+    #[inline]
+    fn is_pressed(&self, key: u8) -> bool {
+        self.0[key as usize & 0xF]
+    }
+
+    fn first_pressed(&self) -> Option<u8> {
+        self.0
+            .iter()
+            .position(|&pressed| pressed)
+            .map(|key| key as u8)
+    }
+}
+
 impl std::ops::Index<u8> for Registers {
     type Output = u8;
 
@@ -40,78 +91,579 @@ impl std::ops::IndexMut<u8> for Registers {
 }
 
 impl Chip8 {
-    pub fn new(screen: Screen) -> Self {
-        let mut mem = Box::new([0; 1024 * 4]);
-        mem[FONT_RANGE].copy_from_slice(FONT);
+    /// An emulator with the default memory layout. Use [`Chip8Config`] to
+    /// describe other machines.
+    pub fn new(quirks: Quirks) -> Self {
+        Chip8::with_config(Chip8Config::new().quirks(quirks))
+    }
+
+    /// Only called with validated configurations, apart from the quirks.
+    pub(crate) fn with_config(config: Chip8Config) -> Self {
+        let mut mem = vec![0; config.memory_len()].into_boxed_slice();
+        mem[config.font_range()].copy_from_slice(&config.font);
+        mem[config.big_font_range()].copy_from_slice(&config.big_font);
 
         Chip8 {
-            pc: 512,
+            pc: config.load_address,
             mem,
             ireg: 0,
-            stack: Vec::new(),
-            _dt: 0,
-            _st: 0,
+            stack: Vec::with_capacity(config.quirks.stack_depth),
+            stack_len: 0,
+            dt: 0,
+            st: 0,
+            timer_clock: 0,
             v: Registers([0; 16]),
-            screen,
+            keypad: Keypad([false; 16]),
+            pending_key: None,
+            vblank: false,
+            flags: [0; 16],
+            halted: false,
+            audio: Audio::new(),
+            rng: Box::new(SeededRandom::default()),
+            config,
+            screen: Framebuffer::new(),
+            frame_dirty: false,
+            access: None,
+            tracer: None,
+            cycles: 0,
+            program_hash: savestate::program_hash(&[]),
         }
     }
 
-    pub fn load_program(&mut self, program: &[u8]) {
-        let pc = self.pc as usize;
-        self.mem[pc..pc + program.len()].copy_from_slice(program)
+    /// Copies the program to the load address. Call this before running it.
+    pub fn load_program(&mut self, program: &[u8]) -> Result<(), Chip8Error> {
+        let range = self.config.program_range();
+        let max = range.len();
+        if program.len() > max {
+            return Err(Chip8Error::RomTooLarge {
+                size: program.len(),
+                max,
+            });
+        }
+        self.mem[range.start..range.start + program.len()].copy_from_slice(program);
+        self.program_hash = savestate::program_hash(program);
+        Ok(())
     }
 
-    pub fn run(&mut self) -> io::Result<()> {
-        loop {
-            self.tick()?;
+    /// A hash of the program passed to [`Chip8::load_program`].
+    #[inline]
+    pub fn program_hash(&self) -> u64 {
+        self.program_hash
+    }
+
+    /// Writes the complete state of the machine, to be restored with
+    /// [`Chip8::load_state`] into a machine running the same program on the
+    /// same platform.
+    pub fn save_state(&self, out: &mut impl io::Write) -> io::Result<()> {
+        savestate::write(self, &self.config, out)
+    }
+
+    /// Restores a state written by [`Chip8::save_state`], leaving the machine
+    /// untouched if it was saved from another program or platform.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        let (snapshot, mem) =
+            savestate::read(data, self.program_hash, &self.config, self.rng.kind())?;
+        self.restore(&snapshot, &mem);
+        Ok(())
+    }
+
+    #[inline]
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    #[inline]
+    pub fn ireg(&self) -> u16 {
+        self.ireg
+    }
+
+    /// The general purpose registers V0 to VF.
+    #[inline]
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.v.0
+    }
+
+    #[inline]
+    pub fn delay_timer(&self) -> u8 {
+        self.dt
+    }
+
+    #[inline]
+    pub fn sound_timer(&self) -> u8 {
+        self.st
+    }
+
+    #[inline]
+    pub fn memory(&self) -> &[u8] {
+        &self.mem
+    }
+
+    /// Number of instructions executed since the program started, not
+    /// counting those that faulted.
+    #[inline]
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
 
-            if event::poll(Duration::ZERO)? {
-                if event::read()? == Event::Key(KeyCode::Char('q').into()) {
-                    return Ok(());
+    /// Number of calls that have not returned yet.
+    #[inline]
+    pub fn call_depth(&self) -> usize {
+        self.stack_len
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    pub fn set_ireg(&mut self, ireg: u16) {
+        self.ireg = ireg;
+    }
+
+    /// Sets register VX, for `x` in `0x0..=0xF`.
+    pub fn set_register(&mut self, x: u8, value: u8) {
+        self.v[x & 0xF] = value;
+    }
+
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.dt = value;
+    }
+
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.st = value;
+    }
+
+    /// Overwrites memory starting at `addr`, e.g. to patch a running program.
+    pub fn write_memory(&mut self, addr: usize, bytes: &[u8]) -> Result<(), Chip8Error> {
+        let range = self.mem_range(addr, bytes.len())?;
+        self.mem[range].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    #[inline]
+    pub fn quirks(&self) -> &Quirks {
+        &self.config.quirks
+    }
+
+    /// The speed the machine was configured with, for driving it with a
+    /// [`Scheduler`](crate::Scheduler).
+    #[inline]
+    pub fn speed(&self) -> Speed {
+        self.config.speed
+    }
+
+    /// Address programs are loaded at and start from.
+    #[inline]
+    pub fn load_address(&self) -> u16 {
+        self.config.load_address
+    }
+
+    /// Checks that `len` bytes starting at `addr` lie within memory.
+    fn mem_range(&self, addr: usize, len: usize) -> Result<Range<usize>, Chip8Error> {
+        match addr.checked_add(len) {
+            Some(end) if end <= self.mem.len() => Ok(addr..end),
+            _ => Err(Chip8Error::MemoryOutOfRange { addr, len }),
+        }
+    }
+
+    /// Like [`Chip8::mem_range`], also reporting the range as accessed by the
+    /// current instruction.
+    fn access(
+        &mut self,
+        kind: AccessKind,
+        addr: usize,
+        len: usize,
+    ) -> Result<Range<usize>, Chip8Error> {
+        let range = self.mem_range(addr, len)?;
+        self.access = Some(MemoryAccess {
+            kind,
+            range: range.clone(),
+        });
+        Ok(range)
+    }
+
+    /// Counts the delay and sound timers down by the number of 60 Hz periods
+    /// that fit into `elapsed`, carrying the remainder over to the next call.
+    pub fn advance_timers(&mut self, elapsed: Duration) {
+        const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+        // Wide enough for any duration, however long the emulator was paused
+        let clock = self.timer_clock as u128 + elapsed.as_nanos() * TIMER_FREQUENCY as u128;
+        let periods = clock / NANOS_PER_SEC;
+        self.timer_clock = (clock % NANOS_PER_SEC) as u64;
+
+        let periods = periods.min(u8::MAX as u128) as u8;
+        self.count_down_timers(periods);
+    }
+
+    /// Counts the delay and sound timers down by a single 60 Hz period, for
+    /// frontends that drive the emulator one frame at a time.
+    pub fn tick_timers(&mut self) {
+        self.count_down_timers(1);
+    }
+
+    fn count_down_timers(&mut self, periods: u8) {
+        if periods > 0 {
+            self.vblank = true;
+        }
+        self.dt = self.dt.saturating_sub(periods);
+        self.st = self.st.saturating_sub(periods);
+    }
+
+    /// Whether the buzzer should currently be sounding.
+    #[inline]
+    pub fn sound_active(&self) -> bool {
+        self.st > 0
+    }
+
+    /// Replaces the generator behind `Cxkk`, e.g. with a seeded one to make
+    /// runs reproducible.
+    pub fn set_random(&mut self, rng: impl Random + 'static) {
+        self.rng = Box::new(rng);
+    }
+
+    #[inline]
+    pub(crate) fn random_kind(&self) -> RandomKind {
+        self.rng.kind()
+    }
+
+    /// Synthesizes the next `out.len()` samples of sound at the rate set with
+    /// [`Chip8::set_sample_rate`]. Call this at the rate samples are consumed;
+    /// the output is silent whenever the sound timer is zero.
+    pub fn fill_audio(&mut self, out: &mut [f32]) {
+        let active = self.sound_active();
+        self.audio.render(active, out);
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.audio.sample_rate()
+    }
+
+    /// Fails if `sample_rate` is outside of
+    /// [`SAMPLE_RATES`](audio::SAMPLE_RATES), keeping the previous rate.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) -> Result<(), InvalidSampleRate> {
+        self.audio.set_sample_rate(sample_rate)
+    }
+
+    /// Starts writing a line per executed instruction to `tracer`.
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Stops tracing, handing back the tracer to be finished.
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    /// Marks a key of the hexadecimal keypad (`0x0..=0xF`) as held down.
+    pub fn press_key(&mut self, key: u8) {
+        self.keypad.0[key as usize & 0xF] = true;
+    }
+
+    pub fn release_key(&mut self, key: u8) {
+        self.keypad.0[key as usize & 0xF] = false;
+    }
+
+    #[inline]
+    pub fn framebuffer(&self) -> &Framebuffer {
+        &self.screen
+    }
+
+    /// Whether the screen changed since it was last presented.
+    #[inline]
+    pub fn is_frame_dirty(&self) -> bool {
+        self.frame_dirty
+    }
+
+    /// Shows the screen on `display` if it changed since the last call.
+    pub fn present(&mut self, display: &mut impl Display) -> io::Result<()> {
+        if self.frame_dirty {
+            display.present(&self.screen)?;
+            self.frame_dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Whether the program has stopped itself with `00FD`.
+    #[inline]
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Executes one instruction, reporting faults as errors.
+    pub fn tick(&mut self) -> Result<(), Chip8Error> {
+        match self.step().outcome {
+            StepOutcome::Fault(err) => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Executes one instruction and reports what it did.
+    pub fn step(&mut self) -> Step {
+        let addr = self.pc;
+        if self.halted {
+            return Step {
+                addr,
+                op: None,
+                access: None,
+                outcome: StepOutcome::Halted,
+            };
+        }
+
+        let op = match self.instruction_at(addr) {
+            Ok(op) => op,
+            Err(err) => {
+                return Step {
+                    addr,
+                    op: None,
+                    access: None,
+                    outcome: StepOutcome::Fault(err),
                 }
             }
+        };
+        self.pc = self.pc.wrapping_add(op.size());
+
+        // Track this instruction's changes separately from earlier ones that
+        // were not presented yet
+        let was_dirty = std::mem::replace(&mut self.frame_dirty, false);
+        self.access = None;
+        let result = self.execute(addr, op);
+        let drew = self.frame_dirty;
+        self.frame_dirty |= was_dirty;
+
+        if let Some(mut tracer) = self.tracer.take() {
+            tracer.record(self, addr, op);
+            self.tracer = Some(tracer);
+        }
+        if result.is_ok() {
+            self.cycles += 1;
+        }
+
+        let outcome = match result {
+            Err(err) => {
+                // Leave PC on the faulting instruction, for debuggers to show
+                self.pc = addr;
+                StepOutcome::Fault(err)
+            }
+            Ok(()) if self.halted => StepOutcome::Halted,
+            Ok(()) if matches!(op, Op::GetKey(_)) && self.pc == addr => StepOutcome::WaitingForKey,
+            Ok(()) if drew => StepOutcome::FrameDirty,
+            Ok(()) => StepOutcome::Continued,
+        };
+        Step {
+            addr,
+            op: Some(op),
+            access: self.access.take(),
+            outcome,
+        }
+    }
+
+    /// Steps until `predicate` accepts a step, or until the program halts,
+    /// faults or blocks on a key press, returning the last step.
+    pub fn run_until(&mut self, mut predicate: impl FnMut(&Chip8, &Step) -> bool) -> Step {
+        loop {
+            let step = self.step();
+            let stopped = matches!(
+                step.outcome,
+                StepOutcome::Halted | StepOutcome::WaitingForKey | StepOutcome::Fault(_)
+            );
+            if stopped || predicate(self, &step) {
+                return step;
+            }
+        }
+    }
+
+    /// Runs `frames` frames at the configured [`speed`](Chip8::speed) without
+    /// waiting for the wall clock, like [`Scheduler::run_frames`](crate::Scheduler::run_frames).
+    pub fn run_frames(&mut self, frames: u32) -> Result<(), Chip8Error> {
+        crate::Scheduler::new(self.speed()).run_frames(self, frames)
+    }
+
+    /// Decodes the instruction at `addr` without executing it.
+    pub fn instruction_at(&self, addr: u16) -> Result<Op, Chip8Error> {
+        let addr = addr as usize;
+        // Decoding only fails when the instruction runs past the end of memory
+        Op::decode(&self.mem[addr.min(self.mem.len())..])
+            .ok_or(Chip8Error::MemoryOutOfRange { addr, len: 2 })
+    }
+
+    /// Return addresses on the stack, from the outermost call to the innermost.
+    pub fn call_stack(&self) -> Vec<u16> {
+        match self.config.quirks.stack_location {
+            StackLocation::Internal => self.stack.clone(),
+            StackLocation::Memory { top } => (0..self.stack_len)
+                .filter_map(|level| self.read_stack_slot(top, level).ok())
+                .collect(),
+        }
+    }
+
+    /// Captures everything but the memory, which callers keep track of
+    /// themselves as it is large and changes little between snapshots.
+    pub(crate) fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            pc: self.pc,
+            ireg: self.ireg,
+            stack: self.stack.clone(),
+            stack_len: self.stack_len,
+            dt: self.dt,
+            st: self.st,
+            timer_clock: self.timer_clock,
+            v: self.v.0,
+            keypad: self.keypad.0,
+            pending_key: self.pending_key,
+            vblank: self.vblank,
+            flags: self.flags,
+            halted: self.halted,
+            audio: self.audio.clone(),
+            rng: self.rng.state(),
+            screen: Rc::new(self.screen.clone()),
+            cycles: self.cycles,
+        }
+    }
+
+    /// Returns to a snapshot, with `mem` as the memory at the time it was
+    /// taken.
+    pub(crate) fn restore(&mut self, snapshot: &Snapshot, mem: &[u8]) {
+        self.mem.copy_from_slice(mem);
+        self.pc = snapshot.pc;
+        self.ireg = snapshot.ireg;
+        self.stack.clone_from(&snapshot.stack);
+        self.stack_len = snapshot.stack_len;
+        self.dt = snapshot.dt;
+        self.st = snapshot.st;
+        self.timer_clock = snapshot.timer_clock;
+        self.v.0 = snapshot.v;
+        self.keypad.0 = snapshot.keypad;
+        self.pending_key = snapshot.pending_key;
+        self.vblank = snapshot.vblank;
+        self.flags = snapshot.flags;
+        self.halted = snapshot.halted;
+        // The sample rate belongs to the frontend, not to the snapshot
+        let sample_rate = self.audio.sample_rate();
+        self.audio.clone_from(&snapshot.audio);
+        self.audio.sample_rate = sample_rate;
+        self.rng.set_state(snapshot.rng);
+        self.screen.clone_from(&snapshot.screen);
+        self.frame_dirty = true;
+        self.access = None;
+        self.cycles = snapshot.cycles;
+    }
+
+    /// Where the return address of the given nesting level is kept when the
+    /// stack lives in memory: two bytes each, growing down from `top`.
+    fn stack_slot(&self, top: u16, level: usize) -> Result<Range<usize>, Chip8Error> {
+        let addr = (top as usize).wrapping_sub(2 * (level + 1));
+        self.mem_range(addr, 2)
+    }
+
+    fn read_stack_slot(&self, top: u16, level: usize) -> Result<u16, Chip8Error> {
+        let range = self.stack_slot(top, level)?;
+        Ok(u16::from_be_bytes([
+            self.mem[range.start],
+            self.mem[range.start + 1],
+        ]))
+    }
+
+    fn push(&mut self, addr: u16) -> Result<(), Chip8Error> {
+        match self.config.quirks.stack_location {
+            StackLocation::Internal => self.stack.push(addr),
+            StackLocation::Memory { top } => {
+                let range = self.stack_slot(top, self.stack_len)?;
+                self.access(AccessKind::Write, range.start, 2)?;
+                self.mem[range].copy_from_slice(&addr.to_be_bytes());
+            }
+        }
+        self.stack_len += 1;
+        Ok(())
+    }
+
+    /// Must only be called with at least one address on the stack.
+    fn pop(&mut self) -> Result<u16, Chip8Error> {
+        self.stack_len -= 1;
+        match self.config.quirks.stack_location {
+            StackLocation::Internal => Ok(self.stack.pop().expect("stack is not empty")),
+            StackLocation::Memory { top } => {
+                let range = self.stack_slot(top, self.stack_len)?;
+                self.access(AccessKind::Read, range.start, 2)?;
+                self.read_stack_slot(top, self.stack_len)
+            }
         }
     }
 
-    fn tick(&mut self) -> io::Result<()> {
-        let (_, op) = Op::from_bytes((&self.mem[self.pc as usize..], 0)).unwrap();
-        self.pc += 2;
-        self.execute(op)
+    /// Steps over the next instruction, which may be the four-byte `F000 NNNN`.
+    fn skip(&mut self) -> Result<(), Chip8Error> {
+        let next = self.mem_range(self.pc as usize, 2)?;
+        let size = if self.mem[next] == [0xF0, 0x00] { 4 } else { 2 };
+        self.pc = self.pc.wrapping_add(size);
+        Ok(())
     }
 
-    fn execute(&mut self, op: Op) -> io::Result<()> {
+    fn execute(&mut self, addr: u16, op: Op) -> Result<(), Chip8Error> {
         match op {
-            Op::AbsJump(addr) => {
-                self.pc = addr;
+            Op::AbsJump(target) => {
+                self.pc = target;
             }
-            Op::Call(addr) => {
-                self.stack.push(self.pc);
-                self.pc = addr;
+            Op::Call(target) => {
+                if self.stack_len == self.config.quirks.stack_depth {
+                    return Err(Chip8Error::StackOverflow { addr });
+                }
+                self.push(self.pc)?;
+                self.pc = target;
             }
             Op::Return => {
-                self.pc = self.stack.pop().expect("stack underflow");
+                if self.stack_len == 0 {
+                    return Err(Chip8Error::StackUnderflow { addr });
+                }
+                self.pc = self.pop()?;
             }
             Op::Clear => {
-                self.screen.clear()?;
+                self.screen.clear();
+                self.frame_dirty = true;
+            }
+            Op::ScrollDown(n) => {
+                self.screen.scroll(0, n as isize);
+                self.frame_dirty = true;
+            }
+            Op::ScrollUp(n) => {
+                self.screen.scroll(0, -(n as isize));
+                self.frame_dirty = true;
+            }
+            Op::ScrollRight => {
+                self.screen.scroll(4, 0);
+                self.frame_dirty = true;
+            }
+            Op::ScrollLeft => {
+                self.screen.scroll(-4, 0);
+                self.frame_dirty = true;
+            }
+            Op::Exit => {
+                self.halted = true;
+            }
+            Op::LowRes => {
+                self.screen.set_hires(false);
+                self.frame_dirty = true;
+            }
+            Op::HighRes => {
+                self.screen.set_hires(true);
+                self.frame_dirty = true;
             }
             Op::SkipEqVal(x, val) => {
                 if self.v[x] == val {
-                    self.pc += 2;
+                    self.skip()?;
                 }
             }
             Op::SkipNeqVal(x, val) => {
                 if self.v[x] != val {
-                    self.pc += 2;
+                    self.skip()?;
                 }
             }
             Op::SkipEqReg(x, y) => {
                 if self.v[x] == self.v[y] {
-                    self.pc += 2;
+                    self.skip()?;
                 }
             }
             Op::SkipNeqReg(x, y) => {
                 if self.v[x] != self.v[y] {
-                    self.pc += 2;
+                    self.skip()?;
                 }
             }
             Op::SetVal(x, val) => {
@@ -126,146 +678,814 @@ impl Chip8 {
             }
             Op::Or(x, y) => {
                 self.v[x] |= self.v[y];
+                if self.config.quirks.logic_resets_vf {
+                    self.v[0xF] = 0;
+                }
             }
             Op::And(x, y) => {
                 self.v[x] &= self.v[y];
+                if self.config.quirks.logic_resets_vf {
+                    self.v[0xF] = 0;
+                }
             }
             Op::Xor(x, y) => {
                 self.v[x] ^= self.v[y];
+                if self.config.quirks.logic_resets_vf {
+                    self.v[0xF] = 0;
+                }
             }
+            // The flag is written after the result, so it wins when `x` is VF
             Op::Add(x, y) => {
                 let (result, overflow) = self.v[x].overflowing_add(self.v[y]);
-                self.v[0xF] = overflow as u8;
                 self.v[x] = result;
+                self.v[0xF] = overflow as u8;
             }
             Op::Sub(x, y) => {
-                let (result, overflow) = self.v[x].overflowing_sub(self.v[y]);
-                self.v[0xF] = overflow as u8;
+                let (result, borrow) = self.v[x].overflowing_sub(self.v[y]);
                 self.v[x] = result;
+                self.v[0xF] = !borrow as u8;
             }
             Op::SubN(x, y) => {
-                let (result, overflow) = self.v[y].overflowing_sub(self.v[x]);
-                self.v[0xF] = overflow as u8;
+                let (result, borrow) = self.v[y].overflowing_sub(self.v[x]);
                 self.v[x] = result;
+                self.v[0xF] = !borrow as u8;
             }
             Op::Shr(x, y) => {
-                self.v[x] = self.v[y]; // TODO: add config to skip this
-                self.v[0xF] = self.v[x] & 0b0000_0001;
-                self.v[x] >>= 1;
+                let value = if self.config.quirks.shift_copies_vy {
+                    self.v[y]
+                } else {
+                    self.v[x]
+                };
+                self.v[x] = value >> 1;
+                self.v[0xF] = value & 0b0000_0001;
             }
             Op::Shl(x, y) => {
-                self.v[x] = self.v[y]; // TODO: add config to skip this
-                self.v[0xF] = self.v[x] & 0b1000_0000;
-                self.v[x] <<= 1;
+                let value = if self.config.quirks.shift_copies_vy {
+                    self.v[y]
+                } else {
+                    self.v[x]
+                };
+                self.v[x] = value << 1;
+                self.v[0xF] = value >> 7;
+            }
+            Op::SetIndex(target) => {
+                self.ireg = target;
             }
-            Op::SetIndex(addr) => {
-                self.ireg = addr;
+            Op::LongIndex(target) => {
+                self.ireg = target;
             }
-            Op::OffsetJump(addr) => {
-                self.pc = addr + self.v[0] as u16;
+            Op::SelectPlanes(planes) => {
+                self.screen.select_planes(planes);
+            }
+            Op::LoadAudio => {
+                let range =
+                    self.access(AccessKind::Read, self.ireg as usize, audio::PATTERN_SIZE)?;
+                let pattern = self.mem[range].try_into().unwrap();
+                self.audio.load_pattern(pattern);
+            }
+            Op::SetPitch(x) => {
+                self.audio.set_pitch(self.v[x]);
+            }
+            Op::SaveRange(x, y) => {
+                let registers = register_range(x, y);
+                let range = self.access(AccessKind::Write, self.ireg as usize, registers.len())?;
+                for (addr, reg) in range.zip(registers) {
+                    self.mem[addr] = self.v[reg];
+                }
+            }
+            Op::LoadRange(x, y) => {
+                let registers = register_range(x, y);
+                let range = self.access(AccessKind::Read, self.ireg as usize, registers.len())?;
+                for (addr, reg) in range.zip(registers) {
+                    self.v[reg] = self.mem[addr];
+                }
+            }
+            Op::OffsetJump(target) => {
+                let offset = if self.config.quirks.jump_uses_vx {
+                    self.v[(target >> 8) as u8]
+                } else {
+                    self.v[0]
+                };
+                self.pc = target + offset as u16;
             }
             Op::Rand(x, val) => {
-                self.v[x] = fastrand::u8(..) & val;
+                self.v[x] = self.rng.next_byte(&self.mem) & val;
+            }
+            Op::GetDelay(x) => {
+                self.v[x] = self.dt;
+            }
+            Op::SetDelay(x) => {
+                self.dt = self.v[x];
+            }
+            Op::SetSoundTimer(x) => {
+                self.st = self.v[x];
+            }
+            Op::SkipKey(x) => {
+                if self.keypad.is_pressed(self.v[x]) {
+                    self.skip()?;
+                }
+            }
+            Op::SkipNoKey(x) => {
+                if !self.keypad.is_pressed(self.v[x]) {
+                    self.skip()?;
+                }
+            }
+            Op::GetKey(x) => {
+                // Like the COSMAC VIP, only complete once the key is released again
+                match self.pending_key {
+                    Some(key) if !self.keypad.is_pressed(key) => {
+                        self.v[x] = key;
+                        self.pending_key = None;
+                    }
+                    pending => {
+                        self.pending_key = pending.or_else(|| self.keypad.first_pressed());
+                        self.pc = addr;
+                    }
+                }
+            }
+            Op::IncrIndex(x) => {
+                self.ireg = self.ireg.wrapping_add(self.v[x] as u16);
+            }
+            Op::SetSpriteI(x) => {
+                let digit = (self.v[x] & 0xF) as u16;
+                self.ireg = self.config.font_address + digit * FONT_CHAR_SIZE as u16;
+            }
+            Op::SetBigSpriteI(x) => {
+                let digit = (self.v[x] & 0xF) as u16;
+                self.ireg = self.config.big_font_address + digit * BIG_FONT_CHAR_SIZE as u16;
+            }
+            Op::DumpFlags(x) => {
+                let n = x as usize + 1;
+                self.flags[..n].copy_from_slice(&self.v.0[..n]);
+            }
+            Op::LoadFlags(x) => {
+                let n = x as usize + 1;
+                self.v.0[..n].copy_from_slice(&self.flags[..n]);
+            }
+            Op::DecimalRepr(x) => {
+                let range = self.access(AccessKind::Write, self.ireg as usize, 3)?;
+                let vx = self.v[x];
+                self.mem[range].copy_from_slice(&[vx / 100, vx / 10 % 10, vx % 10]);
+            }
+            Op::DumpRegisters(x) => {
+                let n = x as usize + 1;
+                let range = self.access(AccessKind::Write, self.ireg as usize, n)?;
+                self.mem[range].copy_from_slice(&self.v.0[..n]);
+                self.ireg = self
+                    .ireg
+                    .wrapping_add(self.config.quirks.index_increment.amount(x));
+            }
+            Op::LoadRegisters(x) => {
+                let n = x as usize + 1;
+                let range = self.access(AccessKind::Read, self.ireg as usize, n)?;
+                self.v.0[..n].copy_from_slice(&self.mem[range]);
+                self.ireg = self
+                    .ireg
+                    .wrapping_add(self.config.quirks.index_increment.amount(x));
             }
             Op::Draw(x, y, height) => {
-                let sprite = &self.mem[self.ireg as usize..(self.ireg as usize + height as usize)];
-                let start_x = self.v[x] as usize & (SCREEN_WIDTH - 1);
-                let start_y = self.v[y] as usize & (SCREEN_HEIGHT - 1);
-                self.v[0xF] = 0;
-
-                for (screen_y, row) in (start_y..SCREEN_HEIGHT).zip(sprite) {
-                    for (screen_x, bit) in
-                        (start_x..SCREEN_WIDTH).zip(row.view_bits::<deku::bitvec::Msb0>())
-                    {
-                        let pixel = self.screen.pixel_mut(screen_x, screen_y);
-                        match (*pixel, *bit) {
-                            (true, true) => {
-                                *pixel = false;
-                                self.v[0xF] = 1;
+                if self.config.quirks.display_wait {
+                    if !self.vblank {
+                        self.pc = addr;
+                        return Ok(());
+                    }
+                    self.vblank = false;
+                }
+
+                // `Dxy0` draws a 16x16 sprite made of two bytes per row
+                let (bytes_per_row, rows) = if height == 0 {
+                    (2, 16)
+                } else {
+                    (1, height as usize)
+                };
+                let sprite_size = bytes_per_row * rows;
+                let (width, height) = (self.screen.width(), self.screen.height());
+                let start_x = self.v[x] as usize % width;
+                let start_y = self.v[y] as usize % height;
+
+                // Each selected plane reads its own sprite, stored one after another
+                let mut collided_rows = 0;
+                let selected = self.screen.planes();
+                let planes = (0..2).map(|n| 1 << n).filter(|p| selected & p != 0);
+                let n_planes = selected.count_ones() as usize;
+                self.access(AccessKind::Read, self.ireg as usize, n_planes * sprite_size)?;
+                for (n, plane) in planes.enumerate() {
+                    let range =
+                        self.mem_range(self.ireg as usize + n * sprite_size, sprite_size)?;
+                    let sprite = &self.mem[range];
+
+                    for (dy, row) in sprite.chunks(bytes_per_row).enumerate() {
+                        let mut screen_y = start_y + dy;
+                        if screen_y >= height {
+                            if !self.config.quirks.wrap_sprites {
+                                // SUPER-CHIP counts rows clipped at the bottom as collisions
+                                if self.screen.is_hires() {
+                                    collided_rows += rows - dy;
+                                }
+                                break;
                             }
-                            (false, true) => *pixel = true,
-                            _ => (),
+                            screen_y %= height;
                         }
+                        let mut collided = false;
+                        for (dx, bit) in row.view_bits::<deku::bitvec::Msb0>().iter().enumerate() {
+                            let mut screen_x = start_x + dx;
+                            if screen_x >= width {
+                                if !self.config.quirks.wrap_sprites {
+                                    break;
+                                }
+                                screen_x %= width;
+                            }
+                            if *bit {
+                                let pixel = self.screen.pixel_mut(screen_x, screen_y);
+                                collided |= *pixel & plane != 0;
+                                *pixel ^= plane;
+                            }
+                        }
+                        collided_rows += collided as usize;
                     }
                 }
-                self.screen.draw()?;
+
+                // In high resolution VF holds the number of rows that collided
+                self.v[0xF] = if self.screen.is_hires() {
+                    collided_rows as u8
+                } else {
+                    (collided_rows > 0) as u8
+                };
+                self.frame_dirty = true;
+            }
+            Op::Unknown(opcode) => {
+                return Err(Chip8Error::UnknownOpcode { addr, opcode });
             }
-            _ => todo!("{:?}", op),
         }
 
         Ok(())
     }
 }
 
-const FONT_RANGE: RangeInclusive<usize> = 0x50..=0x9F;
-const FONT: &[u8] = &[
-    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
-    0x20, 0x60, 0x20, 0x20, 0x70, // 1
-    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
-    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
-    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
-    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
-    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
-    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
-    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
-    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
-    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
-    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
-    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
-    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
-    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
-    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
-];
-
-pub const SCREEN_WIDTH: usize = 64;
-pub const SCREEN_HEIGHT: usize = 32;
-const N_PIXELS: usize = SCREEN_WIDTH * SCREEN_HEIGHT;
-
-pub struct Screen {
-    pixels: [bool; N_PIXELS],
-    output: io::Stdout,
+/// The result of executing a single instruction with [`Chip8::step`].
+#[derive(Debug)]
+pub struct Step {
+    /// Where the instruction was fetched from.
+    pub addr: u16,
+    /// The decoded instruction, unless nothing could be fetched.
+    pub op: Option<Op>,
+    /// The memory the instruction read or wrote, if any.
+    pub access: Option<MemoryAccess>,
+    pub outcome: StepOutcome,
+}
+
+#[derive(Debug)]
+pub enum StepOutcome {
+    Continued,
+    /// The instruction changed the screen.
+    FrameDirty,
+    /// `Fx0A` is blocked until a key is pressed and released.
+    WaitingForKey,
+    /// The program exited, either now or earlier.
+    Halted,
+    Fault(Chip8Error),
+}
+
+/// Data read or written by an instruction. Instruction fetches are not
+/// included.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub kind: AccessKind,
+    pub range: Range<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// The state of a [`Chip8`] at some point, apart from its memory and its
+/// configuration.
+#[derive(Clone)]
+pub(crate) struct Snapshot {
+    pub(crate) pc: u16,
+    pub(crate) ireg: u16,
+    pub(crate) stack: Vec<u16>,
+    pub(crate) stack_len: usize,
+    pub(crate) dt: u8,
+    pub(crate) st: u8,
+    pub(crate) timer_clock: u64,
+    pub(crate) v: [u8; 16],
+    pub(crate) keypad: [bool; 16],
+    pub(crate) pending_key: Option<u8>,
+    pub(crate) vblank: bool,
+    pub(crate) flags: [u8; 16],
+    pub(crate) halted: bool,
+    pub(crate) audio: Audio,
+    pub(crate) rng: u64,
+    /// Shared between snapshots while the screen does not change.
+    pub(crate) screen: Rc<Framebuffer>,
+    pub(crate) cycles: u64,
 }
 
-impl Screen {
-    const ON: &str = "\u{2588}\u{2588}";
-    const OFF: &str = "  ";
+/// Faults that stop the emulated program.
+#[derive(Debug)]
+pub enum Chip8Error {
+    /// `Call` with every level of the stack already in use.
+    StackOverflow {
+        addr: u16,
+    },
+    /// `Return` with an empty stack.
+    StackUnderflow {
+        addr: u16,
+    },
+    /// An access of `len` bytes at `addr` reaching past the end of memory.
+    MemoryOutOfRange {
+        addr: usize,
+        len: usize,
+    },
+    UnknownOpcode {
+        addr: u16,
+        opcode: u16,
+    },
+    /// The program does not fit between the load address and the end of memory.
+    RomTooLarge {
+        size: usize,
+        max: usize,
+    },
+}
 
-    pub fn new(output: io::Stdout) -> Self {
-        Screen {
-            pixels: [false; N_PIXELS],
-            output,
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Chip8Error::StackOverflow { addr } => write!(f, "stack overflow at {addr:#05x}"),
+            Chip8Error::StackUnderflow { addr } => write!(f, "stack underflow at {addr:#05x}"),
+            Chip8Error::MemoryOutOfRange { addr, len } => write!(
+                f,
+                "memory access of {len} bytes at {addr:#05x} is out of range"
+            ),
+            Chip8Error::UnknownOpcode { addr, opcode } => {
+                write!(f, "unknown opcode {opcode:#06x} at {addr:#05x}")
+            }
+            Chip8Error::RomTooLarge { size, max } => write!(
+                f,
+                "program is {size} bytes, but at most {max} bytes fit into memory"
+            ),
         }
     }
+}
 
-    #[inline]
-    fn pixel(&self, x: usize, y: usize) -> &bool {
-        &self.pixels[SCREEN_WIDTH * y + x]
+impl error::Error for Chip8Error {}
+
+/// Iterates over the registers from VX to VY, backwards if X is above Y.
+fn register_range(x: u8, y: u8) -> Vec<u8> {
+    if x <= y {
+        (x..=y).collect()
+    } else {
+        (y..=x).rev().collect()
     }
-    #[inline]
-    fn pixel_mut(&mut self, x: usize, y: usize) -> &mut bool {
-        &mut self.pixels[SCREEN_WIDTH * y + x]
+}
+
+pub const TIMER_FREQUENCY: u64 = 60;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Platform;
+
+    fn chip8(program: &[u8]) -> Chip8 {
+        let mut chip8 = Chip8::new(Quirks::CHIP8);
+        chip8.load_program(program).unwrap();
+        chip8
     }
 
-    fn draw(&mut self) -> io::Result<()> {
-        for y in 0..SCREEN_HEIGHT {
-            for x in 0..SCREEN_WIDTH {
-                let symbol = if *self.pixel(x, y) {
-                    Self::ON
-                } else {
-                    Self::OFF
-                };
-                self.output
-                    .queue(cursor::MoveTo(x as u16 * 2, y as u16))?
-                    .queue(style::Print(symbol))?;
-            }
+    #[test]
+    fn fault_leaves_pc_on_the_instruction() {
+        let mut chip8 = chip8(&[0x00, 0xEE]);
+        let step = chip8.step();
+        assert!(matches!(
+            step.outcome,
+            StepOutcome::Fault(Chip8Error::StackUnderflow { addr: 0x200 })
+        ));
+        assert_eq!(chip8.pc(), 0x200);
+        assert!(matches!(chip8.step().outcome, StepOutcome::Fault(_)));
+        assert_eq!(chip8.pc(), 0x200);
+        assert_eq!(chip8.cycles(), 0);
+    }
+
+    #[test]
+    fn faults_are_not_counted_as_cycles() {
+        // LD V0, 1; RET with nothing to return to
+        let mut chip8 = chip8(&[0x60, 0x01, 0x00, 0xEE]);
+        chip8.step();
+        assert!(matches!(chip8.step().outcome, StepOutcome::Fault(_)));
+        assert_eq!(chip8.cycles(), 1);
+
+        // An instruction that cannot even be decoded
+        chip8.set_pc(0xFFF);
+        assert!(matches!(chip8.step().outcome, StepOutcome::Fault(_)));
+        assert_eq!(chip8.cycles(), 1);
+    }
+
+    #[test]
+    fn index_out_of_range() {
+        // LD I, #FFF; LD [I], V1
+        let mut chip8 = chip8(&[0xAF, 0xFF, 0xF1, 0x55]);
+        chip8.step();
+        assert!(matches!(
+            chip8.step().outcome,
+            StepOutcome::Fault(Chip8Error::MemoryOutOfRange {
+                addr: 0xFFF,
+                len: 2
+            })
+        ));
+        assert_eq!(chip8.pc(), 0x202);
+    }
+
+    #[test]
+    fn unknown_opcode() {
+        let mut chip8 = chip8(&[0x00, 0xE0, 0xE1, 0x23]);
+        chip8.step();
+        assert!(matches!(
+            chip8.step().outcome,
+            StepOutcome::Fault(Chip8Error::UnknownOpcode {
+                addr: 0x202,
+                opcode: 0xE123
+            })
+        ));
+    }
+
+    #[test]
+    fn rom_too_large() {
+        let mut chip8 = Chip8::new(Quirks::CHIP8);
+        assert!(chip8.load_program(&[0; 0xE00]).is_ok());
+        assert!(matches!(
+            chip8.load_program(&[0; 0xE01]),
+            Err(Chip8Error::RomTooLarge {
+                size: 0xE01,
+                max: 0xE00
+            })
+        ));
+    }
+
+    #[test]
+    fn stack_overflow() {
+        // A subroutine calling itself
+        let mut chip8 = chip8(&[0x22, 0x00]);
+        for _ in 0..Quirks::CHIP8.stack_depth {
+            assert!(matches!(chip8.step().outcome, StepOutcome::Continued));
         }
-        self.output.flush()
+        assert!(matches!(
+            chip8.step().outcome,
+            StepOutcome::Fault(Chip8Error::StackOverflow { addr: 0x200 })
+        ));
+        assert_eq!(chip8.call_depth(), Quirks::CHIP8.stack_depth);
     }
 
-    #[inline]
-    fn clear(&mut self) -> io::Result<()> {
-        self.output
-            .execute(terminal::Clear(terminal::ClearType::All))?;
-        Ok(())
+    #[test]
+    fn stack_in_memory() {
+        let mut chip8 = Chip8Config::new()
+            .stack_location(StackLocation::VIP)
+            .build()
+            .unwrap();
+        // CALL #204; JP #202; CALL #208; RET
+        chip8
+            .load_program(&[0x22, 0x04, 0x12, 0x02, 0x22, 0x08, 0x00, 0x00, 0x00, 0xEE])
+            .unwrap();
+        chip8.step();
+        chip8.step();
+        assert_eq!(chip8.memory()[0xECC..0xED0], [0x02, 0x06, 0x02, 0x02]);
+        chip8.step();
+        assert_eq!(chip8.pc(), 0x206);
+        assert_eq!(chip8.call_depth(), 1);
+    }
+
+    #[test]
+    fn run_frames_ticks_the_timers_once_per_frame() {
+        // LD VA, 60; LD DT, VA; loop: ADD V0, 1; JP loop
+        let mut chip8 = chip8(&[0x6A, 0x3C, 0xFA, 0x15, 0x70, 0x01, 0x12, 0x04]);
+        chip8.run_frames(2).unwrap();
+        assert_eq!(chip8.cycles(), 30);
+        assert_eq!(chip8.registers()[0], 14);
+        assert_eq!(chip8.delay_timer(), 58);
+    }
+
+    #[test]
+    fn timers_count_down_at_60_hz() {
+        let mut chip8 = chip8(&[]);
+        chip8.set_delay_timer(10);
+        chip8.set_sound_timer(2);
+        chip8.advance_timers(Duration::from_millis(50));
+        assert_eq!((chip8.delay_timer(), chip8.sound_timer()), (7, 0));
+        assert!(!chip8.sound_active());
+
+        // Periods that did not complete are carried over
+        chip8.advance_timers(Duration::from_millis(10));
+        assert_eq!(chip8.delay_timer(), 7);
+        chip8.advance_timers(Duration::from_millis(10));
+        assert_eq!(chip8.delay_timer(), 6);
+
+        chip8.tick_timers();
+        assert_eq!(chip8.delay_timer(), 5);
+
+        chip8.advance_timers(Duration::MAX);
+        assert_eq!(chip8.delay_timer(), 0);
+    }
+
+    #[test]
+    fn get_key_waits_for_release() {
+        // LD V3, K
+        let mut chip8 = chip8(&[0xF3, 0x0A]);
+        assert!(matches!(chip8.step().outcome, StepOutcome::WaitingForKey));
+        chip8.press_key(0x7);
+        assert!(matches!(chip8.step().outcome, StepOutcome::WaitingForKey));
+        // Other keys pressed meanwhile do not count
+        chip8.press_key(0x2);
+        chip8.release_key(0x7);
+        assert!(matches!(chip8.step().outcome, StepOutcome::Continued));
+        assert_eq!(chip8.registers()[3], 0x7);
+        assert_eq!(chip8.pc(), 0x202);
+    }
+
+    #[test]
+    fn get_key_at_the_end_of_memory() {
+        let mut chip8 = Chip8Config::new().memory_size(0x10000).build().unwrap();
+        chip8.write_memory(0xFFFE, &[0xF3, 0x0A]).unwrap();
+        chip8.set_pc(0xFFFE);
+        assert!(matches!(chip8.step().outcome, StepOutcome::WaitingForKey));
+        assert_eq!(chip8.pc(), 0xFFFE);
+    }
+
+    #[test]
+    fn skip_on_key() {
+        // LD V1, 5; SKP V1; SKNP V1
+        let mut chip8 = chip8(&[0x61, 0x05, 0xE1, 0x9E, 0xE1, 0xA1]);
+        chip8.step();
+        chip8.step();
+        assert_eq!(chip8.pc(), 0x204);
+        chip8.press_key(0x5);
+        chip8.step();
+        assert_eq!(chip8.pc(), 0x206);
+
+        chip8.set_pc(0x202);
+        chip8.step();
+        assert_eq!(chip8.pc(), 0x206);
+    }
+
+    /// Runs `steps` instructions of `program` with `quirks`, after a timer
+    /// tick so a draw does not have to wait.
+    fn run(quirks: Quirks, program: &[u8], steps: usize) -> Chip8 {
+        let mut chip8 = Chip8::new(quirks);
+        chip8.load_program(program).unwrap();
+        chip8.tick_timers();
+        for _ in 0..steps {
+            let step = chip8.step();
+            assert!(!matches!(step.outcome, StepOutcome::Fault(_)), "{step:?}");
+        }
+        chip8
+    }
+
+    #[test]
+    fn decimal_digits() {
+        // LD V4, 137; LD I, #300; LD B, V4
+        let chip8 = run(Quirks::CHIP8, &[0x64, 137, 0xA3, 0x00, 0xF4, 0x33], 3);
+        assert_eq!(chip8.memory()[0x300..0x303], [1, 3, 7]);
+        assert_eq!(chip8.ireg(), 0x300);
+    }
+
+    #[test]
+    fn font_digits() {
+        // LD V0, #1A; LD F, V0
+        let chip8 = run(Quirks::CHIP8, &[0x60, 0x1A, 0xF0, 0x29], 2);
+        let addr = chip8.ireg() as usize;
+        assert_eq!(addr, 0x50 + 0xA * FONT_CHAR_SIZE);
+        assert_eq!(
+            chip8.memory()[addr..addr + FONT_CHAR_SIZE],
+            [0xF0, 0x90, 0xF0, 0x90, 0x90]
+        );
+    }
+
+    #[test]
+    fn add_to_index() {
+        // LD I, #FFF; LD V5, 2; ADD I, V5
+        let chip8 = run(Quirks::CHIP8, &[0xAF, 0xFF, 0x65, 0x02, 0xF5, 0x1E], 3);
+        assert_eq!(chip8.ireg(), 0x1001);
+    }
+
+    #[test]
+    fn store_and_load_registers() {
+        // I moves past the registers on the VIP, by X on CHIP-48, not at all
+        // on SUPER-CHIP
+        for (quirks, ireg) in [
+            (Quirks::CHIP8, 0x303),
+            (Quirks::CHIP48, 0x302),
+            (Quirks::SCHIP, 0x301),
+        ] {
+            #[rustfmt::skip]
+            let program = [
+                0x60, 0x11, 0x61, 0x22, 0x62, 0x33, // LD V0-V2
+                0xA3, 0x00, 0xF2, 0x55,             // LD I, #300; LD [I], V2
+                0xA3, 0x01, 0xF1, 0x65,             // LD I, #301; LD V1, [I]
+            ];
+            let chip8 = run(quirks, &program, 7);
+            assert_eq!(chip8.memory()[0x300..0x304], [0x11, 0x22, 0x33, 0x00]);
+            assert_eq!(chip8.registers()[..3], [0x22, 0x33, 0x33]);
+            assert_eq!(chip8.ireg(), ireg, "{quirks:?}");
+        }
+    }
+
+    #[test]
+    fn quirk_presets() {
+        for &(name, platform) in Platform::ALL {
+            let quirks = platform.quirks();
+
+            // LD V0, 1; LD V1, 4; SHR V0, V1
+            let chip8 = run(quirks, &[0x60, 0x01, 0x61, 0x04, 0x80, 0x16], 3);
+            let shifted = if quirks.shift_copies_vy { 2 } else { 0 };
+            assert_eq!(chip8.registers()[0], shifted, "{name}");
+
+            // LD VF, 5; OR V0, V1
+            let chip8 = run(quirks, &[0x6F, 0x05, 0x80, 0x11], 2);
+            let vf = if quirks.logic_resets_vf { 0 } else { 5 };
+            assert_eq!(chip8.registers()[0xF], vf, "{name}");
+
+            // LD V0, 4; LD V2, #10; JP V0, #210
+            let chip8 = run(quirks, &[0x60, 0x04, 0x62, 0x10, 0xB2, 0x10], 3);
+            let target = if quirks.jump_uses_vx { 0x220 } else { 0x214 };
+            assert_eq!(chip8.pc(), target, "{name}");
+
+            // LD V0, 62; LD V1, 0; LD F, V1; DRW V0, V1, 5
+            let chip8 = run(quirks, &[0x60, 62, 0x61, 0, 0xF1, 0x29, 0xD0, 0x15], 4);
+            let wrapped = chip8.framebuffer().pixel(0, 0) != 0;
+            assert_eq!(wrapped, quirks.wrap_sprites, "{name}");
+
+            // A second draw in the same frame
+            let mut chip8 = run(quirks, &[0xD0, 0x15, 0xD0, 0x15], 1);
+            chip8.step();
+            let waited = chip8.pc() == 0x202;
+            assert_eq!(waited, quirks.display_wait, "{name}");
+        }
+    }
+
+    /// The positions of the pixels set in any plane.
+    fn lit(chip8: &Chip8) -> Vec<(usize, usize)> {
+        let screen = chip8.framebuffer();
+        let mut lit = Vec::new();
+        for (y, row) in screen.rows().enumerate() {
+            lit.extend((0..row.len()).filter(|&x| row[x] != 0).map(|x| (x, y)));
+        }
+        lit
+    }
+
+    #[test]
+    fn hires_sprites() {
+        // HIGH; LD I, sprite; DRW V0, V1, 0; DRW V0, V1, 0
+        let mut program = vec![0x00, 0xFF, 0xA2, 0x08, 0xD0, 0x10, 0xD0, 0x10];
+        program.extend([0xFF; 32]);
+        let mut chip8 = run(Quirks::SCHIP, &program, 3);
+        assert!(chip8.framebuffer().is_hires());
+        assert_eq!(chip8.framebuffer().width(), 128);
+        let pixels = lit(&chip8);
+        assert_eq!(pixels.len(), 16 * 16);
+        assert_eq!(pixels.last(), Some(&(15, 15)));
+        assert_eq!(chip8.registers()[0xF], 0);
+
+        // Every row collides when drawn again
+        chip8.step();
+        assert!(lit(&chip8).is_empty());
+        assert_eq!(chip8.registers()[0xF], 16);
+    }
+
+    #[test]
+    fn hires_counts_clipped_rows_as_collisions() {
+        // HIGH; LD V1, 56; LD I, sprite; DRW V0, V1, 0
+        let mut program = vec![0x00, 0xFF, 0x61, 56, 0xA2, 0x08, 0xD0, 0x10];
+        program.extend([0xFF; 32]);
+        let chip8 = run(Quirks::SCHIP, &program, 4);
+        assert_eq!(lit(&chip8).len(), 16 * 8);
+        assert_eq!(chip8.registers()[0xF], 8);
+    }
+
+    #[test]
+    fn scrolling() {
+        for (mode, hires) in [(0xE0, false), (0xFF, true)] {
+            // CLS or HIGH; LD I, dot; DRW V0, V1, 1; SCD 2; SCR; SCL
+            #[rustfmt::skip]
+            let program = [
+                0x00, mode, 0xA2, 0x0C, 0xD0, 0x11,
+                0x00, 0xC2, 0x00, 0xFB, 0x00, 0xFC,
+                0x80,
+            ];
+            let mut chip8 = run(Quirks::SCHIP, &program, 4);
+            assert_eq!(chip8.framebuffer().is_hires(), hires);
+            assert_eq!(lit(&chip8), [(0, 2)]);
+            chip8.step();
+            assert_eq!(lit(&chip8), [(4, 2)]);
+            chip8.step();
+            assert_eq!(lit(&chip8), [(0, 2)]);
+        }
+    }
+
+    #[test]
+    fn low_resolution_and_exit() {
+        // HIGH; LOW; EXIT
+        let mut chip8 = run(Quirks::SCHIP, &[0x00, 0xFF, 0x00, 0xFE, 0x00, 0xFD], 2);
+        assert!(!chip8.framebuffer().is_hires());
+        assert!(matches!(chip8.step().outcome, StepOutcome::Halted));
+        assert!(chip8.is_halted());
+    }
+
+    /// An XO-CHIP machine running `program`.
+    fn xochip(program: &[u8], steps: usize) -> Chip8 {
+        let mut chip8 = Chip8Config::new()
+            .platform(Platform::XoChip)
+            .build()
+            .unwrap();
+        chip8.load_program(program).unwrap();
+        for _ in 0..steps {
+            let step = chip8.step();
+            assert!(!matches!(step.outcome, StepOutcome::Fault(_)), "{step:?}");
+        }
+        chip8
+    }
+
+    #[test]
+    fn long_index() {
+        // LD I, LONG #E123
+        let chip8 = xochip(&[0xF0, 0x00, 0xE1, 0x23], 1);
+        assert_eq!(chip8.ireg(), 0xE123);
+        assert_eq!(chip8.pc(), 0x204);
+    }
+
+    #[test]
+    fn skips_over_long_index() {
+        // SE V0, 0; LD I, LONG #E123; SNE V0, 0; LD I, LONG #E123
+        let program = [0x30, 0x00, 0xF0, 0x00, 0xE1, 0x23, 0x40, 0x00, 0xF0, 0x00];
+        let mut chip8 = xochip(&program, 1);
+        assert_eq!(chip8.pc(), 0x206);
+        chip8.step();
+        assert_eq!(chip8.pc(), 0x208);
+    }
+
+    #[test]
+    fn draws_to_selected_planes() {
+        #[rustfmt::skip]
+        let program = [
+            0xF2, 0x01, 0xA2, 0x10, 0xD0, 0x11, // PLANE 2; LD I, dots; DRW V0, V1, 1
+            0xF3, 0x01, 0xD0, 0x11,             // PLANE 3; DRW V0, V1, 1
+            0xF1, 0x01, 0x00, 0xE0,             // PLANE 1; CLS
+            0x00, 0x00,
+            0x80, 0x40,                         // dots: one row per plane
+        ];
+        let mut chip8 = xochip(&program, 3);
+        assert_eq!(chip8.framebuffer().planes(), 0b10);
+        assert_eq!(lit(&chip8), [(0, 0)]);
+        assert_eq!(chip8.framebuffer().pixel(0, 0), 0b10);
+
+        // Each selected plane takes its own row of the sprite
+        chip8.step();
+        chip8.step();
+        assert_eq!(chip8.registers()[0xF], 0);
+        assert_eq!(chip8.framebuffer().pixel(0, 0), 0b11);
+        assert_eq!(chip8.framebuffer().pixel(1, 0), 0b10);
+
+        // Clearing leaves the unselected plane alone
+        chip8.step();
+        chip8.step();
+        assert_eq!(chip8.framebuffer().pixel(0, 0), 0b10);
+        assert_eq!(chip8.framebuffer().pixel(1, 0), 0b10);
+    }
+
+    #[test]
+    fn register_ranges() {
+        #[rustfmt::skip]
+        let program = [
+            0x61, 0x01, 0x62, 0x02, 0x63, 0x03, // LD V1-V3
+            0xA3, 0x00, 0x51, 0x32,             // LD I, #300; SAVE V1 - V3
+            0xA3, 0x04, 0x53, 0x12,             // LD I, #304; SAVE V3 - V1
+            0xA3, 0x00, 0x53, 0x13,             // LD I, #300; LOAD V3 - V1
+        ];
+        let chip8 = xochip(&program, 9);
+        assert_eq!(chip8.memory()[0x300..0x307], [1, 2, 3, 0, 3, 2, 1]);
+        assert_eq!(chip8.registers()[1..4], [3, 2, 1]);
+        // I is left where it was
+        assert_eq!(chip8.ireg(), 0x300);
+    }
+
+    #[test]
+    fn scroll_up() {
+        // LD V1, 5; LD I, dot; DRW V0, V1, 1; SCU 3
+        let program = [0x61, 0x05, 0xA2, 0x08, 0xD0, 0x11, 0x00, 0xD3, 0x80];
+        let chip8 = xochip(&program, 4);
+        assert_eq!(lit(&chip8), [(0, 2)]);
+    }
+
+    #[test]
+    fn display_wait_at_the_end_of_memory() {
+        let mut chip8 = Chip8Config::new()
+            .platform(Platform::XoChip)
+            .quirks(Quirks::CHIP8)
+            .build()
+            .unwrap();
+        chip8.write_memory(0xFFFE, &[0xD0, 0x11]).unwrap();
+        chip8.set_pc(0xFFFE);
+        chip8.step();
+        assert_eq!(chip8.pc(), 0xFFFE);
     }
 }
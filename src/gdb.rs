@@ -0,0 +1,656 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+use crate::debug::{Breakpoints, Cause, Hit, Watch};
+use crate::emulator::{AccessKind, Chip8, Chip8Error};
+use crate::scheduler::Scheduler;
+
+/// Describes the registers to the debugger. CHIP-8 is unknown to GDB, so no
+/// architecture is given; 16-bit registers are sent in big-endian order, as
+/// they are stored in memory.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chip8.core">
+    <reg name="v0" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="v1" bitsize="8" type="uint8"/>
+    <reg name="v2" bitsize="8" type="uint8"/>
+    <reg name="v3" bitsize="8" type="uint8"/>
+    <reg name="v4" bitsize="8" type="uint8"/>
+    <reg name="v5" bitsize="8" type="uint8"/>
+    <reg name="v6" bitsize="8" type="uint8"/>
+    <reg name="v7" bitsize="8" type="uint8"/>
+    <reg name="v8" bitsize="8" type="uint8"/>
+    <reg name="v9" bitsize="8" type="uint8"/>
+    <reg name="va" bitsize="8" type="uint8"/>
+    <reg name="vb" bitsize="8" type="uint8"/>
+    <reg name="vc" bitsize="8" type="uint8"/>
+    <reg name="vd" bitsize="8" type="uint8"/>
+    <reg name="ve" bitsize="8" type="uint8"/>
+    <reg name="vf" bitsize="8" type="uint8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="dt" bitsize="8" type="uint8"/>
+    <reg name="st" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+/// Register numbers, in the order of the target description.
+const REG_I: usize = 16;
+const REG_PC: usize = 17;
+const REG_DT: usize = 18;
+const REG_ST: usize = 19;
+const N_REGISTERS: usize = 20;
+
+/// Signals reported in stop replies.
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+/// Ctrl-C, sent by the debugger to interrupt a running target.
+const INTERRUPT: u8 = 0x03;
+
+/// Serves the GDB remote serial protocol over a TCP connection, so debuggers
+/// such as GDB or LLDB can inspect and control the emulator.
+///
+/// The emulator runs at its usual speed while continuing. Breakpoints are
+/// kept by the server rather than patched into memory, and watchpoints set
+/// with `watch`, `rwatch` and `awatch` are supported too.
+pub struct GdbServer {
+    stream: TcpStream,
+    /// Bytes received but not processed yet.
+    received: Vec<u8>,
+    /// Whether packets are acknowledged, until the debugger turns it off.
+    ack: bool,
+    breakpoints: Breakpoints,
+    /// Ids of the breakpoints set with `Z` packets, by type, address and
+    /// length.
+    inserted: HashMap<(u8, u16, u16), usize>,
+    /// The reply to `?`.
+    last_stop: String,
+}
+
+/// What to do after a packet.
+enum Action {
+    Reply(String),
+    Continue,
+    Step,
+    /// End the session, sending the reply if there is one.
+    Close(Option<String>),
+}
+
+impl GdbServer {
+    pub fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        Ok(GdbServer {
+            stream,
+            received: Vec::new(),
+            ack: true,
+            breakpoints: Breakpoints::new(),
+            inserted: HashMap::new(),
+            last_stop: format!("S{SIGTRAP:02x}"),
+        })
+    }
+
+    /// Handles requests until the debugger detaches or disconnects.
+    pub fn run(&mut self, chip8: &mut Chip8, scheduler: &mut Scheduler) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            let stop = match self.handle(&packet, chip8) {
+                Action::Reply(reply) => {
+                    self.send(&reply)?;
+                    // The packet turning acknowledgements off was acknowledged
+                    if packet == "QStartNoAckMode" {
+                        self.ack = false;
+                    }
+                    continue;
+                }
+                Action::Continue => self.resume(chip8, scheduler)?,
+                Action::Step => self.step(chip8, scheduler),
+                Action::Close(reply) => {
+                    if let Some(reply) = reply {
+                        self.send(&reply)?;
+                    }
+                    return Ok(());
+                }
+            };
+            self.last_stop = stop.clone();
+            self.send(&stop)?;
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, packet: &str, chip8: &mut Chip8) -> Action {
+        let reply = |reply: &str| Action::Reply(reply.to_string());
+        let error = || Action::Reply(String::from("E01"));
+
+        let command = packet.get(..1).unwrap_or_default();
+        let args = packet.get(1..).unwrap_or_default();
+        match command {
+            "?" => Action::Reply(self.last_stop.clone()),
+            "g" => Action::Reply(to_hex(&read_registers(chip8))),
+            "G" => match from_hex(args) {
+                Some(bytes) if bytes.len() == registers_size() => {
+                    write_registers(chip8, &bytes);
+                    reply("OK")
+                }
+                _ => error(),
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(n) if n < N_REGISTERS => {
+                    let (start, len) = register_span(n);
+                    let bytes = read_registers(chip8);
+                    Action::Reply(to_hex(&bytes[start..start + len]))
+                }
+                _ => error(),
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(n, value)| {
+                    let n = usize::from_str_radix(n, 16).ok()?;
+                    (n < N_REGISTERS).then_some((n, from_hex(value)?))
+                });
+                match parsed {
+                    Some((n, value)) if value.len() == register_span(n).1 => {
+                        let mut bytes = read_registers(chip8);
+                        let (start, len) = register_span(n);
+                        bytes[start..start + len].copy_from_slice(&value);
+                        write_registers(chip8, &bytes);
+                        reply("OK")
+                    }
+                    _ => error(),
+                }
+            }
+            "m" => match parse_addr_len(args) {
+                Some((addr, len)) => {
+                    let mem = chip8.memory();
+                    let Some(end) = addr.checked_add(len) else {
+                        return error();
+                    };
+                    let end = end.min(mem.len());
+                    match mem.get(addr..end) {
+                        Some(bytes) if !bytes.is_empty() || len == 0 => {
+                            Action::Reply(to_hex(bytes))
+                        }
+                        _ => error(),
+                    }
+                }
+                None => error(),
+            },
+            "M" => {
+                let parsed = args.split_once(':').and_then(|(addr_len, data)| {
+                    let (addr, len) = parse_addr_len(addr_len)?;
+                    let data = from_hex(data)?;
+                    (data.len() == len).then_some((addr, data))
+                });
+                match parsed.map(|(addr, data)| chip8.write_memory(addr, &data)) {
+                    Some(Ok(())) => reply("OK"),
+                    _ => error(),
+                }
+            }
+            "c" | "s" => {
+                if !args.is_empty() {
+                    match u16::from_str_radix(args, 16) {
+                        Ok(addr) => chip8.set_pc(addr),
+                        Err(_) => return error(),
+                    }
+                }
+                if command == "c" {
+                    Action::Continue
+                } else {
+                    Action::Step
+                }
+            }
+            "Z" | "z" => self.set_breakpoint(command == "Z", args),
+            "D" => Action::Close(Some(String::from("OK"))),
+            "k" => Action::Close(None),
+            "H" | "T" => reply("OK"),
+            _ => self.handle_query(packet),
+        }
+    }
+
+    /// Handles the general queries and `v` packets.
+    fn handle_query(&self, packet: &str) -> Action {
+        let reply = |reply: &str| Action::Reply(reply.to_string());
+
+        if packet.starts_with("qSupported") {
+            return reply("PacketSize=4000;qXfer:features:read+;swbreak+;QStartNoAckMode+");
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, len)) = parse_addr_len(range) else {
+                return reply("E01");
+            };
+            let xml = TARGET_XML.as_bytes();
+            let start = offset.min(xml.len());
+            let Some(end) = start.checked_add(len) else {
+                return reply("E01");
+            };
+            let end = end.min(xml.len());
+            let more = if end < xml.len() { 'm' } else { 'l' };
+            let chunk = String::from_utf8_lossy(&xml[start..end]);
+            return Action::Reply(format!("{more}{chunk}"));
+        }
+        match packet {
+            "QStartNoAckMode" => reply("OK"),
+            "qAttached" => reply("1"),
+            "qC" => reply("QC1"),
+            "qfThreadInfo" => reply("m1"),
+            "qsThreadInfo" => reply("l"),
+            "qSymbol::" => reply("OK"),
+            // Anything else is unsupported, which is signalled by an empty reply
+            _ => reply(""),
+        }
+    }
+
+    /// Handles `Z`/`z` packets: `type,addr,kind`.
+    fn set_breakpoint(&mut self, insert: bool, args: &str) -> Action {
+        let mut fields = args.split(',');
+        let parsed = (|| {
+            let kind: u8 = fields.next()?.parse().ok()?;
+            let addr = u16::from_str_radix(fields.next()?, 16).ok()?;
+            let len = u16::from_str_radix(fields.next()?, 16).ok()?;
+            Some((kind, addr, len))
+        })();
+        let Some((kind, addr, len)) = parsed else {
+            return Action::Reply(String::from("E01"));
+        };
+
+        let watch = match kind {
+            // Hardware breakpoints behave like software ones here
+            0 | 1 => Watch::Address(addr),
+            2..=4 => Watch::Memory {
+                range: addr..=addr.saturating_add(len.max(1) - 1),
+                read: kind != 2,
+                write: kind != 3,
+            },
+            _ => return Action::Reply(String::new()),
+        };
+        let key = (kind, addr, len);
+        if insert {
+            if !self.inserted.contains_key(&key) {
+                if let Some(id) = self.breakpoints.add(watch, None) {
+                    self.inserted.insert(key, id);
+                }
+            }
+        } else if let Some(id) = self.inserted.remove(&key) {
+            self.breakpoints.remove(id);
+        }
+        Action::Reply(String::from("OK"))
+    }
+
+    /// Runs until a breakpoint fires, the program stops or the debugger
+    /// interrupts, returning the stop reply.
+    fn resume(&mut self, chip8: &mut Chip8, scheduler: &mut Scheduler) -> io::Result<String> {
+        self.breakpoints.sync(chip8);
+        scheduler.reset_clock();
+        self.stream.set_nonblocking(true)?;
+
+        let breakpoints = &mut self.breakpoints;
+        let stop = loop {
+            let mut hits = Vec::new();
+            let result = scheduler.run_frame_until(chip8, |chip8, step| {
+                hits = breakpoints.check(chip8, step);
+                !hits.is_empty()
+            });
+            match result {
+                Err(err) => break fault_reply(&err),
+                Ok(Some(_)) => break hit_reply(breakpoints, &hits),
+                Ok(None) if chip8.is_halted() => break String::from("W00"),
+                Ok(None) => (),
+            }
+
+            if poll_interrupt(&mut self.stream, &mut self.received)? {
+                break format!("S{SIGINT:02x}");
+            }
+            scheduler.wait_for_next_frame();
+        };
+
+        self.stream.set_nonblocking(false)?;
+        Ok(stop)
+    }
+
+    fn step(&mut self, chip8: &mut Chip8, scheduler: &mut Scheduler) -> String {
+        self.breakpoints.sync(chip8);
+        match scheduler.step(chip8) {
+            Ok(Some(step)) if !chip8.is_halted() => {
+                let hits = self.breakpoints.check(chip8, &step);
+                hit_reply(&self.breakpoints, &hits)
+            }
+            Ok(_) => String::from("W00"),
+            Err(err) => fault_reply(&err),
+        }
+    }
+
+    /// Reads the next packet, acknowledging it, or `None` once the debugger
+    /// disconnected.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // Skip acknowledgements and interrupts sent while stopped
+            let start = self.received.iter().position(|&byte| byte == b'$');
+            let Some(start) = start else {
+                self.received.clear();
+                if !self.fill()? {
+                    return Ok(None);
+                }
+                continue;
+            };
+            let end = self.received[start..]
+                .iter()
+                .position(|&byte| byte == b'#')
+                .map(|end| start + end);
+            let Some(end) = end.filter(|&end| end + 2 < self.received.len()) else {
+                if !self.fill()? {
+                    return Ok(None);
+                }
+                continue;
+            };
+
+            let data = unescape(&self.received[start + 1..end]);
+            let checksum = std::str::from_utf8(&self.received[end + 1..end + 3])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            let valid = checksum == Some(checksum_of(&self.received[start + 1..end]));
+            self.received.drain(..end + 3);
+
+            if self.ack {
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    /// Reads more bytes, returning false at the end of the connection.
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut buf = [0; 4096];
+        let n = self.stream.read(&mut buf)?;
+        self.received.extend_from_slice(&buf[..n]);
+        Ok(n > 0)
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let data = escape(data.as_bytes());
+        let checksum = checksum_of(&data);
+        let mut packet = Vec::with_capacity(data.len() + 4);
+        packet.push(b'$');
+        packet.extend_from_slice(&data);
+        packet.extend_from_slice(format!("#{checksum:02x}").as_bytes());
+        self.stream.write_all(&packet)
+    }
+}
+
+/// Checks for a Ctrl-C without blocking, keeping anything else received.
+fn poll_interrupt(stream: &mut TcpStream, received: &mut Vec<u8>) -> io::Result<bool> {
+    let mut buf = [0; 4096];
+    loop {
+        match stream.read(&mut buf) {
+            // A closed connection stops execution too
+            Ok(0) => return Ok(true),
+            Ok(n) => received.extend_from_slice(&buf[..n]),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Err(err) => return Err(err),
+        }
+    }
+    match received.iter().position(|&byte| byte == INTERRUPT) {
+        Some(pos) => {
+            received.remove(pos);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+fn hit_reply(breakpoints: &Breakpoints, hits: &[Hit]) -> String {
+    let reason = hits.iter().find_map(|hit| match hit.cause {
+        Cause::Address(_) => Some(String::from("swbreak:")),
+        Cause::Memory { kind, start, .. } => {
+            let name = match kind {
+                AccessKind::Read => "rwatch",
+                AccessKind::Write => "watch",
+            };
+            // Report an address within the watched range, so the debugger
+            // can tell which watchpoint fired
+            let breakpoint = breakpoints
+                .iter()
+                .find(|breakpoint| breakpoint.id == hit.id);
+            let addr = match breakpoint.map(|breakpoint| &breakpoint.watch) {
+                Some(Watch::Memory { range, .. }) => start.max(*range.start() as usize),
+                _ => start,
+            };
+            Some(format!("{name}:{addr:x}"))
+        }
+        _ => None,
+    });
+    match reason {
+        Some(reason) => format!("T{SIGTRAP:02x}{reason};"),
+        None => format!("S{SIGTRAP:02x}"),
+    }
+}
+
+fn fault_reply(err: &Chip8Error) -> String {
+    let signal = match err {
+        Chip8Error::UnknownOpcode { .. } => SIGILL,
+        _ => SIGSEGV,
+    };
+    format!("S{signal:02x}")
+}
+
+/// Offset and size of register `n` within the `g` packet.
+fn register_span(n: usize) -> (usize, usize) {
+    match n {
+        0..=15 => (n, 1),
+        REG_I => (16, 2),
+        REG_PC => (18, 2),
+        REG_DT => (20, 1),
+        _ => (21, 1),
+    }
+}
+
+fn registers_size() -> usize {
+    let (start, len) = register_span(REG_ST);
+    start + len
+}
+
+fn read_registers(chip8: &Chip8) -> Vec<u8> {
+    let mut bytes = chip8.registers().to_vec();
+    bytes.extend_from_slice(&chip8.ireg().to_be_bytes());
+    bytes.extend_from_slice(&chip8.pc().to_be_bytes());
+    bytes.push(chip8.delay_timer());
+    bytes.push(chip8.sound_timer());
+    bytes
+}
+
+fn write_registers(chip8: &mut Chip8, bytes: &[u8]) {
+    for (x, &value) in bytes[..16].iter().enumerate() {
+        chip8.set_register(x as u8, value);
+    }
+    chip8.set_ireg(u16::from_be_bytes([bytes[16], bytes[17]]));
+    chip8.set_pc(u16::from_be_bytes([bytes[18], bytes[19]]));
+    chip8.set_delay_timer(bytes[20]);
+    chip8.set_sound_timer(bytes[21]);
+}
+
+/// Parses `addr,length` in hexadecimal.
+fn parse_addr_len(args: &str) -> Option<(usize, usize)> {
+    let (addr, len) = args.split_once(',')?;
+    let addr = usize::from_str_radix(addr, 16).ok()?;
+    let len = usize::from_str_radix(len, 16).ok()?;
+    Some((addr, len))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// Escapes the bytes that delimit packets.
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for &byte in data {
+        if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+            escaped.extend_from_slice(&[b'}', byte ^ 0x20]);
+        } else {
+            escaped.push(byte);
+        }
+    }
+    escaped
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            b'}' => unescaped.extend(bytes.next().map(|byte| byte ^ 0x20)),
+            _ => unescaped.push(byte),
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::thread::{self, JoinHandle};
+
+    use super::*;
+    use crate::Quirks;
+
+    /// LD V0, 5; CALL sub; ADD V0, 1; loop: JP loop; sub: LD I, 0x300; RET
+    const PROGRAM: &[u8] = &[
+        0x60, 0x05, 0x22, 0x08, 0x70, 0x01, 0x12, 0x06, 0xA3, 0x00, 0x00, 0xEE,
+    ];
+
+    /// A debugger connected to a server on another thread.
+    struct Client {
+        stream: TcpStream,
+        ack: bool,
+        server: JoinHandle<io::Result<()>>,
+    }
+
+    impl Client {
+        fn new() -> Self {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = thread::spawn(move || {
+                let mut chip8 = Chip8::new(Quirks::CHIP8);
+                chip8.load_program(PROGRAM).unwrap();
+                let mut scheduler = Scheduler::new(chip8.speed());
+                let (stream, _) = listener.accept()?;
+                GdbServer::new(stream)?.run(&mut chip8, &mut scheduler)
+            });
+            Client {
+                stream: TcpStream::connect(addr).unwrap(),
+                ack: true,
+                server,
+            }
+        }
+
+        fn byte(&mut self) -> u8 {
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+
+        fn send(&mut self, packet: &str) {
+            let checksum = checksum_of(packet.as_bytes());
+            write!(self.stream, "${packet}#{checksum:02x}").unwrap();
+            if self.ack {
+                assert_eq!(self.byte(), b'+');
+            }
+        }
+
+        fn receive(&mut self) -> String {
+            assert_eq!(self.byte(), b'$');
+            let mut data = Vec::new();
+            loop {
+                match self.byte() {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+            let checksum = [self.byte(), self.byte()];
+            let checksum = u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16);
+            assert_eq!(checksum, Ok(checksum_of(&data)));
+            if self.ack {
+                self.stream.write_all(b"+").unwrap();
+            }
+            String::from_utf8(unescape(&data)).unwrap()
+        }
+
+        fn request(&mut self, packet: &str) -> String {
+            self.send(packet);
+            self.receive()
+        }
+
+        fn detach(mut self) {
+            assert_eq!(self.request("D"), "OK");
+            self.server.join().unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    fn session() {
+        let mut client = Client::new();
+        assert!(client
+            .request("qSupported:swbreak+")
+            .contains("qXfer:features:read+"));
+        // V0-VF, I, PC, DT and ST
+        let registers = "00".repeat(16) + "0000" + "0200" + "00" + "00";
+        assert_eq!(client.request("g"), registers);
+
+        assert_eq!(client.request("s"), "S05");
+        assert_eq!(client.request("p0"), "05");
+        assert_eq!(client.request("p11"), "0202");
+
+        assert_eq!(client.request("m200,4"), "60052208");
+        assert_eq!(client.request("M300,2:abcd"), "OK");
+        assert_eq!(client.request("m300,2"), "abcd");
+        assert_eq!(client.request("mffffffffffffffff,2"), "E01");
+        assert_eq!(client.request("Mffffffffffffffff,1:00"), "E01");
+
+        assert_eq!(client.request("Z0,208,2"), "OK");
+        assert_eq!(client.request("c"), "T05swbreak:;");
+        assert_eq!(client.request("p11"), "0208");
+        assert_eq!(client.request("z0,208,2"), "OK");
+        assert_eq!(client.request("s"), "S05");
+        assert_eq!(client.request("p10"), "0300");
+
+        // The program loops forever until interrupted
+        client.send("c");
+        client.stream.write_all(&[INTERRUPT]).unwrap();
+        assert_eq!(client.receive(), "S02");
+        assert_eq!(client.request("p11"), "0206");
+
+        client.detach();
+    }
+
+    #[test]
+    fn acknowledgements() {
+        let mut client = Client::new();
+        client.stream.write_all(b"$qC#00").unwrap();
+        assert_eq!(client.byte(), b'-');
+        assert_eq!(client.request("qC"), "QC1");
+
+        assert_eq!(client.request("QStartNoAckMode"), "OK");
+        client.ack = false;
+        assert_eq!(client.request("qAttached"), "1");
+
+        client.detach();
+    }
+}
@@ -0,0 +1,25 @@
+pub mod asm;
+pub mod audio;
+pub mod config;
+pub mod dap;
+pub mod debug;
+pub mod disasm;
+pub mod display;
+pub mod emulator;
+pub mod gdb;
+pub mod octo;
+pub mod ops;
+pub mod quirks;
+pub mod random;
+pub mod rewind;
+pub mod savestate;
+pub mod scheduler;
+pub mod trace;
+
+pub use config::{Chip8Config, ConfigError, Platform};
+pub use display::{Display, Framebuffer};
+pub use emulator::{Chip8, Chip8Error, Step, StepOutcome};
+pub use ops::Op;
+pub use quirks::Quirks;
+pub use random::Random;
+pub use scheduler::{Scheduler, Speed};
@@ -1,483 +1,610 @@
-use std::io::Write;
-use std::time::Duration;
-use std::{fs, io, ops::RangeInclusive};
-
-use deku::{bitvec::BitView, prelude::*};
-
-use crossterm::event::{Event, KeyCode};
+mod debugger;
+
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::{error, fmt, fs, str};
+
+use chip8::asm;
+use chip8::audio::{self, WavWriter};
+use chip8::dap::DapServer;
+use chip8::disasm::Disassembly;
+use chip8::emulator::TIMER_FREQUENCY;
+use chip8::gdb::GdbServer;
+use chip8::octo;
+use chip8::quirks::StackLocation;
+use chip8::random::{SeededRandom, VipRandom};
+use chip8::rewind::{self, Rewind};
+use chip8::trace::{self, TraceReader, Tracer};
+use chip8::{Chip8, Chip8Config, Display, Framebuffer, Platform, Scheduler, Speed};
+
+use debugger::{Debugger, DEBUGGER_ROWS};
+
+use crossterm::event::{
+    Event, KeyCode, KeyEventKind, KeyboardEnhancementFlags, PopKeyboardEnhancementFlags,
+    PushKeyboardEnhancementFlags,
+};
 use crossterm::{cursor, event, style, terminal, ExecutableCommand, QueueableCommand};
 
+const USAGE: &str = "USAGE: ./chip8 [run] [OPTIONS] <PROGRAM.ch8|PROGRAM.8o>
+       ./chip8 dap           serve the Debug Adapter Protocol on stdin and stdout
+       ./chip8 trace-diff <A.log> <B.log>
+                             report where two traces written with --trace diverge
+       ./chip8 disasm [--load-address <ADDR>] <PROGRAM.ch8>
+                             list the program, telling code from data
+       ./chip8 asm <SOURCE.s> [-o <OUTPUT.ch8>]
+                             assemble a program, by default next to the source
+
+OPTIONS:
+    --quirks <PRESET>     chip8 (default), chip48, schip or xochip
+    --ipf <N>             instructions per 60 Hz frame (default 15)
+    --ips <N>             instructions per second
+    --load-address <ADDR> where the program is loaded and started (default 0x200)
+    --vip-stack           keep the call stack in emulated memory like the COSMAC VIP
+    --seed <N>            seed the random number generator for reproducible runs
+    --vip-random          generate random numbers like the COSMAC VIP interpreter
+    --wav <OUTPUT.wav>    record the sound to a file
+    --trace <OUTPUT.log>  write a line per executed instruction to a file
+    --sample-rate <HZ>    sample rate of the recording (default 44100)
+    --rewind <FRAMES>     frames kept to rewind with Backspace (default 3600, 0 to disable)
+    --debug               start paused in the debugger
+    --gdb <PORT>          wait for a GDB connection on localhost instead of showing the game";
+
 fn main() -> io::Result<()> {
-    let program_path = std::env::args()
-        .nth(1)
-        .expect("USAGE: ./chip8 <PROGRAM.ch8>");
-    let src = fs::read(&program_path)?;
+    let mut program_path = None;
+    let mut platform = Platform::Chip8;
+    let mut wav_path = None;
+    let mut sample_rate = audio::DEFAULT_SAMPLE_RATE;
+    let mut speed = Speed::default();
+    let mut seed: Option<u64> = None;
+    let mut vip_random = false;
+    let mut vip_stack = false;
+    let mut load_address = None;
+    let mut debug = false;
+    let mut gdb_port: Option<u16> = None;
+    let mut trace_path = None;
+    let mut rewind_frames = rewind::DEFAULT_CAPACITY;
+
+    let mut args = std::env::args().skip(1).peekable();
+    match args.peek().map(String::as_str) {
+        Some("dap") => {
+            let stdin = io::BufReader::new(io::stdin());
+            if let Err(err) = DapServer::new(io::stdout()).run(stdin) {
+                exit_with_error(err);
+            }
+            return Ok(());
+        }
+        Some("trace-diff") => {
+            let (a, b) = (required(args.nth(1)), required(args.next()));
+            match trace_diff(&a, &b) {
+                Ok(true) => return Ok(()),
+                Ok(false) => std::process::exit(1),
+                Err(err) => exit_with_error(err),
+            }
+        }
+        Some("disasm") => {
+            args.next();
+            let mut load_address = 0x200;
+            let mut path = None;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--load-address" => load_address = parse_address(args.next()),
+                    _ => path = Some(arg),
+                }
+            }
+            let path = required(path);
+            let program =
+                fs::read(&path).unwrap_or_else(|err| exit_with_error(format!("{path}: {err}")));
+            print!("{}", Disassembly::new(&program, load_address));
+            return Ok(());
+        }
+        Some("asm") => {
+            args.next();
+            let mut source_path = None;
+            let mut output_path = None;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "-o" => output_path = Some(PathBuf::from(required(args.next()))),
+                    _ => source_path = Some(arg),
+                }
+            }
+            let source_path = required(source_path);
+            let output_path =
+                output_path.unwrap_or_else(|| Path::new(&source_path).with_extension("ch8"));
+            let source = fs::read_to_string(&source_path)
+                .unwrap_or_else(|err| exit_with_error(format!("{source_path}: {err}")));
+            let program =
+                asm::assemble(&source_path, &source).unwrap_or_else(|err| exit_with_error(err));
+            fs::write(&output_path, program)
+                .unwrap_or_else(|err| exit_with_error(format!("{}: {err}", output_path.display())));
+            return Ok(());
+        }
+        Some("run") => {
+            args.next();
+        }
+        _ => (),
+    }
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--quirks" => {
+                let name = required(args.next());
+                platform = Platform::from_name(&name)
+                    .unwrap_or_else(|| usage_error(format!("unknown quirks preset '{name}'")));
+            }
+            "--ipf" => speed = Speed::InstructionsPerFrame(parse_number(args.next())),
+            "--ips" => speed = Speed::InstructionsPerSecond(parse_number(args.next())),
+            "--load-address" => load_address = Some(parse_address(args.next())),
+            "--vip-stack" => vip_stack = true,
+            "--seed" => seed = Some(parse_number(args.next())),
+            "--vip-random" => vip_random = true,
+            "--wav" => wav_path = Some(required(args.next())),
+            "--trace" => trace_path = Some(required(args.next())),
+            "--sample-rate" => {
+                sample_rate = parse_number(args.next());
+            }
+            "--rewind" => rewind_frames = parse_number(args.next()),
+            "--debug" => debug = true,
+            "--gdb" => gdb_port = Some(parse_number(args.next())),
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(());
+            }
+            _ => program_path = Some(arg),
+        }
+    }
 
-    let original_terminal_size = terminal::size()?;
-    prepare_ui(original_terminal_size)?;
+    let mut config = Chip8Config::new().platform(platform).speed(speed);
+    if vip_stack {
+        config = config.stack_location(StackLocation::VIP);
+    }
+    if let Some(addr) = load_address {
+        config = config.load_address(addr);
+    }
 
-    let screen = Screen::new(io::stdout());
-    let mut chip8 = Chip8::new(screen);
-    chip8.load_program(&src);
+    let program_path = required(program_path);
+    // Octo sources are compiled on the fly
+    let src = match program_path.ends_with(".8o") {
+        true => {
+            let source = fs::read_to_string(&program_path)
+                .unwrap_or_else(|err| exit_with_error(format!("{program_path}: {err}")));
+            octo::compile(&program_path, &source).unwrap_or_else(|err| exit_with_error(err))
+        }
+        false => fs::read(&program_path)
+            .unwrap_or_else(|err| exit_with_error(format!("{program_path}: {err}"))),
+    };
 
-    let result = run(chip8);
+    let mut chip8 = config.build().unwrap_or_else(|err| exit_with_error(err));
+    if let Err(err) = chip8.load_program(&src) {
+        exit_with_error(err);
+    }
+    if let Err(err) = chip8.set_sample_rate(sample_rate) {
+        usage_error(err);
+    }
+    match (vip_random, seed) {
+        (true, seed) => chip8.set_random(VipRandom::new(seed.unwrap_or_default() as u16)),
+        (false, Some(seed)) => chip8.set_random(SeededRandom::new(seed)),
+        (false, None) => (),
+    }
+    if let Some(path) = trace_path {
+        chip8.set_tracer(Tracer::create(path)?);
+    }
 
-    restore_ui(original_terminal_size)?;
-    result
+    if let Some(port) = gdb_port {
+        if let Err(err) = serve_gdb(&mut chip8, port) {
+            exit_after_trace(&mut chip8, err);
+        }
+        return finish_trace(&mut chip8);
+    }
+
+    let mut wav = match wav_path {
+        Some(path) => Some(WavWriter::create(path, chip8.sample_rate())?),
+        None => None,
+    };
+
+    let debugger = debug.then(Debugger::new);
+    let rows = if debug {
+        TERMINAL_ROWS + DEBUGGER_ROWS
+    } else {
+        TERMINAL_ROWS
+    };
+
+    let original_terminal_size = terminal::size()?;
+    let key_release_events = prepare_ui(original_terminal_size, rows)?;
+
+    let rewind = (rewind_frames > 0).then(|| Rewind::new(rewind_frames));
+    let slots = SaveSlots {
+        program: program_path.into(),
+        slot: 1,
+    };
+    let result = run(
+        &mut chip8,
+        key_release_events,
+        wav.as_mut(),
+        debugger,
+        rewind,
+        slots,
+    );
+
+    restore_ui(original_terminal_size, key_release_events)?;
+    if let Some(wav) = wav {
+        wav.finish()?;
+    }
+    if let Err(err) = result {
+        exit_after_trace(&mut chip8, err);
+    }
+    finish_trace(&mut chip8)
 }
 
-fn run(mut chip8: Chip8) -> io::Result<()> {
-    loop {
-        chip8.tick()?;
+/// Flushes the trace before exiting, since the instructions leading up to an
+/// error are what it is most useful for.
+fn exit_after_trace(chip8: &mut Chip8, err: impl fmt::Display) -> ! {
+    if let Err(trace_err) = finish_trace(chip8) {
+        eprintln!("Error: {trace_err}");
+    }
+    exit_with_error(err);
+}
 
-        if event::poll(Duration::ZERO)? {
-            if event::read()? == Event::Key(KeyCode::Char('q').into()) {
-                return Ok(());
-            }
-        }
+fn finish_trace(chip8: &mut Chip8) -> io::Result<()> {
+    match chip8.take_tracer() {
+        Some(tracer) => tracer.finish(),
+        None => Ok(()),
     }
 }
 
-fn prepare_ui((rows, cols): (u16, u16)) -> io::Result<()> {
-    if rows < 128 || cols < 32 {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!(
-                "Minimum supported terminal size is 128x64, but current size is: {rows}x{cols}"
-            ),
-        ));
+/// Prints where two traces diverge, returning whether they agree.
+fn trace_diff(a: &str, b: &str) -> Result<bool, Box<dyn error::Error>> {
+    let open = |path: &str| -> Result<_, Box<dyn error::Error>> {
+        let file = fs::File::open(path).map_err(|err| format!("{path}: {err}"))?;
+        Ok(TraceReader::new(path, io::BufReader::new(file)))
+    };
+    match trace::diff(&mut open(a)?, &mut open(b)?)? {
+        Some(divergence) => {
+            print!("{divergence}");
+            Ok(false)
+        }
+        None => {
+            println!("traces agree");
+            Ok(true)
+        }
     }
-    terminal::enable_raw_mode()?;
-    io::stdout()
-        .execute(terminal::SetSize(SCREEN_WIDTH as u16, SCREEN_HEIGHT as u16))?
-        .execute(terminal::Clear(terminal::ClearType::All))?
-        .execute(cursor::Hide)?;
-    Ok(())
 }
 
-fn restore_ui((rows, cols): (u16, u16)) -> io::Result<()> {
-    io::stdout()
-        .execute(terminal::SetSize(cols, rows))?
-        .execute(terminal::Clear(terminal::ClearType::All))?
-        .execute(cursor::Show)?
-        .execute(style::ResetColor)?;
-    terminal::disable_raw_mode()?;
-    Ok(())
+/// An argument that must be given, exiting with the usage otherwise.
+fn required(arg: Option<String>) -> String {
+    arg.unwrap_or_else(|| usage_error("missing argument"))
 }
 
-#[derive(Debug, PartialEq, DekuRead, DekuWrite)]
-#[deku(type = "u16", endian = "big")]
-enum Op {
-    #[deku(id = "0x00E0")]
-    Clear,
-
-    #[deku(id = "0x00EE")]
-    Return,
-
-    #[deku(id_pat = "0x1000..=0x1FFF")]
-    AbsJump(#[deku(pad_bits_before = "4", bits = "12")] u16),
-
-    #[deku(id_pat = "0x2000..=0x2FFF")]
-    Call(#[deku(pad_bits_before = "4", bits = "12")] u16),
-
-    #[deku(id_pat = "0xB000..=0xBFFF")]
-    OffsetJump(#[deku(pad_bits_before = "4", bits = "12")] u16),
-
-    #[deku(id_pat = "0x3000..=0x3FFF")]
-    SkipEqVal(#[deku(pad_bits_before = "4", bits = "4")] u8, u8),
-
-    #[deku(id_pat = "0x4000..=0x4FFF")]
-    SkipNeqVal(#[deku(pad_bits_before = "4", bits = "4")] u8, u8),
-
-    #[deku(id_pat = "0x5000..=0x5FFF")]
-    SkipEqReg(
-        #[deku(pad_bits_before = "4", bits = "4")] u8,
-        #[deku(pad_bits_after = "4", bits = "4")] u8,
-    ),
-
-    #[deku(id_pat = "0x6000..=0x6FFF")]
-    SetVal(#[deku(pad_bits_before = "4", bits = "4")] u8, u8),
-
-    #[deku(id_pat = "0x7000..=0x7FFF")]
-    AddVal(#[deku(pad_bits_before = "4", bits = "4")] u8, u8),
-
-    #[deku(id_pat = "0x9000..=0x9FFF")]
-    SkipNeqReg(
-        #[deku(pad_bits_before = "4", bits = "4")] u8,
-        #[deku(pad_bits_after = "4", bits = "4")] u8,
-    ),
-
-    #[deku(id_pat = "0xC000..=0xCFFF")]
-    Rand(#[deku(pad_bits_before = "4", bits = "4")] u8, u8),
-
-    #[deku(id_pat = "x @ 0x8000..=0x8FFF if (x & 0xF) == 0x0")]
-    Mov(
-        #[deku(pad_bits_before = "4", bits = "4")] u8,
-        #[deku(pad_bits_after = "4", bits = "4")] u8,
-    ),
-
-    #[deku(id_pat = "x @ 0x8000..=0x8FFF if (x & 0xF) == 0x1")]
-    Or(
-        #[deku(pad_bits_before = "4", bits = "4")] u8,
-        #[deku(pad_bits_after = "4", bits = "4")] u8,
-    ),
-
-    #[deku(id_pat = "x @ 0x8000..=0x8FFF if (x & 0xF) == 0x2")]
-    And(
-        #[deku(pad_bits_before = "4", bits = "4")] u8,
-        #[deku(pad_bits_after = "4", bits = "4")] u8,
-    ),
-
-    #[deku(id_pat = "x @ 0x8000..=0x8FFF if (x & 0xF) == 0x3")]
-    Xor(
-        #[deku(pad_bits_before = "4", bits = "4")] u8,
-        #[deku(pad_bits_after = "4", bits = "4")] u8,
-    ),
-
-    #[deku(id_pat = "x @ 0x8000..=0x8FFF if (x & 0xF) == 0x4")]
-    Add(
-        #[deku(pad_bits_before = "4", bits = "4")] u8,
-        #[deku(pad_bits_after = "4", bits = "4")] u8,
-    ),
-
-    #[deku(id_pat = "x @ 0x8000..=0x8FFF if (x & 0xF) == 0x5")]
-    Sub(
-        #[deku(pad_bits_before = "4", bits = "4")] u8,
-        #[deku(pad_bits_after = "4", bits = "4")] u8,
-    ),
-
-    #[deku(id_pat = "x @ 0x8000..=0x8FFF if (x & 0xF) == 0x6")]
-    Shr(
-        #[deku(pad_bits_before = "4", bits = "4")] u8,
-        #[deku(pad_bits_after = "4", bits = "4")] u8,
-    ),
-
-    #[deku(id_pat = "x @ 0x8000..=0x8FFF if (x & 0xF) == 0x7")]
-    SubN(
-        #[deku(pad_bits_before = "4", bits = "4")] u8,
-        #[deku(pad_bits_after = "4", bits = "4")] u8,
-    ),
-
-    #[deku(id_pat = "x @ 0x8000..=0x8FFF if (x & 0xF) == 0xE")]
-    Shl(
-        #[deku(pad_bits_before = "4", bits = "4")] u8,
-        #[deku(pad_bits_after = "4", bits = "4")] u8,
-    ),
-
-    #[deku(id_pat = "0xD000..=0xDFFF")]
-    Draw(
-        #[deku(pad_bits_before = "4", bits = "4")] u8,
-        #[deku(bits = "4")] u8,
-        #[deku(bits = "4")] u8,
-    ),
-
-    #[deku(
-        id_pat = "0xE09E | 0xE19E | 0xE29E | 0xE39E | 0xE49E | 0xE59E | 0xE69E | 0xE79E | 0xE89E | 0xE99E | 0xEA9E | 0xEB9E  | 0xEC9E  | 0xED9E  | 0xEE9E  | 0xEF9E"
-    )]
-    SkipKey(#[deku(pad_bits_before = "4", bits = "4", pad_bits_after = "8")] u8),
-
-    #[deku(
-        id_pat = "0xE0A1 | 0xE1A1 | 0xE2A1 | 0xE3A1 | 0xE4A1 | 0xE5A1 | 0xE6A1 | 0xE7A1 | 0xE8A1 | 0xE9A1 | 0xEAA1 | 0xEBA1  | 0xECA1  | 0xEDA1  | 0xEEA1  | 0xEFA1"
-    )]
-    SkipNoKey(#[deku(pad_bits_before = "4", bits = "4", pad_bits_after = "8")] u8),
-
-    #[deku(
-        id_pat = "0xF00A | 0xF10A | 0xF20A | 0xF30A | 0xF40A | 0xF50A | 0xF60A | 0xF70A | 0xF80A | 0xF90A | 0xFA0A | 0xFB0A  | 0xFC0A  | 0xFD0A  | 0xFE0A  | 0xFF0A"
-    )]
-    GetKey(#[deku(pad_bits_before = "4", bits = "4", pad_bits_after = "8")] u8),
-
-    #[deku(
-        id_pat = "0xF007 | 0xF107 | 0xF207 | 0xF307 | 0xF407 | 0xF507 | 0xF607 | 0xF707 | 0xF807 | 0xF907 | 0xFA07 | 0xFB07  | 0xFC07  | 0xFD07  | 0xFE07  | 0xFF07"
-    )]
-    GetDelay(#[deku(pad_bits_before = "4", bits = "4", pad_bits_after = "8")] u8),
-
-    #[deku(
-        id_pat = "0xF015 | 0xF115 | 0xF215 | 0xF315 | 0xF415 | 0xF515 | 0xF615 | 0xF715 | 0xF815 | 0xF915 | 0xFA15 | 0xFB15  | 0xFC15  | 0xFD15  | 0xFE15  | 0xFF15"
-    )]
-    SetDelay(#[deku(pad_bits_before = "4", bits = "4", pad_bits_after = "8")] u8),
-
-    #[deku(
-        id_pat = "0xF018 | 0xF118 | 0xF218 | 0xF318 | 0xF418 | 0xF518 | 0xF618 | 0xF718 | 0xF818 | 0xF918 | 0xFA18 | 0xFB18  | 0xFC18  | 0xFD18  | 0xFE18  | 0xFF18"
-    )]
-    SetSoundTimer(#[deku(pad_bits_before = "4", bits = "4", pad_bits_after = "8")] u8),
-
-    #[deku(
-        id_pat = "0xF01E | 0xF11E | 0xF21E | 0xF31E | 0xF41E | 0xF51E | 0xF61E | 0xF71E | 0xF81E | 0xF91E | 0xFA1E | 0xFB1E  | 0xFC1E  | 0xFD1E  | 0xFE1E  | 0xFF1E"
-    )]
-    IncrIndex(#[deku(pad_bits_before = "4", bits = "4", pad_bits_after = "8")] u8),
-
-    #[deku(id_pat = "0xA000..=0xAFFF")]
-    SetIndex(#[deku(pad_bits_before = "4", bits = "12")] u16),
-
-    #[deku(
-        id_pat = "0xF029 | 0xF129 | 0xF229 | 0xF329 | 0xF429 | 0xF529 | 0xF629 | 0xF729 | 0xF829 | 0xF929 | 0xFA29 | 0xFB29  | 0xFC29  | 0xFD29  | 0xFE29  | 0xFF29"
-    )]
-    SetSpriteI(#[deku(pad_bits_before = "4", bits = "4", pad_bits_after = "8")] u8),
-
-    #[deku(
-        id_pat = "0xF033 | 0xF133 | 0xF233 | 0xF333 | 0xF433 | 0xF533 | 0xF633 | 0xF733 | 0xF833 | 0xF933 | 0xFA33 | 0xFB33  | 0xFC33  | 0xFD33  | 0xFE33  | 0xFF33"
-    )]
-    DecimalRepr(#[deku(pad_bits_before = "4", bits = "4", pad_bits_after = "8")] u8),
-
-    #[deku(
-        id_pat = "0xF055 | 0xF155 | 0xF255 | 0xF553 | 0xF455 | 0xF555 | 0xF655 | 0xF755 | 0xF855 | 0xF955 | 0xFA55 | 0xFB55  | 0xFC55  | 0xFD55  | 0xFE55  | 0xFF55"
-    )]
-    DumpRegisters(#[deku(pad_bits_before = "4", bits = "4", pad_bits_after = "8")] u8),
-
-    #[deku(
-        id_pat = "0xF065 | 0xF165 | 0xF265 | 0xF653 | 0xF465 | 0xF655 | 0xF665 | 0xF765 | 0xF865 | 0xF965 | 0xFA65 | 0xFB65  | 0xFC65  | 0xFD65  | 0xFE65  | 0xFF65"
-    )]
-    LoadRegisters(#[deku(pad_bits_before = "4", bits = "4", pad_bits_after = "8")] u8),
-
-    #[deku(id_pat = "_")]
-    Unknown(u16),
+fn parse_number<T: str::FromStr>(arg: Option<String>) -> T {
+    let arg = required(arg);
+    arg.parse()
+        .unwrap_or_else(|_| usage_error(format!("invalid number '{arg}'")))
 }
 
-struct Chip8 {
-    pc: u16,
-    mem: Box<[u8; 1024 * 4]>,
-    ireg: u16,
-    stack: Vec<u16>,
-    dt: u8,
-    st: u8,
-    v: Registers,
-    screen: Screen,
+/// Parses an address, in hexadecimal when prefixed with `0x`.
+fn parse_address(arg: Option<String>) -> u16 {
+    let arg = required(arg);
+    let parsed = match arg.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => arg.parse(),
+    };
+    parsed.unwrap_or_else(|_| usage_error(format!("invalid address '{arg}'")))
 }
 
-struct Registers([u8; 16]);
+fn exit_with_error(err: impl fmt::Display) -> ! {
+    eprintln!("Error: {err}");
+    std::process::exit(1);
+}
 
-impl std::ops::Index<u8> for Registers {
-    type Output = u8;
+fn usage_error(message: impl fmt::Display) -> ! {
+    exit_with_error(format!("{message}\n\n{USAGE}"))
+}
 
-    #[inline]
-    fn index(&self, index: u8) -> &Self::Output {
-        &self.0[index as usize]
-    }
+/// Runs the program under the control of a GDB client, without a screen.
+fn serve_gdb(chip8: &mut Chip8, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+    eprintln!("Waiting for GDB on {}", listener.local_addr()?);
+    let (stream, addr) = listener.accept()?;
+    eprintln!("GDB connected from {addr}");
+
+    let mut scheduler = Scheduler::new(chip8.speed());
+    GdbServer::new(stream)?.run(chip8, &mut scheduler)
 }
 
-impl std::ops::IndexMut<u8> for Registers {
-    #[inline]
-    fn index_mut(&mut self, index: u8) -> &mut Self::Output {
-        &mut self.0[index as usize]
-    }
+/// Save states kept next to the program, one file per numbered slot.
+struct SaveSlots {
+    program: PathBuf,
+    slot: u8,
 }
 
-impl Chip8 {
-    fn new(screen: Screen) -> Self {
-        let mut mem = Box::new([0; 1024 * 4]);
-        mem[FONT_RANGE].copy_from_slice(FONT);
-
-        Chip8 {
-            pc: 512,
-            mem,
-            ireg: 0,
-            stack: Vec::new(),
-            dt: 0,
-            st: 0,
-            v: Registers([0; 16]),
-            screen,
-        }
+impl SaveSlots {
+    const SLOTS: u8 = 9;
+
+    fn path(&self) -> PathBuf {
+        let mut path = self.program.clone().into_os_string();
+        path.push(format!(".state{}", self.slot));
+        path.into()
     }
 
-    fn load_program(&mut self, program: &[u8]) {
-        let pc = self.pc as usize;
-        self.mem[pc..pc + program.len()].copy_from_slice(program)
+    /// Selects the next slot, returning a message to show.
+    fn next(&mut self) -> String {
+        self.slot = self.slot % Self::SLOTS + 1;
+        format!("slot {}", self.slot)
     }
 
-    fn tick(&mut self) -> io::Result<()> {
-        let (_, op) = Op::from_bytes((&self.mem[self.pc as usize..], 0)).unwrap();
-        self.pc += 2;
-        self.execute(op)
+    fn save(&self, chip8: &Chip8) -> String {
+        let result = fs::File::create(self.path()).and_then(|mut file| chip8.save_state(&mut file));
+        match result {
+            Ok(()) => format!("saved slot {}", self.slot),
+            Err(err) => format!("cannot save slot {}: {err}", self.slot),
+        }
     }
 
-    fn execute(&mut self, op: Op) -> io::Result<()> {
-        match op {
-            Op::AbsJump(addr) => {
-                self.pc = addr;
-            }
-            Op::Call(addr) => {
-                self.stack.push(self.pc);
-                self.pc = addr;
-            }
-            Op::Return => {
-                self.pc = self.stack.pop().expect("stack underflow");
+    fn load(&self, chip8: &mut Chip8) -> String {
+        let result = fs::read(self.path())
+            .map_err(|err| err.to_string())
+            .and_then(|data| chip8.load_state(&data).map_err(|err| err.to_string()));
+        match result {
+            Ok(()) => format!("loaded slot {}", self.slot),
+            Err(err) => format!("cannot load slot {}: {err}", self.slot),
+        }
+    }
+}
+
+/// Frames rewound per press of Backspace, or per auto-repeat while held.
+const REWIND_STEP: usize = 30;
+
+/// How long a key counts as held when the terminal cannot report key releases.
+/// Holding a key down keeps it pressed through the terminal's auto-repeat.
+const KEY_HOLD_TIME: Duration = Duration::from_millis(150);
+
+fn run(
+    chip8: &mut Chip8,
+    key_release_events: bool,
+    mut wav: Option<&mut WavWriter>,
+    mut debugger: Option<Debugger>,
+    mut rewind: Option<Rewind>,
+    mut slots: SaveSlots,
+) -> Result<(), Box<dyn error::Error>> {
+    let mut display = Terminal(io::stdout());
+    let mut scheduler = Scheduler::new(chip8.speed());
+    let mut was_beeping = false;
+    let mut pressed_at = [None::<Instant>; 16];
+    // Samples owed from previous frames, in units of 1/60 sample
+    let mut audio_clock = 0;
+    let mut samples = Vec::new();
+
+    // The debugger keeps the program on screen after it exits
+    while !chip8.is_halted() || debugger.is_some() {
+        if let Some(rewind) = rewind.as_mut() {
+            rewind.record(chip8, &scheduler);
+        }
+        match debugger.as_mut() {
+            Some(debugger) => {
+                debugger.run_frame(&mut scheduler, chip8);
+                chip8.present(&mut display)?;
+                debugger.draw(chip8, &mut display.0, TERMINAL_ROWS + 1)?;
             }
-            Op::Clear => {
-                self.screen.clear()?;
+            None => {
+                scheduler.run_frame(chip8)?;
+                chip8.present(&mut display)?;
             }
-            Op::SkipEqVal(x, val) => {
-                if self.v[x] == val {
-                    self.pc += 2;
+        }
+
+        if let Some(wav) = wav.as_mut() {
+            audio_clock += chip8.sample_rate() as u64;
+            samples.resize((audio_clock / TIMER_FREQUENCY) as usize, 0.0);
+            audio_clock %= TIMER_FREQUENCY;
+            chip8.fill_audio(&mut samples);
+            wav.write_samples(&samples)?;
+        }
+
+        // The terminal has no tone generator, so ring the bell once per beep
+        let beeping = chip8.sound_active();
+        if beeping && !was_beeping {
+            io::stdout().execute(style::Print('\x07'))?;
+        }
+        was_beeping = beeping;
+
+        let now = Instant::now();
+        while event::poll(Duration::ZERO)? {
+            let Event::Key(key_event) = event::read()? else {
+                continue;
+            };
+            if let Some(debugger) = debugger.as_mut() {
+                if key_event.kind != KeyEventKind::Release
+                    && debugger.handle_key(key_event.code, &mut scheduler, chip8, rewind.as_mut())
+                {
+                    continue;
                 }
             }
-            Op::SkipNeqVal(x, val) => {
-                if self.v[x] != val {
-                    self.pc += 2;
-                }
+            if key_event.code == KeyCode::Esc {
+                return Ok(());
             }
-            Op::SkipEqReg(x, y) => {
-                if self.v[x] == self.v[y] {
-                    self.pc += 2;
+            if key_event.kind != KeyEventKind::Release {
+                let message = match key_event.code {
+                    KeyCode::Tab => Some(slots.next()),
+                    KeyCode::F(2) => Some(slots.save(chip8)),
+                    KeyCode::F(3) => {
+                        let message = slots.load(chip8);
+                        if let Some(rewind) = rewind.as_mut() {
+                            rewind.clear();
+                        }
+                        scheduler.reset_clock();
+                        Some(message)
+                    }
+                    _ => None,
+                };
+                if let Some(message) = message {
+                    io::stdout().execute(terminal::SetTitle(format!("chip8: {message}")))?;
+                    continue;
                 }
             }
-            Op::SkipNeqReg(x, y) => {
-                if self.v[x] != self.v[y] {
-                    self.pc += 2;
+            if key_event.code == KeyCode::Backspace && key_event.kind != KeyEventKind::Release {
+                if let Some(rewind) = rewind.as_mut() {
+                    rewind.rewind(chip8, &mut scheduler, REWIND_STEP);
                 }
+                continue;
             }
-            Op::SetVal(x, val) => {
-                self.v[x] = val;
-            }
-            Op::AddVal(x, val) => {
-                let vx = &mut self.v[x];
-                *vx = vx.wrapping_add(val);
-            }
-            Op::Mov(x, y) => {
-                self.v[x] = self.v[y];
-            }
-            Op::Or(x, y) => {
-                self.v[x] |= self.v[y];
-            }
-            Op::And(x, y) => {
-                self.v[x] &= self.v[y];
-            }
-            Op::Xor(x, y) => {
-                self.v[x] ^= self.v[y];
-            }
-            Op::Add(x, y) => {
-                let (result, overflow) = self.v[x].overflowing_add(self.v[y]);
-                self.v[0xF] = overflow as u8;
-                self.v[x] = result;
-            }
-            Op::Sub(x, y) => {
-                let (result, overflow) = self.v[x].overflowing_sub(self.v[y]);
-                self.v[0xF] = overflow as u8;
-                self.v[x] = result;
-            }
-            Op::SubN(x, y) => {
-                let (result, overflow) = self.v[y].overflowing_sub(self.v[x]);
-                self.v[0xF] = overflow as u8;
-                self.v[x] = result;
-            }
-            Op::Shr(x, y) => {
-                self.v[x] = self.v[y]; // TODO: add config to skip this
-                self.v[0xF] = self.v[x] & 0b0000_0001;
-                self.v[x] >>= 1;
-            }
-            Op::Shl(x, y) => {
-                self.v[x] = self.v[y]; // TODO: add config to skip this
-                self.v[0xF] = self.v[x] & 0b1000_0000;
-                self.v[x] <<= 1;
-            }
-            Op::SetIndex(addr) => {
-                self.ireg = addr;
+            let Some(key) = keypad_key(key_event.code) else {
+                continue;
+            };
+            if key_event.kind == KeyEventKind::Release {
+                chip8.release_key(key);
+            } else {
+                chip8.press_key(key);
+                pressed_at[key as usize] = Some(now);
             }
-            Op::OffsetJump(addr) => {
-                self.pc = addr + self.v[0] as u16;
-            }
-            Op::Rand(x, val) => {
-                self.v[x] = fastrand::u8(..) & val;
-            }
-            Op::Draw(x, y, height) => {
-                let sprite = &self.mem[self.ireg as usize..(self.ireg as usize + height as usize)];
-                let start_x = self.v[x] as usize & (SCREEN_WIDTH - 1);
-                let start_y = self.v[y] as usize & (SCREEN_HEIGHT - 1);
-                self.v[0xF] = 0;
-
-                for (screen_y, row) in (start_y..SCREEN_HEIGHT).zip(sprite) {
-                    for (screen_x, bit) in
-                        (start_x..SCREEN_WIDTH).zip(row.view_bits::<deku::bitvec::Msb0>())
-                    {
-                        let pixel = self.screen.pixel_mut(screen_x, screen_y);
-                        match (*pixel, *bit) {
-                            (true, true) => {
-                                *pixel = false;
-                                self.v[0xF] = 1;
-                            }
-                            (false, true) => *pixel = true,
-                            _ => (),
-                        }
-                    }
+        }
+
+        if !key_release_events {
+            for (key, pressed) in pressed_at.iter_mut().enumerate() {
+                if pressed.is_some_and(|at| now - at >= KEY_HOLD_TIME) {
+                    *pressed = None;
+                    chip8.release_key(key as u8);
                 }
-                self.screen.draw()?;
             }
-            _ => todo!("{:#04x?}", op),
         }
 
-        Ok(())
+        scheduler.wait_for_next_frame();
     }
+    Ok(())
 }
 
-const FONT_RANGE: RangeInclusive<usize> = 0x50..=0x9F;
-const FONT: &[u8] = &[
-    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
-    0x20, 0x60, 0x20, 0x20, 0x70, // 1
-    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
-    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
-    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
-    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
-    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
-    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
-    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
-    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
-    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
-    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
-    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
-    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
-    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
-    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
-];
-
-const SCREEN_WIDTH: usize = 64;
-const SCREEN_HEIGHT: usize = 32;
-const N_PIXELS: usize = SCREEN_WIDTH * SCREEN_HEIGHT;
-
-struct Screen {
-    pixels: [bool; N_PIXELS],
-    output: io::Stdout,
+/// Maps the left-hand block of a QWERTY keyboard onto the COSMAC VIP keypad:
+///
+/// ```text
+/// 1 2 3 4      1 2 3 C
+/// Q W E R  ->  4 5 6 D
+/// A S D F      7 8 9 E
+/// Z X C V      A 0 B F
+/// ```
+fn keypad_key(code: KeyCode) -> Option<u8> {
+    let KeyCode::Char(c) = code else {
+        return None;
+    };
+    let key = match c.to_ascii_lowercase() {
+        '1' => 0x1,
+        '2' => 0x2,
+        '3' => 0x3,
+        '4' => 0xC,
+        'q' => 0x4,
+        'w' => 0x5,
+        'e' => 0x6,
+        'r' => 0xD,
+        'a' => 0x7,
+        's' => 0x8,
+        'd' => 0x9,
+        'f' => 0xE,
+        'z' => 0xA,
+        'x' => 0x0,
+        'c' => 0xB,
+        'v' => 0xF,
+        _ => return None,
+    };
+    Some(key)
 }
 
-impl Screen {
-    const ON: &str = "\u{2588}\u{2588}";
-    const OFF: &str = "  ";
+/// Both resolutions are rendered into a 128x32 character area: low resolution
+/// pixels are two cells wide, high resolution ones half a cell tall.
+const TERMINAL_COLUMNS: u16 = 128;
+const TERMINAL_ROWS: u16 = 32;
+
+/// Sets up the terminal with `rows` lines and returns whether it will report
+/// key releases.
+fn prepare_ui((rows, cols): (u16, u16), min_rows: u16) -> io::Result<bool> {
+    if rows < TERMINAL_COLUMNS || cols < min_rows {
+        return Err(io::Error::other(format!(
+            "Minimum supported terminal size is {TERMINAL_COLUMNS}x{min_rows}, but current size is: {rows}x{cols}"
+        )));
+    }
+    terminal::enable_raw_mode()?;
+    io::stdout()
+        .execute(terminal::SetSize(TERMINAL_COLUMNS, min_rows))?
+        .execute(terminal::Clear(terminal::ClearType::All))?
+        .execute(cursor::Hide)?;
 
-    fn new(output: io::Stdout) -> Self {
-        Screen {
-            pixels: [false; N_PIXELS],
-            output,
-        }
+    let key_release_events = terminal::supports_keyboard_enhancement()?;
+    if key_release_events {
+        io::stdout().execute(PushKeyboardEnhancementFlags(
+            KeyboardEnhancementFlags::REPORT_EVENT_TYPES,
+        ))?;
     }
+    Ok(key_release_events)
+}
 
-    #[inline]
-    fn pixel(&self, x: usize, y: usize) -> &bool {
-        &self.pixels[SCREEN_WIDTH * y + x]
+fn restore_ui((rows, cols): (u16, u16), key_release_events: bool) -> io::Result<()> {
+    if key_release_events {
+        io::stdout().execute(PopKeyboardEnhancementFlags)?;
     }
-    #[inline]
-    fn pixel_mut(&mut self, x: usize, y: usize) -> &mut bool {
-        &mut self.pixels[SCREEN_WIDTH * y + x]
+    io::stdout()
+        .execute(terminal::SetSize(cols, rows))?
+        .execute(terminal::Clear(terminal::ClearType::All))?
+        .execute(cursor::Show)?
+        .execute(style::ResetColor)?;
+    terminal::disable_raw_mode()?;
+    Ok(())
+}
+
+/// Renders the screen with block characters, using colours for the XO-CHIP
+/// bitplanes.
+struct Terminal(io::Stdout);
+
+impl Terminal {
+    /// Colours for each combination of the two bitplanes.
+    const PALETTE: [style::Color; 4] = [
+        style::Color::Reset,
+        style::Color::White,
+        style::Color::Red,
+        style::Color::Yellow,
+    ];
+
+    fn present_lores(&mut self, frame: &Framebuffer) -> io::Result<()> {
+        for (y, row) in frame.rows().enumerate() {
+            for (x, &pixel) in row.iter().enumerate() {
+                let color = Self::PALETTE[pixel as usize];
+                let symbol = if pixel == 0 { "  " } else { "\u{2588}\u{2588}" };
+                self.0
+                    .queue(cursor::MoveTo(x as u16 * 2, y as u16))?
+                    .queue(style::SetForegroundColor(color))?
+                    .queue(style::SetBackgroundColor(style::Color::Reset))?
+                    .queue(style::Print(symbol))?;
+            }
+        }
+        self.0.flush()
     }
 
-    fn draw(&mut self) -> io::Result<()> {
-        for y in 0..SCREEN_HEIGHT {
-            for x in 0..SCREEN_WIDTH {
-                let symbol = if *self.pixel(x, y) {
-                    Self::ON
-                } else {
-                    Self::OFF
+    /// Packs two rows of pixels into each line using half-block characters.
+    fn present_hires(&mut self, frame: &Framebuffer) -> io::Result<()> {
+        use style::Color::Reset;
+
+        for y in (0..frame.height()).step_by(2) {
+            for x in 0..frame.width() {
+                let top = Self::PALETTE[frame.pixel(x, y) as usize];
+                let bottom = Self::PALETTE[frame.pixel(x, y + 1) as usize];
+                let (symbol, fg, bg) = match (top, bottom) {
+                    (Reset, Reset) => (' ', Reset, Reset),
+                    (Reset, _) => ('\u{2584}', bottom, Reset),
+                    _ if top == bottom => ('\u{2588}', top, Reset),
+                    _ => ('\u{2580}', top, bottom),
                 };
-                self.output
-                    .queue(cursor::MoveTo(x as u16 * 2, y as u16))?
+                self.0
+                    .queue(cursor::MoveTo(x as u16, y as u16 / 2))?
+                    .queue(style::SetForegroundColor(fg))?
+                    .queue(style::SetBackgroundColor(bg))?
                     .queue(style::Print(symbol))?;
             }
         }
-        self.output.flush()
+        self.0.flush()
     }
+}
 
-    #[inline]
-    fn clear(&mut self) -> io::Result<()> {
-        self.pixels.fill(false);
-        self.output
-            .execute(terminal::Clear(terminal::ClearType::All))?;
-        Ok(())
+impl Display for Terminal {
+    fn present(&mut self, frame: &Framebuffer) -> io::Result<()> {
+        if frame.is_hires() {
+            self.present_hires(frame)
+        } else {
+            self.present_lores(frame)
+        }
     }
 }
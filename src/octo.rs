@@ -0,0 +1,1013 @@
+//! A compiler for Octo, the language of the Octo IDE that most modern
+//! CHIP-8 programs are written in.
+//!
+//! ```text
+//! :const SPEED 2                  # constants
+//! :alias x v3                     # names for registers
+//! :macro step reg { reg += SPEED }
+//! :calc HALF { SPEED / 2 }        # right to left, use parentheses
+//!
+//! : main                          # programs start at main
+//!   i := ball
+//!   loop
+//!     sprite x v4 1
+//!     step x
+//!     if x == 60 then x := 0
+//!     if v4 != 0 begin v4 += -1 else v4 := 31 end
+//!     while x < 50
+//!   again
+//!
+//! : ball 0xC0 0b11000000          # bytes
+//! ```
+//!
+//! Statements are separated by whitespace and `#` starts a comment.
+//! `:unpack`, `:org`, `:next`, `:byte` and the XO-CHIP instructions are
+//! supported; `:breakpoint` is accepted and ignored.
+
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::{error, fmt};
+
+use crate::Op;
+
+/// Where programs are loaded and compiled to.
+const START: u16 = 0x200;
+/// Macros expanding into macros deeper than this are assumed to recurse
+/// forever.
+const MAX_MACRO_DEPTH: usize = 64;
+/// The register comparisons other than `==` and `!=` compute in.
+const VF: u8 = 0xF;
+
+/// Compiles `source`, returning the program to load at `0x200`. `name` is
+/// used in errors.
+///
+/// ```
+/// let program = chip8::octo::compile("loop.8o", ": main v0 += 1 main").unwrap();
+/// assert_eq!(program, [0x70, 0x01, 0x22, 0x00]);
+/// ```
+pub fn compile(name: &str, source: &str) -> Result<Vec<u8>, OctoError> {
+    let mut compiler = Compiler {
+        file: name.to_string(),
+        tokens: tokenize(source),
+        position: (1, 1),
+        rom: Vec::new(),
+        here: START as u32,
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        macros: HashMap::new(),
+        fixups: Vec::new(),
+        blocks: Vec::new(),
+        entry_jump: true,
+    };
+    // Left out again if `main` comes first
+    let main = Token {
+        text: String::from("main"),
+        line: 1,
+        column: 1,
+        depth: 0,
+    };
+    compiler.emit_target(Target::Forward(main), 12, Op::AbsJump)?;
+    while let Some(token) = compiler.next()? {
+        compiler.statement(token)?;
+    }
+    compiler.finish()
+}
+
+struct Compiler {
+    file: String,
+    tokens: VecDeque<Token>,
+    /// Line and column of the last token read.
+    position: (usize, usize),
+    /// Memory from `START` on.
+    rom: Vec<u8>,
+    /// Address of the next byte, up to the end of the address space.
+    here: u32,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Rc<Macro>>,
+    fixups: Vec<Fixup>,
+    /// The `begin`s and `loop`s not closed yet, innermost last.
+    blocks: Vec<(Token, Block)>,
+    /// Whether the program starts with a jump to `main`.
+    entry_jump: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+    /// Number of macro expansions the token came out of.
+    depth: usize,
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+/// An instruction referring to a label defined further down, completed once
+/// the whole program is compiled.
+struct Fixup {
+    addr: u16,
+    name: Token,
+    bits: u32,
+    make: Box<dyn Fn(u16) -> Op>,
+}
+
+#[derive(Debug, Clone)]
+enum Target {
+    Known(u16),
+    Forward(Token),
+}
+
+enum Block {
+    /// `if ... begin`, with the jump to the `else` or `end`.
+    If { jump: u16 },
+    /// `else`, with the jump over it to the `end`.
+    Else { jump: u16 },
+    /// `loop`, with the jumps out of it of its `while`s.
+    Loop { start: u16, exits: Vec<u16> },
+}
+
+/// The right-hand side of a comparison.
+#[derive(Clone, Copy)]
+enum Operand {
+    Reg(u8),
+    Byte(u8),
+}
+
+struct Comparison {
+    x: u8,
+    operator: String,
+    rhs: Option<Operand>,
+}
+
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (i, line) in source.lines().enumerate() {
+        let mut rest = line;
+        loop {
+            rest = rest.trim_start();
+            if rest.is_empty() || rest.starts_with('#') {
+                break;
+            }
+            let len = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let offset = line.len() - rest.len();
+            tokens.push_back(Token {
+                text: rest[..len].to_string(),
+                line: i + 1,
+                column: line[..offset].chars().count() + 1,
+                depth: 0,
+            });
+            rest = &rest[len..];
+        }
+    }
+    tokens
+}
+
+fn parse_number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()? as f64
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+impl Compiler {
+    fn error(&self, message: impl Into<String>) -> OctoError {
+        OctoError {
+            file: self.file.clone(),
+            line: self.position.0,
+            column: self.position.1,
+            message: message.into(),
+        }
+    }
+
+    fn error_at(&self, token: &Token, message: impl Into<String>) -> OctoError {
+        OctoError {
+            file: self.file.clone(),
+            line: token.line,
+            column: token.column,
+            message: message.into(),
+        }
+    }
+
+    /// The next token, without expanding macros.
+    fn raw(&mut self) -> Result<Token, OctoError> {
+        let token = self
+            .tokens
+            .pop_front()
+            .ok_or_else(|| self.error("unexpected end of the program"))?;
+        self.position = (token.line, token.column);
+        Ok(token)
+    }
+
+    /// The next token after expanding macros, or `None` at the end.
+    fn next(&mut self) -> Result<Option<Token>, OctoError> {
+        while !self.tokens.is_empty() {
+            let token = self.raw()?;
+            let Some(definition) = self.macros.get(&token.text).cloned() else {
+                return Ok(Some(token));
+            };
+            if token.depth >= MAX_MACRO_DEPTH {
+                return Err(self.error(format!("macro {} expands forever", token.text)));
+            }
+            let mut args = HashMap::new();
+            for param in &definition.params {
+                let arg = self.raw().map_err(|_| {
+                    self.error_at(&token, format!("missing arguments to {}", token.text))
+                })?;
+                args.insert(param.as_str(), arg.text);
+            }
+            for body in definition.body.iter().rev() {
+                let mut expanded = body.clone();
+                if let Some(arg) = args.get(body.text.as_str()) {
+                    expanded.text = arg.clone();
+                }
+                expanded.depth = token.depth + 1;
+                self.tokens.push_front(expanded);
+            }
+        }
+        Ok(None)
+    }
+
+    fn token(&mut self) -> Result<Token, OctoError> {
+        self.next()?
+            .ok_or_else(|| self.error("unexpected end of the program"))
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), OctoError> {
+        let token = self.token()?;
+        if token.text != text {
+            return Err(self.error(format!("expected '{text}', found '{}'", token.text)));
+        }
+        Ok(())
+    }
+
+    fn peek_is(&self, text: &str) -> bool {
+        self.tokens.front().is_some_and(|token| token.text == text)
+    }
+
+    /// The name being defined by a label or directive.
+    fn name(&mut self) -> Result<String, OctoError> {
+        let token = self.raw()?;
+        if parse_number(&token.text).is_some() || self.register_of(&token.text).is_some() {
+            return Err(self.error(format!("'{}' cannot be used as a name", token.text)));
+        }
+        Ok(token.text)
+    }
+
+    fn register_of(&self, text: &str) -> Option<u8> {
+        if let Some(&register) = self.aliases.get(text) {
+            return Some(register);
+        }
+        let digit = text.strip_prefix(['v', 'V'])?;
+        (digit.len() == 1)
+            .then(|| u8::from_str_radix(digit, 16).ok())
+            .flatten()
+    }
+
+    fn register(&mut self) -> Result<u8, OctoError> {
+        let token = self.token()?;
+        self.register_of(&token.text)
+            .ok_or_else(|| self.error(format!("expected a register, found '{}'", token.text)))
+    }
+
+    /// The value of a number, a constant, a label defined above or a
+    /// `{ ... }` expression, or `None` for other names.
+    fn value_of(&mut self, token: &Token) -> Result<Option<f64>, OctoError> {
+        if token.text == "{" {
+            return self.calc_block().map(Some);
+        }
+        if let Some(value) = parse_number(&token.text) {
+            return Ok(Some(value));
+        }
+        if let Some(&value) = self.constants.get(&token.text) {
+            return Ok(Some(value));
+        }
+        Ok(self.labels.get(&token.text).map(|&addr| addr as f64))
+    }
+
+    fn number_of(&mut self, token: &Token, min: i64, max: i64) -> Result<i64, OctoError> {
+        let Some(value) = self.value_of(token)? else {
+            return Err(self.error(format!("undefined name '{}'", token.text)));
+        };
+        self.check(value, min, max)
+    }
+
+    fn check(&self, value: f64, min: i64, max: i64) -> Result<i64, OctoError> {
+        let value = value.floor() as i64;
+        if !(min..=max).contains(&value) {
+            return Err(self.error(format!("{value} is out of range {min} to {max}")));
+        }
+        Ok(value)
+    }
+
+    fn number(&mut self, min: i64, max: i64) -> Result<i64, OctoError> {
+        let token = self.token()?;
+        self.number_of(&token, min, max)
+    }
+
+    fn byte_of(&mut self, token: &Token) -> Result<u8, OctoError> {
+        Ok(self.number_of(token, -128, 255)? as u8)
+    }
+
+    fn byte(&mut self) -> Result<u8, OctoError> {
+        let token = self.token()?;
+        self.byte_of(&token)
+    }
+
+    fn nibble(&mut self) -> Result<u8, OctoError> {
+        Ok(self.number(0, 15)? as u8)
+    }
+
+    /// An address of `bits` bits, which may be a label defined further down.
+    fn target_of(&mut self, token: Token, bits: u32) -> Result<Target, OctoError> {
+        if self.value_of(&token)?.is_some() {
+            let addr = self.number_of(&token, 0, (1 << bits) - 1)?;
+            return Ok(Target::Known(addr as u16));
+        }
+        if token.text.starts_with(':') || self.register_of(&token.text).is_some() {
+            return Err(self.error(format!("expected an address, found '{}'", token.text)));
+        }
+        Ok(Target::Forward(token))
+    }
+
+    fn target(&mut self, bits: u32) -> Result<Target, OctoError> {
+        let token = self.token()?;
+        self.target_of(token, bits)
+    }
+
+    fn write(&mut self, addr: u16, bytes: &[u8]) {
+        let offset = (addr - START) as usize;
+        if self.rom.len() < offset + bytes.len() {
+            self.rom.resize(offset + bytes.len(), 0);
+        }
+        self.rom[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn emit_bytes(&mut self, bytes: &[u8]) -> Result<(), OctoError> {
+        if self.here as usize + bytes.len() > 0x10000 {
+            return Err(self.error("the program runs past the end of memory"));
+        }
+        self.write(self.here as u16, bytes);
+        self.here += bytes.len() as u32;
+        Ok(())
+    }
+
+    fn encode(&self, op: Op) -> Result<Vec<u8>, OctoError> {
+        op.encode()
+            .map_err(|err| self.error(format!("cannot encode {op}: {err}")))
+    }
+
+    fn emit(&mut self, op: Op) -> Result<(), OctoError> {
+        let bytes = self.encode(op)?;
+        self.emit_bytes(&bytes)
+    }
+
+    /// Emits an instruction that jumps to `addr`, once it is known.
+    fn patch_jump(&mut self, at: u16, addr: u32) -> Result<(), OctoError> {
+        if addr > 0xFFF {
+            return Err(self.error(format!("cannot jump to {addr:#x}, past 0xFFF")));
+        }
+        let bytes = self.encode(Op::AbsJump(addr as u16))?;
+        self.write(at, &bytes);
+        Ok(())
+    }
+
+    fn emit_target(
+        &mut self,
+        target: Target,
+        bits: u32,
+        make: impl Fn(u16) -> Op + 'static,
+    ) -> Result<(), OctoError> {
+        match target {
+            Target::Known(addr) => self.emit(make(addr)),
+            Target::Forward(name) => {
+                let addr = self.here as u16;
+                self.emit(make(0))?;
+                self.fixups.push(Fixup {
+                    addr,
+                    name,
+                    bits,
+                    make: Box::new(make),
+                });
+                Ok(())
+            }
+        }
+    }
+
+    fn define_label(&mut self, name: String, addr: u32) -> Result<(), OctoError> {
+        if self.labels.contains_key(&name) || self.constants.contains_key(&name) {
+            return Err(self.error(format!("'{name}' is already defined")));
+        }
+        if addr > 0xFFFF {
+            return Err(self.error(format!("'{name}' is past the end of memory")));
+        }
+        // Nothing to jump over when the program starts with main
+        if name == "main" && self.entry_jump && self.rom.len() == 2 && self.here == START as u32 + 2
+        {
+            self.entry_jump = false;
+            self.fixups.remove(0);
+            self.rom.clear();
+            self.here = START as u32;
+            self.labels.insert(name, START);
+            return Ok(());
+        }
+        self.labels.insert(name, addr as u16);
+        Ok(())
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), OctoError> {
+        if let Some(x) = self.register_of(&token.text) {
+            return self.assignment(x);
+        }
+        let op = match token.text.as_str() {
+            ":" => {
+                let name = self.name()?;
+                return self.define_label(name, self.here);
+            }
+            ":next" => {
+                let name = self.name()?;
+                return self.define_label(name, self.here + 1);
+            }
+            ":const" => {
+                let name = self.name()?;
+                let value = self.token()?;
+                let Some(value) = self.value_of(&value)? else {
+                    return Err(self.error(format!("undefined name '{}'", value.text)));
+                };
+                if self.labels.contains_key(&name) || self.constants.contains_key(&name) {
+                    return Err(self.error(format!("'{name}' is already defined")));
+                }
+                self.constants.insert(name, value);
+                return Ok(());
+            }
+            ":calc" => {
+                let name = self.name()?;
+                self.expect("{")?;
+                let value = self.calc_block()?;
+                if self.labels.contains_key(&name) {
+                    return Err(self.error(format!("'{name}' is already defined")));
+                }
+                self.constants.insert(name, value);
+                return Ok(());
+            }
+            ":alias" => {
+                let name = self.name()?;
+                let register = self.register()?;
+                self.aliases.insert(name, register);
+                return Ok(());
+            }
+            ":macro" => return self.define_macro(),
+            ":unpack" => return self.unpack(),
+            ":org" => {
+                self.here = self.number(START as i64, 0xFFFF)? as u32;
+                return Ok(());
+            }
+            ":byte" => {
+                let byte = self.byte()?;
+                return self.emit_bytes(&[byte]);
+            }
+            ":breakpoint" => {
+                self.name()?;
+                return Ok(());
+            }
+            ";" | "return" => Op::Return,
+            "clear" => Op::Clear,
+            "hires" => Op::HighRes,
+            "lores" => Op::LowRes,
+            "exit" => Op::Exit,
+            "scroll-left" => Op::ScrollLeft,
+            "scroll-right" => Op::ScrollRight,
+            "scroll-down" => Op::ScrollDown(self.nibble()?),
+            "scroll-up" => Op::ScrollUp(self.nibble()?),
+            "plane" => Op::SelectPlanes(self.number(0, 3)? as u8),
+            "audio" => Op::LoadAudio,
+            "jump" => {
+                let target = self.target(12)?;
+                return self.emit_target(target, 12, Op::AbsJump);
+            }
+            "jump0" => {
+                let target = self.target(12)?;
+                return self.emit_target(target, 12, Op::OffsetJump);
+            }
+            "native" => {
+                let target = self.target(12)?;
+                return self.emit_target(target, 12, Op::Unknown);
+            }
+            "sprite" => Op::Draw(self.register()?, self.register()?, self.nibble()?),
+            "save" | "load" => {
+                let x = self.register()?;
+                let range = self.peek_is("-");
+                let op = match (token.text.as_str(), range) {
+                    ("save", false) => Op::DumpRegisters(x),
+                    ("load", false) => Op::LoadRegisters(x),
+                    ("save", true) => Op::SaveRange(x, self.after_dash()?),
+                    _ => Op::LoadRange(x, self.after_dash()?),
+                };
+                return self.emit(op);
+            }
+            "bcd" => Op::DecimalRepr(self.register()?),
+            "saveflags" => Op::DumpFlags(self.register()?),
+            "loadflags" => Op::LoadFlags(self.register()?),
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                match token.text.as_str() {
+                    "delay" => Op::SetDelay(x),
+                    "buzzer" => Op::SetSoundTimer(x),
+                    _ => Op::SetPitch(x),
+                }
+            }
+            "i" => return self.index(),
+            "if" => return self.if_statement(token),
+            "else" => {
+                let Some((_, Block::If { jump })) = self.blocks.pop() else {
+                    return Err(self.error("else without if ... begin"));
+                };
+                let over = self.here as u16;
+                self.emit(Op::AbsJump(0))?;
+                self.patch_jump(jump, self.here)?;
+                self.blocks.push((token, Block::Else { jump: over }));
+                return Ok(());
+            }
+            "end" => {
+                let Some((_, Block::If { jump } | Block::Else { jump })) = self.blocks.pop() else {
+                    return Err(self.error("end without if ... begin"));
+                };
+                return self.patch_jump(jump, self.here);
+            }
+            "loop" => {
+                let start = self.here as u16;
+                let exits = Vec::new();
+                self.blocks.push((token, Block::Loop { start, exits }));
+                return Ok(());
+            }
+            "while" => {
+                let comparison = self.comparison()?;
+                if self.loop_exits().is_none() {
+                    return Err(self.error("while outside of a loop"));
+                }
+                self.skip_unless(&comparison, true)?;
+                let exit = self.here as u16;
+                self.emit(Op::AbsJump(0))?;
+                self.loop_exits()
+                    .into_iter()
+                    .for_each(|exits| exits.push(exit));
+                return Ok(());
+            }
+            "again" => {
+                let Some((_, Block::Loop { start, exits })) = self.blocks.pop() else {
+                    return Err(self.error("again without loop"));
+                };
+                self.emit(Op::AbsJump(start))?;
+                for exit in exits {
+                    self.patch_jump(exit, self.here)?;
+                }
+                return Ok(());
+            }
+            text if text.starts_with(':') => {
+                return Err(self.error(format!("unknown directive {text}")));
+            }
+            // Labels are called, while numbers and constants are bytes of
+            // data
+            text if self.labels.contains_key(text) => {
+                let target = self.target_of(token, 12)?;
+                return self.emit_target(target, 12, Op::Call);
+            }
+            _ => match self.value_of(&token)? {
+                Some(value) => {
+                    let byte = self.check(value, -128, 255)? as u8;
+                    return self.emit_bytes(&[byte]);
+                }
+                None => {
+                    let target = self.target_of(token, 12)?;
+                    return self.emit_target(target, 12, Op::Call);
+                }
+            },
+        };
+        self.emit(op)
+    }
+
+    /// The jumps out of the innermost loop.
+    fn loop_exits(&mut self) -> Option<&mut Vec<u16>> {
+        self.blocks
+            .iter_mut()
+            .rev()
+            .find_map(|(_, block)| match block {
+                Block::Loop { exits, .. } => Some(exits),
+                _ => None,
+            })
+    }
+
+    fn after_dash(&mut self) -> Result<u8, OctoError> {
+        self.expect("-")?;
+        self.register()
+    }
+
+    fn assignment(&mut self, x: u8) -> Result<(), OctoError> {
+        let operator_token = self.token()?;
+        let rhs = self.token()?;
+        let y = self.register_of(&rhs.text);
+        let op = match (operator_token.text.as_str(), rhs.text.as_str(), y) {
+            (":=", "key", _) => Op::GetKey(x),
+            (":=", "delay", _) => Op::GetDelay(x),
+            (":=", "random", _) => Op::Rand(x, self.byte()?),
+            (":=", _, Some(y)) => Op::Mov(x, y),
+            (":=", _, None) => Op::SetVal(x, self.byte_of(&rhs)?),
+            ("+=", _, Some(y)) => Op::Add(x, y),
+            ("+=", _, None) => Op::AddVal(x, self.byte_of(&rhs)?),
+            ("-=", _, Some(y)) => Op::Sub(x, y),
+            ("-=", _, None) => Op::AddVal(x, self.byte_of(&rhs)?.wrapping_neg()),
+            ("=-", _, Some(y)) => Op::SubN(x, y),
+            ("|=", _, Some(y)) => Op::Or(x, y),
+            ("&=", _, Some(y)) => Op::And(x, y),
+            ("^=", _, Some(y)) => Op::Xor(x, y),
+            (">>=", _, Some(y)) => Op::Shr(x, y),
+            ("<<=", _, Some(y)) => Op::Shl(x, y),
+            ("=-" | "|=" | "&=" | "^=" | ">>=" | "<<=", _, None) => {
+                return Err(self.error(format!("expected a register, found '{}'", rhs.text)));
+            }
+            (operator, _, _) => {
+                return Err(self.error_at(
+                    &operator_token,
+                    format!("expected an assignment to v{x:x}, found '{operator}'"),
+                ));
+            }
+        };
+        self.emit(op)
+    }
+
+    fn index(&mut self) -> Result<(), OctoError> {
+        let operator_token = self.token()?;
+        let rhs = self.token()?;
+        match (operator_token.text.as_str(), rhs.text.as_str()) {
+            (":=", "long") => {
+                let target = self.target(16)?;
+                self.emit_target(target, 16, Op::LongIndex)
+            }
+            (":=", "hex") => {
+                let x = self.register()?;
+                self.emit(Op::SetSpriteI(x))
+            }
+            (":=", "bighex") => {
+                let x = self.register()?;
+                self.emit(Op::SetBigSpriteI(x))
+            }
+            (":=", _) => {
+                let target = self.target_of(rhs, 12)?;
+                self.emit_target(target, 12, Op::SetIndex)
+            }
+            ("+=", _) => {
+                let x = self.register_of(&rhs.text).ok_or_else(|| {
+                    self.error(format!("expected a register, found '{}'", rhs.text))
+                })?;
+                self.emit(Op::IncrIndex(x))
+            }
+            (operator, _) => Err(self.error_at(
+                &operator_token,
+                format!("expected an assignment to i, found '{operator}'"),
+            )),
+        }
+    }
+
+    /// `:unpack` loads an address into `v0` and `v1`, the high nibble of
+    /// `v0` being the given one or, with `long`, a 16-bit address.
+    fn unpack(&mut self) -> Result<(), OctoError> {
+        let token = self.token()?;
+        let (nibble, bits) = match token.text.as_str() {
+            "long" => (0, 16),
+            _ => (self.number_of(&token, 0, 15)? as u8, 12),
+        };
+        let target = self.target(bits)?;
+        let high = move |addr: u16| Op::SetVal(0, (nibble << 4) | (addr >> 8) as u8);
+        self.emit_target(target.clone(), bits, high)?;
+        self.emit_target(target, bits, |addr| Op::SetVal(1, addr as u8))
+    }
+
+    fn define_macro(&mut self) -> Result<(), OctoError> {
+        let name = self.name()?;
+        let mut params = Vec::new();
+        loop {
+            let token = self.raw()?;
+            if token.text == "{" {
+                break;
+            }
+            params.push(token.text);
+        }
+        let mut body = Vec::new();
+        let mut depth = 0;
+        loop {
+            let token = self.raw()?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" if depth == 0 => break,
+                "}" => depth -= 1,
+                _ => (),
+            }
+            body.push(token);
+        }
+        self.macros.insert(name, Rc::new(Macro { params, body }));
+        Ok(())
+    }
+
+    fn comparison(&mut self) -> Result<Comparison, OctoError> {
+        let x = self.register()?;
+        let operator = self.token()?.text;
+        let rhs = match operator.as_str() {
+            "key" | "-key" => None,
+            "==" | "!=" | "<" | ">" | "<=" | ">=" => {
+                let rhs = self.token()?;
+                Some(match self.register_of(&rhs.text) {
+                    Some(y) => Operand::Reg(y),
+                    None => Operand::Byte(self.byte_of(&rhs)?),
+                })
+            }
+            _ => return Err(self.error(format!("expected a comparison, found '{operator}'"))),
+        };
+        Ok(Comparison { x, operator, rhs })
+    }
+
+    /// Emits instructions that skip the next one unless the comparison
+    /// holds, or, if `negate`, when it holds. Comparisons other than `==`
+    /// and `!=` subtract in `vf`.
+    fn skip_unless(&mut self, comparison: &Comparison, negate: bool) -> Result<(), OctoError> {
+        let x = comparison.x;
+        let operator = match (comparison.operator.as_str(), negate) {
+            (operator, false) => operator,
+            ("==", true) => "!=",
+            ("!=", true) => "==",
+            ("<", true) => ">=",
+            (">=", true) => "<",
+            (">", true) => "<=",
+            ("<=", true) => ">",
+            ("key", true) => "-key",
+            (_, true) => "key",
+        };
+        let Some(rhs) = comparison.rhs else {
+            return self.emit(match operator {
+                "key" => Op::SkipNoKey(x),
+                _ => Op::SkipKey(x),
+            });
+        };
+        match (operator, rhs) {
+            ("==", Operand::Reg(y)) => self.emit(Op::SkipNeqReg(x, y)),
+            ("==", Operand::Byte(byte)) => self.emit(Op::SkipNeqVal(x, byte)),
+            ("!=", Operand::Reg(y)) => self.emit(Op::SkipEqReg(x, y)),
+            ("!=", Operand::Byte(byte)) => self.emit(Op::SkipEqVal(x, byte)),
+            _ => {
+                self.emit(match rhs {
+                    Operand::Reg(y) => Op::Mov(VF, y),
+                    Operand::Byte(byte) => Op::SetVal(VF, byte),
+                })?;
+                // vf is 1 when vx >= rhs after `vf =- vx`, when rhs >= vx
+                // after `vf -= vx`
+                self.emit(match operator {
+                    "<" | ">=" => Op::SubN(VF, x),
+                    _ => Op::Sub(VF, x),
+                })?;
+                self.emit(match operator {
+                    "<" | ">" => Op::SkipNeqVal(VF, 0),
+                    _ => Op::SkipEqVal(VF, 0),
+                })
+            }
+        }
+    }
+
+    fn if_statement(&mut self, token: Token) -> Result<(), OctoError> {
+        let comparison = self.comparison()?;
+        let word = self.token()?;
+        match word.text.as_str() {
+            "then" => self.skip_unless(&comparison, false),
+            "begin" => {
+                self.skip_unless(&comparison, true)?;
+                let jump = self.here as u16;
+                self.emit(Op::AbsJump(0))?;
+                self.blocks.push((token, Block::If { jump }));
+                Ok(())
+            }
+            text => Err(self.error(format!("expected then or begin, found '{text}'"))),
+        }
+    }
+
+    /// Evaluates a `:calc` expression up to the closing brace, the opening
+    /// one having been read.
+    fn calc_block(&mut self) -> Result<f64, OctoError> {
+        let mut tokens = Vec::new();
+        loop {
+            let token = self.raw()?;
+            if token.text == "}" {
+                break;
+            }
+            tokens.push(token);
+        }
+        let mut pos = 0;
+        let value = self.calc(&tokens, &mut pos)?;
+        if let Some(token) = tokens.get(pos) {
+            return Err(self.error_at(token, format!("unexpected '{}'", token.text)));
+        }
+        Ok(value)
+    }
+
+    /// Operators have no precedence and are applied from right to left.
+    fn calc(&self, tokens: &[Token], pos: &mut usize) -> Result<f64, OctoError> {
+        let lhs = self.calc_term(tokens, pos)?;
+        let Some(token) = tokens.get(*pos) else {
+            return Ok(lhs);
+        };
+        let op: fn(f64, f64) -> f64 = match token.text.as_str() {
+            "+" => |a, b| a + b,
+            "-" => |a, b| a - b,
+            "*" => |a, b| a * b,
+            "/" => |a, b| a / b,
+            "%" => |a, b| a % b,
+            "&" => |a, b| (a as i64 & b as i64) as f64,
+            "|" => |a, b| (a as i64 | b as i64) as f64,
+            "^" => |a, b| (a as i64 ^ b as i64) as f64,
+            "<<" => |a, b| (a as i64).wrapping_shl(b as u32) as f64,
+            ">>" => |a, b| (a as i64).wrapping_shr(b as u32) as f64,
+            "pow" => f64::powf,
+            "min" => f64::min,
+            "max" => f64::max,
+            "<" => |a, b| (a < b) as i64 as f64,
+            "<=" => |a, b| (a <= b) as i64 as f64,
+            ">" => |a, b| (a > b) as i64 as f64,
+            ">=" => |a, b| (a >= b) as i64 as f64,
+            "==" => |a, b| (a == b) as i64 as f64,
+            "!=" => |a, b| (a != b) as i64 as f64,
+            _ => return Ok(lhs),
+        };
+        *pos += 1;
+        let rhs = self.calc(tokens, pos)?;
+        Ok(op(lhs, rhs))
+    }
+
+    fn calc_term(&self, tokens: &[Token], pos: &mut usize) -> Result<f64, OctoError> {
+        let Some(token) = tokens.get(*pos) else {
+            return Err(self.error("expected a value"));
+        };
+        *pos += 1;
+        let unary: fn(f64) -> f64 = match token.text.as_str() {
+            "(" => {
+                let value = self.calc(tokens, pos)?;
+                if tokens.get(*pos).is_none_or(|token| token.text != ")") {
+                    return Err(self.error_at(token, "unclosed parenthesis"));
+                }
+                *pos += 1;
+                return Ok(value);
+            }
+            "-" => |a| -a,
+            "~" => |a| !(a as i64) as f64,
+            "!" => |a| (a == 0.0) as i64 as f64,
+            "abs" => f64::abs,
+            "sqrt" => f64::sqrt,
+            "floor" => f64::floor,
+            "ceil" => f64::ceil,
+            "sin" => f64::sin,
+            "cos" => f64::cos,
+            "tan" => f64::tan,
+            "exp" => f64::exp,
+            "log" => f64::ln,
+            "sign" => f64::signum,
+            "HERE" => return Ok(self.here as f64),
+            "PI" => return Ok(std::f64::consts::PI),
+            "E" => return Ok(std::f64::consts::E),
+            text => {
+                let value = parse_number(text)
+                    .or_else(|| self.constants.get(text).copied())
+                    .or_else(|| self.labels.get(text).map(|&addr| addr as f64));
+                return value
+                    .ok_or_else(|| self.error_at(token, format!("undefined name '{text}'")));
+            }
+        };
+        Ok(unary(self.calc_term(tokens, pos)?))
+    }
+
+    /// Checks that all blocks are closed and completes the instructions
+    /// referring to labels further down.
+    fn finish(mut self) -> Result<Vec<u8>, OctoError> {
+        if let Some((token, block)) = self.blocks.last() {
+            let end = match block {
+                Block::Loop { .. } => "again",
+                _ => "end",
+            };
+            return Err(self.error_at(token, format!("{} without {end}", token.text)));
+        }
+        if self.entry_jump && !self.labels.contains_key("main") {
+            return Err(OctoError {
+                file: self.file,
+                line: 1,
+                column: 1,
+                message: String::from("no ': main' label to start at"),
+            });
+        }
+        for fixup in std::mem::take(&mut self.fixups) {
+            let name = &fixup.name.text;
+            let value = match self.labels.get(name) {
+                Some(&addr) => addr as f64,
+                None => *self.constants.get(name).ok_or_else(|| {
+                    self.error_at(&fixup.name, format!("undefined name '{name}'"))
+                })?,
+            };
+            let addr = value.floor() as i64;
+            if !(0..1 << fixup.bits).contains(&addr) {
+                let message = format!("{name} ({addr:#x}) does not fit in {} bits", fixup.bits);
+                return Err(self.error_at(&fixup.name, message));
+            }
+            let bytes = self.encode((fixup.make)(addr as u16))?;
+            self.write(fixup.addr, &bytes);
+        }
+        Ok(self.rom)
+    }
+}
+
+/// An error in the source, with the line and column it was found at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OctoError {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for OctoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.file, self.line, self.column, self.message
+        )
+    }
+}
+
+impl error::Error for OctoError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compiles_control_flow() {
+        let source = "
+: main
+  v0 := 5
+  i := dot
+  loop
+    sprite v0 v1 1
+    v0 += 1
+    if v0 == 10 then v0 := 0
+    if v1 > 3 begin v1 := 0 else v1 += 1 end
+  again
+: dot 0x80
+";
+        let program = compile("test.8o", source).unwrap();
+        #[rustfmt::skip]
+        let expected = [
+            0x60, 0x05, // v0 := 5
+            0xA2, 0x1C, // i := dot
+            0xD0, 0x11, // loop: sprite v0 v1 1
+            0x70, 0x01, // v0 += 1
+            0x40, 0x0A, // if v0 == 10 then
+            0x60, 0x00, //   v0 := 0
+            0x6F, 0x03, // if v1 > 3 begin, as vf := 3
+            0x8F, 0x15, //   vf -= v1
+            0x3F, 0x00, //   skipping to the block if that borrowed
+            0x12, 0x18, //   or jumping to else
+            0x61, 0x00, //   v1 := 0
+            0x12, 0x1A, // else
+            0x71, 0x01, //   v1 += 1
+            0x12, 0x04, // end again
+            0x80,       // dot
+        ];
+        assert_eq!(program, expected);
+    }
+
+    #[test]
+    fn errors_name_the_line_and_column() {
+        let err = compile("test.8o", ": main\n  v0 := nowhere\n").unwrap_err();
+        assert_eq!(err.to_string(), "test.8o:2:9: undefined name 'nowhere'");
+    }
+
+    #[test]
+    fn labels_must_fit_into_memory() {
+        let source = ": main\n  exit\n:org 0xFFFE\n: last 0x12 0x34\n: end\n";
+        let err = compile("test.8o", source).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "test.8o:5:3: 'end' is past the end of memory"
+        );
+    }
+}
@@ -1,6 +1,11 @@
+// Triggered by the code that deku's derive macros generate for `Op`
+#![allow(clippy::manual_div_ceil)]
+
+use std::fmt;
+
 use deku::prelude::*;
 
-#[derive(Debug, PartialEq, DekuRead, DekuWrite)]
+#[derive(Debug, Clone, Copy, PartialEq, DekuRead, DekuWrite)]
 #[deku(type = "u16", endian = "big")]
 pub enum Op {
     #[deku(id = "0x00E0")]
@@ -9,6 +14,27 @@ pub enum Op {
     #[deku(id = "0x00EE")]
     Return,
 
+    #[deku(id_pat = "0x00C0..=0x00CF")]
+    ScrollDown(#[deku(pad_bits_before = "12", bits = "4")] u8),
+
+    #[deku(id_pat = "0x00D0..=0x00DF")]
+    ScrollUp(#[deku(pad_bits_before = "12", bits = "4")] u8),
+
+    #[deku(id = "0x00FB")]
+    ScrollRight,
+
+    #[deku(id = "0x00FC")]
+    ScrollLeft,
+
+    #[deku(id = "0x00FD")]
+    Exit,
+
+    #[deku(id = "0x00FE")]
+    LowRes,
+
+    #[deku(id = "0x00FF")]
+    HighRes,
+
     #[deku(id_pat = "0x1000..=0x1FFF")]
     AbsJump(#[deku(pad_bits_before = "4", bits = "12")] u16),
 
@@ -24,19 +50,31 @@ pub enum Op {
     #[deku(id_pat = "0x4000..=0x4FFF")]
     SkipNeqVal(#[deku(pad_bits_before = "4", bits = "4")] u8, u8),
 
-    #[deku(id_pat = "0x5000..=0x5FFF")]
+    #[deku(id_pat = "x @ 0x5000..=0x5FFF if (x & 0xF) == 0x0")]
     SkipEqReg(
         #[deku(pad_bits_before = "4", bits = "4")] u8,
         #[deku(pad_bits_after = "4", bits = "4")] u8,
     ),
 
+    #[deku(id_pat = "x @ 0x5000..=0x5FFF if (x & 0xF) == 0x2")]
+    SaveRange(
+        #[deku(pad_bits_before = "4", bits = "4")] u8,
+        #[deku(pad_bits_after = "4", bits = "4")] u8,
+    ),
+
+    #[deku(id_pat = "x @ 0x5000..=0x5FFF if (x & 0xF) == 0x3")]
+    LoadRange(
+        #[deku(pad_bits_before = "4", bits = "4")] u8,
+        #[deku(pad_bits_after = "4", bits = "4")] u8,
+    ),
+
     #[deku(id_pat = "0x6000..=0x6FFF")]
     SetVal(#[deku(pad_bits_before = "4", bits = "4")] u8, u8),
 
     #[deku(id_pat = "0x7000..=0x7FFF")]
     AddVal(#[deku(pad_bits_before = "4", bits = "4")] u8, u8),
 
-    #[deku(id_pat = "0x9000..=0x9FFF")]
+    #[deku(id_pat = "x @ 0x9000..=0x9FFF if (x & 0xF) == 0x0")]
     SkipNeqReg(
         #[deku(pad_bits_before = "4", bits = "4")] u8,
         #[deku(pad_bits_after = "4", bits = "4")] u8,
@@ -144,6 +182,23 @@ pub enum Op {
     #[deku(id_pat = "0xA000..=0xAFFF")]
     SetIndex(#[deku(pad_bits_before = "4", bits = "12")] u16),
 
+    /// `F000 NNNN`, the only instruction taking up four bytes.
+    #[deku(id = "0xF000")]
+    LongIndex(u16),
+
+    #[deku(
+        id_pat = "0xF001 | 0xF101 | 0xF201 | 0xF301 | 0xF401 | 0xF501 | 0xF601 | 0xF701 | 0xF801 | 0xF901 | 0xFA01 | 0xFB01  | 0xFC01  | 0xFD01  | 0xFE01  | 0xFF01"
+    )]
+    SelectPlanes(#[deku(pad_bits_before = "4", bits = "4", pad_bits_after = "8")] u8),
+
+    #[deku(id = "0xF002")]
+    LoadAudio,
+
+    #[deku(
+        id_pat = "0xF03A | 0xF13A | 0xF23A | 0xF33A | 0xF43A | 0xF53A | 0xF63A | 0xF73A | 0xF83A | 0xF93A | 0xFA3A | 0xFB3A  | 0xFC3A  | 0xFD3A  | 0xFE3A  | 0xFF3A"
+    )]
+    SetPitch(#[deku(pad_bits_before = "4", bits = "4", pad_bits_after = "8")] u8),
+
     #[deku(
         id_pat = "0xF029 | 0xF129 | 0xF229 | 0xF329 | 0xF429 | 0xF529 | 0xF629 | 0xF729 | 0xF829 | 0xF929 | 0xFA29 | 0xFB29  | 0xFC29  | 0xFD29  | 0xFE29  | 0xFF29"
     )]
@@ -155,15 +210,171 @@ pub enum Op {
     DecimalRepr(#[deku(pad_bits_before = "4", bits = "4", pad_bits_after = "8")] u8),
 
     #[deku(
-        id_pat = "0xF055 | 0xF155 | 0xF255 | 0xF553 | 0xF455 | 0xF555 | 0xF655 | 0xF755 | 0xF855 | 0xF955 | 0xFA55 | 0xFB55  | 0xFC55  | 0xFD55  | 0xFE55  | 0xFF55"
+        id_pat = "0xF055 | 0xF155 | 0xF255 | 0xF355 | 0xF455 | 0xF555 | 0xF655 | 0xF755 | 0xF855 | 0xF955 | 0xFA55 | 0xFB55  | 0xFC55  | 0xFD55  | 0xFE55  | 0xFF55"
     )]
     DumpRegisters(#[deku(pad_bits_before = "4", bits = "4", pad_bits_after = "8")] u8),
 
     #[deku(
-        id_pat = "0xF065 | 0xF165 | 0xF265 | 0xF653 | 0xF465 | 0xF655 | 0xF665 | 0xF765 | 0xF865 | 0xF965 | 0xFA65 | 0xFB65  | 0xFC65  | 0xFD65  | 0xFE65  | 0xFF65"
+        id_pat = "0xF065 | 0xF165 | 0xF265 | 0xF365 | 0xF465 | 0xF565 | 0xF665 | 0xF765 | 0xF865 | 0xF965 | 0xFA65 | 0xFB65  | 0xFC65  | 0xFD65  | 0xFE65  | 0xFF65"
     )]
     LoadRegisters(#[deku(pad_bits_before = "4", bits = "4", pad_bits_after = "8")] u8),
 
+    #[deku(
+        id_pat = "0xF030 | 0xF130 | 0xF230 | 0xF330 | 0xF430 | 0xF530 | 0xF630 | 0xF730 | 0xF830 | 0xF930 | 0xFA30 | 0xFB30  | 0xFC30  | 0xFD30  | 0xFE30  | 0xFF30"
+    )]
+    SetBigSpriteI(#[deku(pad_bits_before = "4", bits = "4", pad_bits_after = "8")] u8),
+
+    #[deku(
+        id_pat = "0xF075 | 0xF175 | 0xF275 | 0xF375 | 0xF475 | 0xF575 | 0xF675 | 0xF775 | 0xF875 | 0xF975 | 0xFA75 | 0xFB75  | 0xFC75  | 0xFD75  | 0xFE75  | 0xFF75"
+    )]
+    DumpFlags(#[deku(pad_bits_before = "4", bits = "4", pad_bits_after = "8")] u8),
+
+    #[deku(
+        id_pat = "0xF085 | 0xF185 | 0xF285 | 0xF385 | 0xF485 | 0xF585 | 0xF685 | 0xF785 | 0xF885 | 0xF985 | 0xFA85 | 0xFB85  | 0xFC85  | 0xFD85  | 0xFE85  | 0xFF85"
+    )]
+    LoadFlags(#[deku(pad_bits_before = "4", bits = "4", pad_bits_after = "8")] u8),
+
     #[deku(id_pat = "_")]
     Unknown(u16),
 }
+
+impl Op {
+    /// Decodes the instruction at the start of `bytes`, or `None` if they
+    /// end in the middle of it.
+    pub fn decode(bytes: &[u8]) -> Option<Op> {
+        Op::from_bytes((bytes, 0)).ok().map(|(_, op)| op)
+    }
+
+    /// Encodes the instruction. Deku only writes the operands of variants
+    /// matched by `id_pat`, so the fixed bits of their opcodes are added
+    /// here.
+    pub fn encode(&self) -> Result<Vec<u8>, DekuError> {
+        let opcode: u16 = match self {
+            Op::ScrollDown(_) => 0x00C0,
+            Op::ScrollUp(_) => 0x00D0,
+            Op::AbsJump(_) => 0x1000,
+            Op::Call(_) => 0x2000,
+            Op::OffsetJump(_) => 0xB000,
+            Op::SkipEqVal(..) => 0x3000,
+            Op::SkipNeqVal(..) => 0x4000,
+            Op::SkipEqReg(..) => 0x5000,
+            Op::SaveRange(..) => 0x5002,
+            Op::LoadRange(..) => 0x5003,
+            Op::SetVal(..) => 0x6000,
+            Op::AddVal(..) => 0x7000,
+            Op::SkipNeqReg(..) => 0x9000,
+            Op::Rand(..) => 0xC000,
+            Op::Mov(..) => 0x8000,
+            Op::Or(..) => 0x8001,
+            Op::And(..) => 0x8002,
+            Op::Xor(..) => 0x8003,
+            Op::Add(..) => 0x8004,
+            Op::Sub(..) => 0x8005,
+            Op::Shr(..) => 0x8006,
+            Op::SubN(..) => 0x8007,
+            Op::Shl(..) => 0x800E,
+            Op::Draw(..) => 0xD000,
+            Op::SkipKey(_) => 0xE09E,
+            Op::SkipNoKey(_) => 0xE0A1,
+            Op::GetKey(_) => 0xF00A,
+            Op::GetDelay(_) => 0xF007,
+            Op::SetDelay(_) => 0xF015,
+            Op::SetSoundTimer(_) => 0xF018,
+            Op::IncrIndex(_) => 0xF01E,
+            Op::SetIndex(_) => 0xA000,
+            Op::SelectPlanes(_) => 0xF001,
+            Op::SetPitch(_) => 0xF03A,
+            Op::SetSpriteI(_) => 0xF029,
+            Op::DecimalRepr(_) => 0xF033,
+            Op::DumpRegisters(_) => 0xF055,
+            Op::LoadRegisters(_) => 0xF065,
+            Op::SetBigSpriteI(_) => 0xF030,
+            Op::DumpFlags(_) => 0xF075,
+            Op::LoadFlags(_) => 0xF085,
+            // Written in full by deku
+            Op::Clear
+            | Op::Return
+            | Op::ScrollRight
+            | Op::ScrollLeft
+            | Op::Exit
+            | Op::LowRes
+            | Op::HighRes
+            | Op::LongIndex(_)
+            | Op::LoadAudio
+            | Op::Unknown(_) => 0,
+        };
+        let mut bytes = self.to_bytes()?;
+        bytes[0] |= (opcode >> 8) as u8;
+        bytes[1] |= opcode as u8;
+        Ok(bytes)
+    }
+
+    /// Number of bytes the instruction occupies in memory.
+    #[inline]
+    pub fn size(&self) -> u16 {
+        match self {
+            Op::LongIndex(_) => 4,
+            _ => 2,
+        }
+    }
+}
+
+/// Formats the instruction in the mnemonics of Cowgod's technical reference,
+/// with the SUPER-CHIP and XO-CHIP additions.
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Op::Clear => write!(f, "CLS"),
+            Op::Return => write!(f, "RET"),
+            Op::ScrollDown(n) => write!(f, "SCD {n}"),
+            Op::ScrollUp(n) => write!(f, "SCU {n}"),
+            Op::ScrollRight => write!(f, "SCR"),
+            Op::ScrollLeft => write!(f, "SCL"),
+            Op::Exit => write!(f, "EXIT"),
+            Op::LowRes => write!(f, "LOW"),
+            Op::HighRes => write!(f, "HIGH"),
+            Op::AbsJump(addr) => write!(f, "JP #{addr:03X}"),
+            Op::Call(addr) => write!(f, "CALL #{addr:03X}"),
+            Op::OffsetJump(addr) => write!(f, "JP V0, #{addr:03X}"),
+            Op::SkipEqVal(x, val) => write!(f, "SE V{x:X}, #{val:02X}"),
+            Op::SkipNeqVal(x, val) => write!(f, "SNE V{x:X}, #{val:02X}"),
+            Op::SkipEqReg(x, y) => write!(f, "SE V{x:X}, V{y:X}"),
+            Op::SaveRange(x, y) => write!(f, "SAVE V{x:X}, V{y:X}"),
+            Op::LoadRange(x, y) => write!(f, "LOAD V{x:X}, V{y:X}"),
+            Op::SetVal(x, val) => write!(f, "LD V{x:X}, #{val:02X}"),
+            Op::AddVal(x, val) => write!(f, "ADD V{x:X}, #{val:02X}"),
+            Op::SkipNeqReg(x, y) => write!(f, "SNE V{x:X}, V{y:X}"),
+            Op::Rand(x, val) => write!(f, "RND V{x:X}, #{val:02X}"),
+            Op::Mov(x, y) => write!(f, "LD V{x:X}, V{y:X}"),
+            Op::Or(x, y) => write!(f, "OR V{x:X}, V{y:X}"),
+            Op::And(x, y) => write!(f, "AND V{x:X}, V{y:X}"),
+            Op::Xor(x, y) => write!(f, "XOR V{x:X}, V{y:X}"),
+            Op::Add(x, y) => write!(f, "ADD V{x:X}, V{y:X}"),
+            Op::Sub(x, y) => write!(f, "SUB V{x:X}, V{y:X}"),
+            Op::Shr(x, y) => write!(f, "SHR V{x:X}, V{y:X}"),
+            Op::SubN(x, y) => write!(f, "SUBN V{x:X}, V{y:X}"),
+            Op::Shl(x, y) => write!(f, "SHL V{x:X}, V{y:X}"),
+            Op::Draw(x, y, n) => write!(f, "DRW V{x:X}, V{y:X}, {n}"),
+            Op::SkipKey(x) => write!(f, "SKP V{x:X}"),
+            Op::SkipNoKey(x) => write!(f, "SKNP V{x:X}"),
+            Op::GetKey(x) => write!(f, "LD V{x:X}, K"),
+            Op::GetDelay(x) => write!(f, "LD V{x:X}, DT"),
+            Op::SetDelay(x) => write!(f, "LD DT, V{x:X}"),
+            Op::SetSoundTimer(x) => write!(f, "LD ST, V{x:X}"),
+            Op::IncrIndex(x) => write!(f, "ADD I, V{x:X}"),
+            Op::SetIndex(addr) => write!(f, "LD I, #{addr:03X}"),
+            Op::LongIndex(addr) => write!(f, "LD I, LONG #{addr:04X}"),
+            Op::SelectPlanes(n) => write!(f, "PLANE {n}"),
+            Op::LoadAudio => write!(f, "AUDIO"),
+            Op::SetPitch(x) => write!(f, "PITCH V{x:X}"),
+            Op::SetSpriteI(x) => write!(f, "LD F, V{x:X}"),
+            Op::DecimalRepr(x) => write!(f, "LD B, V{x:X}"),
+            Op::DumpRegisters(x) => write!(f, "LD [I], V{x:X}"),
+            Op::LoadRegisters(x) => write!(f, "LD V{x:X}, [I]"),
+            Op::SetBigSpriteI(x) => write!(f, "LD HF, V{x:X}"),
+            Op::DumpFlags(x) => write!(f, "LD R, V{x:X}"),
+            Op::LoadFlags(x) => write!(f, "LD V{x:X}, R"),
+            Op::Unknown(opcode) => write!(f, "DW #{opcode:04X}"),
+        }
+    }
+}
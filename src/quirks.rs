@@ -0,0 +1,113 @@
+use crate::config::Platform;
+
+/// Behaviours that differ between CHIP-8 interpreters. ROMs are usually
+/// written against one of them, so pick the preset matching the ROM's target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// `8xy6`/`8xyE` shift VY into VX instead of shifting VX in place.
+    pub shift_copies_vy: bool,
+    /// `8xy1`/`8xy2`/`8xy3` reset VF to zero.
+    pub logic_resets_vf: bool,
+    /// How far `Fx55`/`Fx65` move I after accessing memory.
+    pub index_increment: IndexIncrement,
+    /// `Bnnn` is read as `Bxnn` and jumps to `xnn + VX` instead of `nnn + V0`.
+    pub jump_uses_vx: bool,
+    /// Sprites crossing the screen edge wrap around instead of being clipped.
+    pub wrap_sprites: bool,
+    /// `Dxyn` waits for the next 60 Hz period, allowing one draw per frame.
+    pub display_wait: bool,
+    /// Number of nested calls before `2nnn` faults with a stack overflow.
+    pub stack_depth: usize,
+    pub stack_location: StackLocation,
+}
+
+/// Where `2nnn` keeps return addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackLocation {
+    /// Outside of emulated memory, out of reach of the program.
+    Internal,
+    /// In emulated memory, two bytes per call growing down from `top`, where
+    /// programs can read or overwrite them.
+    Memory { top: u16 },
+}
+
+impl StackLocation {
+    /// Where the COSMAC VIP interpreter keeps its stack.
+    pub const VIP: StackLocation = StackLocation::Memory { top: 0xED0 };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexIncrement {
+    /// I is left past the last register, as on the COSMAC VIP.
+    XPlusOne,
+    /// I is advanced by `x`, as on CHIP-48.
+    X,
+    /// I is left unchanged, as on SUPER-CHIP 1.1.
+    None,
+}
+
+impl IndexIncrement {
+    #[inline]
+    pub fn amount(self, x: u8) -> u16 {
+        match self {
+            IndexIncrement::XPlusOne => x as u16 + 1,
+            IndexIncrement::X => x as u16,
+            IndexIncrement::None => 0,
+        }
+    }
+}
+
+impl Quirks {
+    /// The original COSMAC VIP interpreter.
+    pub const CHIP8: Quirks = Quirks {
+        shift_copies_vy: true,
+        logic_resets_vf: true,
+        index_increment: IndexIncrement::XPlusOne,
+        jump_uses_vx: false,
+        wrap_sprites: false,
+        display_wait: true,
+        stack_depth: 12,
+        stack_location: StackLocation::Internal,
+    };
+
+    /// CHIP-48 on the HP-48 calculators.
+    pub const CHIP48: Quirks = Quirks {
+        shift_copies_vy: false,
+        logic_resets_vf: false,
+        index_increment: IndexIncrement::X,
+        jump_uses_vx: true,
+        wrap_sprites: false,
+        display_wait: false,
+        stack_depth: 16,
+        stack_location: StackLocation::Internal,
+    };
+
+    /// SUPER-CHIP 1.1.
+    pub const SCHIP: Quirks = Quirks {
+        shift_copies_vy: false,
+        logic_resets_vf: false,
+        index_increment: IndexIncrement::None,
+        jump_uses_vx: true,
+        wrap_sprites: false,
+        display_wait: false,
+        stack_depth: 16,
+        stack_location: StackLocation::Internal,
+    };
+
+    /// XO-CHIP as implemented by Octo.
+    pub const XOCHIP: Quirks = Quirks {
+        shift_copies_vy: true,
+        logic_resets_vf: false,
+        index_increment: IndexIncrement::XPlusOne,
+        jump_uses_vx: false,
+        wrap_sprites: true,
+        display_wait: false,
+        stack_depth: 16,
+        stack_location: StackLocation::Internal,
+    };
+
+    /// The quirks of the platform called `name`, as in [`Platform::ALL`].
+    pub fn from_name(name: &str) -> Option<Quirks> {
+        Platform::from_name(name).map(Platform::quirks)
+    }
+}
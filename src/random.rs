@@ -0,0 +1,155 @@
+use std::fmt;
+
+/// Source of the bytes that `Cxkk` masks with `kk`.
+pub trait Random {
+    /// `mem` is the emulated memory, which some generators draw from.
+    fn next_byte(&mut self, mem: &[u8]) -> u8;
+
+    /// The generator's internal state, so it can be rewound or saved.
+    fn state(&self) -> u64;
+
+    /// Puts the generator back into a state returned by [`Random::state`].
+    fn set_state(&mut self, state: u64);
+
+    /// Which generator this is, so saved states are only loaded into the same
+    /// kind of generator.
+    fn kind(&self) -> RandomKind {
+        RandomKind::Other
+    }
+}
+
+/// The generators provided by this module, and everything else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RandomKind {
+    Seeded,
+    Vip,
+    Other,
+}
+
+impl fmt::Display for RandomKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RandomKind::Seeded => write!(f, "seeded"),
+            RandomKind::Vip => write!(f, "VIP"),
+            RandomKind::Other => write!(f, "custom"),
+        }
+    }
+}
+
+/// Pseudo-random bytes from a seed, so runs can be reproduced.
+pub struct SeededRandom(fastrand::Rng);
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> Self {
+        SeededRandom(fastrand::Rng::with_seed(seed))
+    }
+}
+
+impl Default for SeededRandom {
+    /// Seeds the generator from the host's randomness.
+    fn default() -> Self {
+        SeededRandom(fastrand::Rng::new())
+    }
+}
+
+impl Random for SeededRandom {
+    fn next_byte(&mut self, _mem: &[u8]) -> u8 {
+        self.0.u8(..)
+    }
+
+    fn state(&self) -> u64 {
+        self.0.get_seed()
+    }
+
+    fn set_state(&mut self, state: u64) {
+        self.0.seed(state);
+    }
+
+    fn kind(&self) -> RandomKind {
+        RandomKind::Seeded
+    }
+}
+
+/// Modelled on the routine of the COSMAC VIP interpreter, which keeps its
+/// state in register R9: every `Cxkk` increments R9, adds the memory byte R9
+/// points at to its high half and returns that half. Results depend on the
+/// contents of memory, so they are only repeatable for identical programs.
+pub struct VipRandom {
+    r9: u16,
+}
+
+impl VipRandom {
+    /// The VIP only addresses 4 KiB; higher addresses wrap around.
+    const ADDRESS_MASK: usize = 0xFFF;
+
+    pub fn new(seed: u16) -> Self {
+        VipRandom { r9: seed }
+    }
+}
+
+impl Random for VipRandom {
+    fn next_byte(&mut self, mem: &[u8]) -> u8 {
+        self.r9 = self.r9.wrapping_add(1);
+        let [high, low] = self.r9.to_be_bytes();
+        let byte = mem
+            .get(self.r9 as usize & Self::ADDRESS_MASK)
+            .copied()
+            .unwrap_or(0);
+        let high = high.wrapping_add(byte);
+        self.r9 = u16::from_be_bytes([high, low]);
+        high
+    }
+
+    fn state(&self) -> u64 {
+        self.r9 as u64
+    }
+
+    fn set_state(&mut self, state: u64) {
+        self.r9 = state as u16;
+    }
+
+    fn kind(&self) -> RandomKind {
+        RandomKind::Vip
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(rng: &mut impl Random, mem: &[u8], n: usize) -> Vec<u8> {
+        (0..n).map(|_| rng.next_byte(mem)).collect()
+    }
+
+    #[test]
+    fn vip_random_adds_memory_to_r9() {
+        let mem: Vec<u8> = (0..0x1000).map(|addr: usize| (addr * 7) as u8).collect();
+        let mut rng = VipRandom::new(0x00FF);
+        assert_eq!(
+            bytes(&mut rng, &mem, 6),
+            [0x01, 0x08, 0x16, 0x2B, 0x47, 0x6A]
+        );
+        assert_eq!(rng.state(), 0x6A05);
+
+        // R9 wraps around, and only its low 12 bits address memory
+        let mut rng = VipRandom::new(0xFFFF);
+        assert_eq!(bytes(&mut rng, &mem, 3), [0x00, 0x07, 0x15]);
+        rng.set_state(0x1FFF);
+        assert_eq!(bytes(&mut rng, &mem, 1), [0x20]);
+
+        // Bytes past the end of memory read as zero
+        rng.set_state(0x00FF);
+        assert_eq!(bytes(&mut rng, &mem[..0x100], 2), [0x01, 0x01]);
+    }
+
+    #[test]
+    fn seeded_random_repeats_from_its_state() {
+        let mut rng = SeededRandom::new(42);
+        let state = rng.state();
+        let first = bytes(&mut rng, &[], 8);
+        assert_ne!(bytes(&mut rng, &[], 8), first);
+        rng.set_state(state);
+        assert_eq!(bytes(&mut rng, &[], 8), first);
+        assert_eq!(bytes(&mut SeededRandom::new(42), &[], 8), first);
+    }
+}
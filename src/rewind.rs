@@ -0,0 +1,282 @@
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use crate::debug::{Breakpoints, Hit};
+use crate::emulator::Snapshot;
+use crate::{Chip8, Scheduler};
+
+/// Frames kept by [`Rewind::default`], a minute's worth.
+pub const DEFAULT_CAPACITY: usize = 60 * 60;
+
+/// A ring buffer of snapshots of the emulator, recorded once per frame, to
+/// go back in time.
+///
+/// Going back to an instruction between two snapshots re-executes from the
+/// earlier one, which is deterministic as the snapshots include the random
+/// number generator and the keypad, and frontends only change the keypad
+/// between frames.
+///
+/// ```
+/// # use chip8::{rewind::Rewind, Chip8, Quirks, Scheduler, Speed};
+/// let mut chip8 = Chip8::new(Quirks::CHIP8);
+/// chip8.load_program(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+/// let mut scheduler = Scheduler::new(Speed::default());
+/// let mut rewind = Rewind::default();
+///
+/// for _ in 0..10 {
+///     rewind.record(&chip8, &scheduler);
+///     scheduler.run_frame(&mut chip8).unwrap();
+/// }
+/// let v0 = chip8.registers()[0];
+/// rewind.step_back(&mut chip8, &mut scheduler);
+/// assert_eq!(chip8.registers()[0], v0);
+/// rewind.step_back(&mut chip8, &mut scheduler);
+/// assert_eq!(chip8.registers()[0], v0 - 1);
+/// ```
+pub struct Rewind {
+    capacity: usize,
+    entries: VecDeque<Entry>,
+    /// The memory as of the newest entry.
+    shadow: Box<[u8]>,
+}
+
+struct Entry {
+    state: Snapshot,
+    /// The scheduler's position within its frame.
+    frame_position: (u64, u64),
+    /// Bytes to write to the memory of the next entry to get this entry's
+    /// memory back.
+    undo: Vec<(u16, u8)>,
+}
+
+impl Default for Rewind {
+    fn default() -> Self {
+        Rewind::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl Rewind {
+    /// A buffer of at most `capacity` snapshots, dropping the oldest ones.
+    pub fn new(capacity: usize) -> Self {
+        Rewind {
+            capacity: capacity.max(1),
+            entries: VecDeque::new(),
+            shadow: Box::default(),
+        }
+    }
+
+    /// Number of snapshots recorded.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Forgets all snapshots, e.g. after loading another program.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.shadow = Box::default();
+    }
+
+    /// Takes a snapshot, meant to be called before running each frame. A
+    /// snapshot taken again without executing any instructions replaces the
+    /// previous one, e.g. to capture keys pressed while paused.
+    pub fn record(&mut self, chip8: &Chip8, scheduler: &Scheduler) {
+        let mem = chip8.memory();
+        if self.shadow.len() != mem.len() {
+            self.clear();
+            self.shadow = mem.into();
+        }
+
+        let mut state = chip8.snapshot();
+        if let Some(newest) = self.entries.back() {
+            if *newest.state.screen == *state.screen {
+                state.screen = Rc::clone(&newest.state.screen);
+            }
+        }
+
+        let replace = self
+            .entries
+            .back()
+            .is_some_and(|newest| newest.state.cycles == state.cycles);
+        if replace {
+            self.entries.pop_back();
+        }
+
+        // The previous entry gets back the bytes changed since, keeping the
+        // oldest value of bytes it already restores
+        let changes = self.diff(mem);
+        if let Some(previous) = self.entries.back_mut() {
+            for &(addr, old) in &changes {
+                if !previous.undo.iter().any(|&(a, _)| a == addr) {
+                    previous.undo.push((addr, old));
+                }
+            }
+        }
+        for &(addr, _) in &changes {
+            self.shadow[addr as usize] = mem[addr as usize];
+        }
+
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(Entry {
+            state,
+            frame_position: scheduler.frame_position(),
+            undo: Vec::new(),
+        });
+    }
+
+    /// The bytes of `mem` that differ from the newest entry, with their
+    /// values there.
+    fn diff(&self, mem: &[u8]) -> Vec<(u16, u8)> {
+        mem.iter()
+            .zip(self.shadow.iter())
+            .enumerate()
+            .filter(|(_, (new, old))| new != old)
+            .map(|(addr, (_, &old))| (addr as u16, old))
+            .collect()
+    }
+
+    /// Returns to the snapshot at `index`, dropping the newer ones.
+    fn restore(&mut self, index: usize, chip8: &mut Chip8, scheduler: &mut Scheduler) {
+        for entry in self.entries.range(index..).rev().skip(1) {
+            for &(addr, old) in &entry.undo {
+                self.shadow[addr as usize] = old;
+            }
+        }
+        self.entries.truncate(index + 1);
+
+        let entry = &mut self.entries[index];
+        entry.undo.clear();
+        chip8.restore(&entry.state, &self.shadow);
+        scheduler.set_frame_position(entry.frame_position);
+        scheduler.reset_clock();
+    }
+
+    /// The newest snapshot taken at most `cycles` instructions into the run.
+    fn latest_before(&self, cycles: u64) -> Option<usize> {
+        self.entries
+            .iter()
+            .rposition(|entry| entry.state.cycles <= cycles)
+    }
+
+    /// Goes back `frames` frames, or as far as the snapshots reach, returning
+    /// the number of frames rewound. Frames are counted from the newest
+    /// snapshot, which the frame being run counts as if it began there.
+    pub fn rewind(&mut self, chip8: &mut Chip8, scheduler: &mut Scheduler, frames: usize) -> usize {
+        let Some(newest) = self.entries.back() else {
+            return 0;
+        };
+        let ahead = usize::from(newest.state.cycles != chip8.cycles());
+        let index = (self.entries.len() - 1).saturating_sub(frames.saturating_sub(ahead));
+        let rewound = self.entries.len() - 1 - index + ahead;
+        if rewound > 0 {
+            untraced(chip8, |chip8| self.restore(index, chip8, scheduler));
+        }
+        rewound
+    }
+
+    /// Undoes the last instruction, returning false when no snapshot reaches
+    /// back far enough.
+    pub fn step_back(&mut self, chip8: &mut Chip8, scheduler: &mut Scheduler) -> bool {
+        let Some(target) = chip8.cycles().checked_sub(1) else {
+            return false;
+        };
+        let Some(index) = self.latest_before(target) else {
+            return false;
+        };
+        untraced(chip8, |chip8| {
+            self.restore(index, chip8, scheduler);
+            replay(chip8, scheduler, target, |_, _| ());
+        });
+        true
+    }
+
+    /// Goes back to the latest point where a breakpoint was hit, returning
+    /// the hits. Stops at the oldest snapshot, with no hits, if no breakpoint
+    /// is hit since.
+    pub fn continue_back(
+        &mut self,
+        chip8: &mut Chip8,
+        scheduler: &mut Scheduler,
+        breakpoints: &mut Breakpoints,
+    ) -> Vec<Hit> {
+        untraced(chip8, |chip8| {
+            self.search_back(chip8, scheduler, breakpoints)
+        })
+    }
+
+    fn search_back(
+        &mut self,
+        chip8: &mut Chip8,
+        scheduler: &mut Scheduler,
+        breakpoints: &mut Breakpoints,
+    ) -> Vec<Hit> {
+        let mut end = chip8.cycles();
+        // Where the search starts was already checked, while the start of
+        // an earlier stretch was not
+        let mut include_end = false;
+        // Search the stretches between snapshots from the newest one back
+        while let Some(index) = end.checked_sub(1).and_then(|last| self.latest_before(last)) {
+            self.restore(index, chip8, scheduler);
+            let start = chip8.cycles();
+            breakpoints.sync(chip8);
+
+            let mut latest = None;
+            replay(chip8, scheduler, end, |chip8, step| {
+                let hits = breakpoints.check(chip8, step);
+                if !hits.is_empty() && (chip8.cycles() < end || include_end) {
+                    latest = Some((chip8.cycles(), hits));
+                }
+            });
+
+            self.restore(index, chip8, scheduler);
+            if let Some((cycles, hits)) = latest {
+                breakpoints.sync(chip8);
+                replay(chip8, scheduler, cycles, |chip8, step| {
+                    breakpoints.check(chip8, step);
+                });
+                return hits;
+            }
+            end = start;
+            include_end = true;
+        }
+        Vec::new()
+    }
+}
+
+/// Runs `f` with tracing suspended, so that instructions replayed to go back
+/// in time are not traced again, marking in the trace where it went back to.
+fn untraced<T>(chip8: &mut Chip8, f: impl FnOnce(&mut Chip8) -> T) -> T {
+    let Some(mut tracer) = chip8.take_tracer() else {
+        return f(chip8);
+    };
+    let cycles = chip8.cycles();
+    let result = f(chip8);
+    if chip8.cycles() != cycles {
+        tracer.rewound(chip8);
+    }
+    chip8.set_tracer(tracer);
+    result
+}
+
+/// Executes instructions until `cycles` have been executed since the start,
+/// or the program stops.
+fn replay(
+    chip8: &mut Chip8,
+    scheduler: &mut Scheduler,
+    cycles: u64,
+    mut on_step: impl FnMut(&Chip8, &crate::Step),
+) {
+    while chip8.cycles() < cycles {
+        match scheduler.step(chip8) {
+            Ok(Some(step)) => on_step(chip8, &step),
+            Ok(None) | Err(_) => break,
+        }
+    }
+}
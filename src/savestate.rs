@@ -0,0 +1,411 @@
+//! The on-disk format of save states: a header identifying the program and
+//! platform, followed by the machine's state. Numbers are little-endian.
+//!
+//! ```text
+//! magic        "CHIP8SAV"
+//! version      u16
+//! program      u64 hash of the ROM
+//! platform     memory size u32, load address u16, quirks
+//! state        registers, stack, timers, keypad, RNG and its kind, audio,
+//!              screen
+//! memory       the whole memory
+//! ```
+
+use std::rc::Rc;
+use std::{error, fmt, io};
+
+use crate::audio::{Audio, PATTERN_SIZE};
+use crate::config::Chip8Config;
+use crate::display::{Framebuffer, N_PIXELS};
+use crate::emulator::{Chip8, Snapshot};
+use crate::quirks::{IndexIncrement, Quirks, StackLocation};
+use crate::random::RandomKind;
+
+const MAGIC: &[u8; 8] = b"CHIP8SAV";
+
+/// Version written by [`Chip8::save_state`]. Bump it whenever the format
+/// changes, keeping older versions readable where possible.
+pub const VERSION: u16 = 1;
+
+/// FNV-1a, which is stable across builds and platforms unlike the standard
+/// library's hashers.
+pub fn program_hash(program: &[u8]) -> u64 {
+    program.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+pub(crate) fn write(
+    chip8: &Chip8,
+    config: &Chip8Config,
+    out: &mut impl io::Write,
+) -> io::Result<()> {
+    let state = chip8.snapshot();
+    let mut data = Vec::new();
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&VERSION.to_le_bytes());
+    data.extend_from_slice(&chip8.program_hash().to_le_bytes());
+    write_platform(&mut data, config);
+
+    data.extend_from_slice(&state.pc.to_le_bytes());
+    data.extend_from_slice(&state.ireg.to_le_bytes());
+    data.extend_from_slice(&state.v);
+    data.extend_from_slice(&(state.stack_len as u32).to_le_bytes());
+    data.extend_from_slice(&(state.stack.len() as u32).to_le_bytes());
+    for addr in &state.stack {
+        data.extend_from_slice(&addr.to_le_bytes());
+    }
+    data.push(state.dt);
+    data.push(state.st);
+    data.extend_from_slice(&state.timer_clock.to_le_bytes());
+    let keypad = (0..16).fold(0u16, |keys, key| keys | (state.keypad[key] as u16) << key);
+    data.extend_from_slice(&keypad.to_le_bytes());
+    data.push(state.pending_key.map_or(0xFF, |key| key));
+    data.push(state.vblank as u8);
+    data.extend_from_slice(&state.flags);
+    data.push(state.halted as u8);
+    data.extend_from_slice(&state.rng.to_le_bytes());
+    data.push(match chip8.random_kind() {
+        RandomKind::Seeded => 0,
+        RandomKind::Vip => 1,
+        RandomKind::Other => 2,
+    });
+    data.extend_from_slice(&state.audio.pattern);
+    data.push(state.audio.pitch);
+    data.extend_from_slice(&state.audio.position.to_le_bytes());
+    data.extend_from_slice(&state.cycles.to_le_bytes());
+    data.push(state.screen.hires as u8);
+    data.push(state.screen.planes);
+    data.extend_from_slice(&state.screen.pixels);
+
+    data.extend_from_slice(chip8.memory());
+    out.write_all(&data)
+}
+
+fn write_platform(data: &mut Vec<u8>, config: &Chip8Config) {
+    let quirks = &config.quirks;
+    data.extend_from_slice(&(config.memory_len() as u32).to_le_bytes());
+    data.extend_from_slice(&config.load_address.to_le_bytes());
+    data.push(quirks.shift_copies_vy as u8);
+    data.push(quirks.logic_resets_vf as u8);
+    data.push(match quirks.index_increment {
+        IndexIncrement::XPlusOne => 0,
+        IndexIncrement::X => 1,
+        IndexIncrement::None => 2,
+    });
+    data.push(quirks.jump_uses_vx as u8);
+    data.push(quirks.wrap_sprites as u8);
+    data.push(quirks.display_wait as u8);
+    data.extend_from_slice(&(quirks.stack_depth as u32).to_le_bytes());
+    let top = match quirks.stack_location {
+        StackLocation::Internal => None,
+        StackLocation::Memory { top } => Some(top),
+    };
+    data.push(top.is_some() as u8);
+    data.extend_from_slice(&top.unwrap_or_default().to_le_bytes());
+}
+
+/// Decodes a save state, checking that it belongs to the program with
+/// `program_hash` running on the platform of `config` with a generator of
+/// kind `rng_kind`.
+pub(crate) fn read(
+    data: &[u8],
+    program_hash: u64,
+    config: &Chip8Config,
+    rng_kind: RandomKind,
+) -> Result<(Snapshot, Vec<u8>), SaveStateError> {
+    let mut reader = Reader { data };
+    if reader.bytes(MAGIC.len())? != MAGIC {
+        return Err(SaveStateError::NotASaveState);
+    }
+    let version = reader.u16()?;
+    if version != VERSION {
+        return Err(SaveStateError::UnsupportedVersion(version));
+    }
+    if reader.u64()? != program_hash {
+        return Err(SaveStateError::ProgramMismatch);
+    }
+    read_platform(&mut reader, config)?;
+
+    let pc = reader.u16()?;
+    let ireg = reader.u16()?;
+    let v = reader.array()?;
+    let stack_len = reader.u32()? as usize;
+    let stack = (0..reader.u32()?)
+        .map(|_| reader.u16())
+        .collect::<Result<Vec<_>, _>>()?;
+    let stack_matches = match config.quirks.stack_location {
+        StackLocation::Internal => stack.len() == stack_len,
+        // The return addresses are part of memory
+        StackLocation::Memory { .. } => stack.is_empty(),
+    };
+    if stack_len > config.quirks.stack_depth || !stack_matches {
+        return Err(SaveStateError::Corrupt);
+    }
+    let dt = reader.u8()?;
+    let st = reader.u8()?;
+    let timer_clock = reader.u64()?;
+    let keys = reader.u16()?;
+    let keypad = std::array::from_fn(|key| keys & 1 << key != 0);
+    let pending_key = Some(reader.u8()?).filter(|&key| key <= 0xF);
+    let vblank = reader.flag()?;
+    let flags = reader.array()?;
+    let halted = reader.flag()?;
+    let rng = reader.u64()?;
+    let saved_kind = match reader.u8()? {
+        0 => RandomKind::Seeded,
+        1 => RandomKind::Vip,
+        2 => RandomKind::Other,
+        _ => return Err(SaveStateError::Corrupt),
+    };
+    if saved_kind != rng_kind {
+        return Err(SaveStateError::PlatformMismatch(format!(
+            "{saved_kind} random numbers instead of {rng_kind}"
+        )));
+    }
+    let mut audio = Audio::new();
+    audio.pattern = reader.array::<PATTERN_SIZE>()?;
+    audio.pitch = reader.u8()?;
+    audio.position = f64::from_le_bytes(reader.array()?);
+    let cycles = reader.u64()?;
+    let mut screen = Framebuffer::new();
+    screen.hires = reader.flag()?;
+    screen.planes = reader.u8()? & 0b11;
+    screen.pixels = reader.array::<N_PIXELS>()?;
+    // Each pixel holds one bit per plane
+    if screen.pixels.iter().any(|&pixel| pixel > 0b11) {
+        return Err(SaveStateError::Corrupt);
+    }
+    let mem = reader.bytes(config.memory_len())?.to_vec();
+    if !reader.data.is_empty() {
+        return Err(SaveStateError::Corrupt);
+    }
+
+    let snapshot = Snapshot {
+        pc,
+        ireg,
+        stack,
+        stack_len,
+        dt,
+        st,
+        timer_clock,
+        v,
+        keypad,
+        pending_key,
+        vblank,
+        flags,
+        halted,
+        audio,
+        rng,
+        screen: Rc::new(screen),
+        cycles,
+    };
+    Ok((snapshot, mem))
+}
+
+fn read_platform(reader: &mut Reader, config: &Chip8Config) -> Result<(), SaveStateError> {
+    let memory_size = reader.u32()? as usize;
+    if memory_size != config.memory_len() {
+        return Err(SaveStateError::PlatformMismatch(format!(
+            "{memory_size} bytes of memory instead of {}",
+            config.memory_len()
+        )));
+    }
+    let load_address = reader.u16()?;
+    if load_address != config.load_address {
+        return Err(SaveStateError::PlatformMismatch(format!(
+            "load address {load_address:#05x} instead of {:#05x}",
+            config.load_address
+        )));
+    }
+
+    let shift_copies_vy = reader.flag()?;
+    let logic_resets_vf = reader.flag()?;
+    let index_increment = match reader.u8()? {
+        0 => IndexIncrement::XPlusOne,
+        1 => IndexIncrement::X,
+        2 => IndexIncrement::None,
+        _ => return Err(SaveStateError::Corrupt),
+    };
+    let jump_uses_vx = reader.flag()?;
+    let wrap_sprites = reader.flag()?;
+    let display_wait = reader.flag()?;
+    let stack_depth = reader.u32()? as usize;
+    let in_memory = reader.flag()?;
+    let top = reader.u16()?;
+    let quirks = Quirks {
+        shift_copies_vy,
+        logic_resets_vf,
+        index_increment,
+        jump_uses_vx,
+        wrap_sprites,
+        display_wait,
+        stack_depth,
+        stack_location: match in_memory {
+            true => StackLocation::Memory { top },
+            false => StackLocation::Internal,
+        },
+    };
+    if quirks != config.quirks {
+        return Err(SaveStateError::PlatformMismatch(String::from(
+            "different quirks",
+        )));
+    }
+    Ok(())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], SaveStateError> {
+        if self.data.len() < len {
+            return Err(SaveStateError::Corrupt);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], SaveStateError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Ok(array)
+    }
+
+    fn u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn flag(&mut self) -> Result<bool, SaveStateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    fn u16(&mut self) -> Result<u16, SaveStateError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, SaveStateError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, SaveStateError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SaveStateError {
+    NotASaveState,
+    /// Written by a newer version of the emulator.
+    UnsupportedVersion(u16),
+    /// Saved while running another program.
+    ProgramMismatch,
+    /// Saved on a machine with another memory layout, other quirks or another
+    /// random number generator.
+    PlatformMismatch(String),
+    /// Cut short or otherwise damaged.
+    Corrupt,
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveStateError::NotASaveState => write!(f, "not a save state"),
+            SaveStateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version {version}")
+            }
+            SaveStateError::ProgramMismatch => write!(f, "saved from a different program"),
+            SaveStateError::PlatformMismatch(difference) => {
+                write!(f, "saved on an incompatible platform: {difference}")
+            }
+            SaveStateError::Corrupt => write!(f, "save state is damaged"),
+        }
+    }
+}
+
+impl error::Error for SaveStateError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::VipRandom;
+
+    /// Offset of the stack length, after the header and the platform.
+    const STACK_LEN: usize = 8 + 2 + 8 + 19 + 2 + 2 + 16;
+
+    fn chip8() -> Chip8 {
+        let mut chip8 = Chip8::new(Quirks::CHIP8);
+        // CALL 0x204; JP 0x202; RET
+        chip8
+            .load_program(&[0x22, 0x04, 0x12, 0x02, 0x00, 0xEE])
+            .unwrap();
+        chip8
+    }
+
+    fn save(chip8: &Chip8) -> Vec<u8> {
+        let mut data = Vec::new();
+        chip8.save_state(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut chip8 = chip8();
+        chip8.step();
+        let data = save(&chip8);
+        chip8.step();
+        chip8.load_state(&data).unwrap();
+        assert_eq!(chip8.pc(), 0x204);
+        assert_eq!(chip8.call_stack(), [0x202]);
+    }
+
+    #[test]
+    fn truncated_state_is_corrupt() {
+        let mut chip8 = chip8();
+        let data = save(&chip8);
+        for len in [STACK_LEN, data.len() - 1] {
+            assert_eq!(chip8.load_state(&data[..len]), Err(SaveStateError::Corrupt));
+        }
+        assert_eq!(chip8.pc(), 0x200);
+    }
+
+    #[test]
+    fn stack_length_must_match_the_stack() {
+        let mut chip8 = chip8();
+        chip8.step();
+        let mut data = save(&chip8);
+        assert_eq!(
+            data[STACK_LEN..STACK_LEN + 10],
+            [1, 0, 0, 0, 1, 0, 0, 0, 0x02, 0x02]
+        );
+        // Drop the return address but keep the length
+        data[STACK_LEN + 4] = 0;
+        data.drain(STACK_LEN + 8..STACK_LEN + 10);
+        assert_eq!(chip8.load_state(&data), Err(SaveStateError::Corrupt));
+    }
+
+    #[test]
+    fn pixels_must_fit_the_planes() {
+        let mut chip8 = chip8();
+        let mut data = save(&chip8);
+        // The screen comes right before the memory
+        let last_pixel = data.len() - chip8.memory().len() - 1;
+        data[last_pixel] = 0b100;
+        assert_eq!(chip8.load_state(&data), Err(SaveStateError::Corrupt));
+        data[last_pixel] = 0b11;
+        assert_eq!(chip8.load_state(&data), Ok(()));
+    }
+
+    #[test]
+    fn generator_must_match() {
+        let mut chip8 = chip8();
+        let data = save(&chip8);
+        chip8.set_random(VipRandom::new(0));
+        assert!(matches!(
+            chip8.load_state(&data),
+            Err(SaveStateError::PlatformMismatch(_))
+        ));
+    }
+}
@@ -0,0 +1,235 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::emulator::TIMER_FREQUENCY;
+use crate::{Chip8, Chip8Error, Step, StepOutcome};
+
+/// How many instructions the emulated CPU executes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Speed {
+    InstructionsPerFrame(u32),
+    /// Spread as evenly as possible over the 60 frames of each second.
+    InstructionsPerSecond(u32),
+}
+
+impl Default for Speed {
+    fn default() -> Self {
+        Speed::InstructionsPerFrame(15)
+    }
+}
+
+/// Runs the emulator in 60 Hz frames: a batch of instructions followed by one
+/// timer tick, then sleeping until the frame is over. Frame deadlines are
+/// computed from the start time rather than from the previous frame, so
+/// rounding and oversleeping do not accumulate into drift.
+pub struct Scheduler {
+    speed: Speed,
+    start: Instant,
+    /// Frames run since `start`.
+    frame: u64,
+    /// Instructions owed from previous frames when running at a rate that is
+    /// not a multiple of 60, in units of 1/60 instruction.
+    carry: u64,
+    /// Instructions left in the current frame, when it was interrupted by
+    /// [`Scheduler::run_frame_until`].
+    pending: u64,
+}
+
+/// How far behind the wall clock the scheduler may fall, e.g. after the host
+/// was suspended, before it gives up catching up and starts afresh.
+const MAX_LAG: Duration = Duration::from_millis(250);
+
+impl Scheduler {
+    pub fn new(speed: Speed) -> Self {
+        Scheduler {
+            speed,
+            start: Instant::now(),
+            frame: 0,
+            carry: 0,
+            pending: 0,
+        }
+    }
+
+    #[inline]
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+        self.carry = 0;
+    }
+
+    /// Executes one frame's worth of instructions and ticks the timers once.
+    pub fn run_frame(&mut self, chip8: &mut Chip8) -> Result<(), Chip8Error> {
+        self.run_frame_until(chip8, |_, _| false).map(|_| ())
+    }
+
+    /// Like [`Scheduler::run_frame`], but returns early with the step that
+    /// `stop` accepted. The next call picks the frame up where it left off,
+    /// so stopping does not change how instructions and timer ticks
+    /// interleave.
+    pub fn run_frame_until(
+        &mut self,
+        chip8: &mut Chip8,
+        mut stop: impl FnMut(&Chip8, &Step) -> bool,
+    ) -> Result<Option<Step>, Chip8Error> {
+        if self.pending == 0 {
+            self.pending = self.instructions_per_frame();
+        }
+
+        while self.pending > 0 {
+            self.pending -= 1;
+            let step = chip8.step();
+            match step.outcome {
+                StepOutcome::Fault(err) => return Err(err),
+                StepOutcome::Halted => {
+                    self.pending = 0;
+                    break;
+                }
+                _ if stop(chip8, &step) => {
+                    if self.pending == 0 {
+                        self.end_frame(chip8);
+                    }
+                    return Ok(Some(step));
+                }
+                _ => (),
+            }
+        }
+        self.end_frame(chip8);
+        Ok(None)
+    }
+
+    /// Executes a single instruction, ticking the timers whenever it
+    /// completes a frame. Returns `None` once the program has halted.
+    pub fn step(&mut self, chip8: &mut Chip8) -> Result<Option<Step>, Chip8Error> {
+        while !chip8.is_halted() {
+            if let Some(step) = self.run_frame_until(chip8, |_, _| true)? {
+                return Ok(Some(step));
+            }
+        }
+        Ok(None)
+    }
+
+    /// How far into the current frame the scheduler is, for rewinding.
+    pub(crate) fn frame_position(&self) -> (u64, u64) {
+        (self.carry, self.pending)
+    }
+
+    pub(crate) fn set_frame_position(&mut self, (carry, pending): (u64, u64)) {
+        self.carry = carry;
+        self.pending = pending;
+    }
+
+    fn instructions_per_frame(&mut self) -> u64 {
+        match self.speed {
+            Speed::InstructionsPerFrame(n) => n as u64,
+            Speed::InstructionsPerSecond(n) => {
+                self.carry += n as u64;
+                let instructions = self.carry / TIMER_FREQUENCY;
+                self.carry %= TIMER_FREQUENCY;
+                instructions
+            }
+        }
+    }
+
+    fn end_frame(&mut self, chip8: &mut Chip8) {
+        chip8.tick_timers();
+        self.frame += 1;
+    }
+
+    /// Runs `frames` frames back to back, without waiting for the wall clock.
+    pub fn run_frames(&mut self, chip8: &mut Chip8, frames: u32) -> Result<(), Chip8Error> {
+        for _ in 0..frames {
+            if chip8.is_halted() {
+                break;
+            }
+            self.run_frame(chip8)?;
+        }
+        Ok(())
+    }
+
+    /// When the current frame should end.
+    pub fn next_deadline(&self) -> Instant {
+        let nanos = self.frame * 1_000_000_000 / TIMER_FREQUENCY;
+        self.start + Duration::from_nanos(nanos)
+    }
+
+    /// Starts counting frames afresh from now, e.g. after being paused, so the
+    /// scheduler does not try to catch up on the time that passed.
+    pub fn reset_clock(&mut self) {
+        self.start = Instant::now();
+        self.frame = 0;
+    }
+
+    /// Sleeps for the rest of the current frame.
+    pub fn wait_for_next_frame(&mut self) {
+        let deadline = self.next_deadline();
+        let now = Instant::now();
+        if now < deadline {
+            thread::sleep(deadline - now);
+        } else if now - deadline > MAX_LAG {
+            self.reset_clock();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Quirks;
+
+    /// A machine looping on a single jump.
+    fn chip8() -> Chip8 {
+        let mut chip8 = Chip8::new(Quirks::CHIP8);
+        chip8.load_program(&[0x12, 0x00]).unwrap();
+        chip8
+    }
+
+    #[test]
+    fn instructions_per_second_are_spread_over_frames() {
+        let mut chip8 = chip8();
+        let mut scheduler = Scheduler::new(Speed::InstructionsPerSecond(90));
+        let mut cycles = Vec::new();
+        for _ in 0..4 {
+            scheduler.run_frame(&mut chip8).unwrap();
+            cycles.push(chip8.cycles());
+        }
+        assert_eq!(cycles, [1, 3, 4, 6]);
+
+        scheduler.run_frames(&mut chip8, 56).unwrap();
+        assert_eq!(chip8.cycles(), 90);
+    }
+
+    #[test]
+    fn timers_tick_once_per_frame() {
+        let mut chip8 = chip8();
+        chip8.set_delay_timer(10);
+        let mut scheduler = Scheduler::new(Speed::InstructionsPerFrame(3));
+
+        // Stopping partway through a frame does not tick the timers
+        let step = scheduler.run_frame_until(&mut chip8, |_, _| true).unwrap();
+        assert_eq!(step.map(|step| step.addr), Some(0x200));
+        assert_eq!(chip8.delay_timer(), 10);
+
+        scheduler.run_frame(&mut chip8).unwrap();
+        assert_eq!(chip8.cycles(), 3);
+        assert_eq!(chip8.delay_timer(), 9);
+
+        for _ in 0..3 {
+            scheduler.step(&mut chip8).unwrap();
+        }
+        assert_eq!(chip8.delay_timer(), 8);
+    }
+
+    #[test]
+    fn run_frames_stops_at_faults() {
+        let mut chip8 = Chip8::new(Quirks::CHIP8);
+        chip8.load_program(&[0x00, 0xEE]).unwrap();
+        let mut scheduler = Scheduler::new(Speed::default());
+        assert!(matches!(
+            scheduler.run_frames(&mut chip8, 2),
+            Err(Chip8Error::StackUnderflow { addr: 0x200 })
+        ));
+    }
+}
@@ -0,0 +1,480 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::Path;
+
+use crate::{Chip8, Op};
+
+/// First line of every trace, identifying the format.
+pub const HEADER: &str = "# chip8 trace v1";
+const COLUMNS: &str =
+    "# cycle pc opcode v0 v1 v2 v3 v4 v5 v6 v7 v8 v9 va vb vc vd ve vf i dt st ; instruction";
+
+/// Writes a line per executed instruction, installed with
+/// [`Chip8::set_tracer`].
+///
+/// Each line holds the number of instructions executed before, the address
+/// and bytes of the instruction, and the registers, `I` and the timers once
+/// it executed, all in hexadecimal but the cycle. The disassembly follows a
+/// `;`, for reading only:
+///
+/// ```text
+/// 2 0204 F033 05 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 0300 00 00 ; LD B, V0
+/// ```
+///
+/// Lines starting with `#` are comments, which also mark where
+/// [`Rewind`](crate::rewind::Rewind) went back to an earlier cycle.
+/// Instructions waiting for a key are traced each time they execute.
+pub struct Tracer {
+    output: Box<dyn Write>,
+    /// The first error, after which nothing more is written.
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(output: impl Write + 'static) -> Self {
+        let mut tracer = Tracer {
+            output: Box::new(output),
+            error: None,
+        };
+        tracer.write(format_args!("{HEADER}\n{COLUMNS}"));
+        tracer
+    }
+
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Tracer::new(BufWriter::new(File::create(path)?)))
+    }
+
+    /// Records the instruction at `addr`, which just executed but is not
+    /// counted in [`Chip8::cycles`] yet.
+    pub(crate) fn record(&mut self, chip8: &Chip8, addr: u16, op: Op) {
+        let record = TraceRecord::capture(chip8, addr, op);
+        self.write(format_args!("{record}"));
+    }
+
+    /// Marks that execution went back to `chip8`'s current state, leaving
+    /// out the instructions replayed to get there.
+    pub(crate) fn rewound(&mut self, chip8: &Chip8) {
+        self.write(format_args!("# rewound to cycle {}", chip8.cycles()));
+    }
+
+    fn write(&mut self, line: fmt::Arguments) {
+        if self.error.is_none() {
+            if let Err(err) = writeln!(self.output, "{line}") {
+                self.error = Some(err);
+            }
+        }
+    }
+
+    /// Flushes the trace, reporting the first error writing it.
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => self.output.flush(),
+        }
+    }
+}
+
+/// A line of a trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub cycle: u64,
+    pub pc: u16,
+    /// The two or four bytes of the instruction.
+    pub opcode: Vec<u8>,
+    pub v: [u8; 16],
+    pub ireg: u16,
+    pub dt: u8,
+    pub st: u8,
+    /// The disassembly, which is not compared.
+    pub instruction: String,
+}
+
+impl TraceRecord {
+    fn capture(chip8: &Chip8, addr: u16, op: Op) -> Self {
+        let start = addr as usize;
+        let opcode = chip8
+            .memory()
+            .get(start..start + op.size() as usize)
+            .unwrap_or_default();
+        TraceRecord {
+            cycle: chip8.cycles(),
+            pc: addr,
+            opcode: opcode.to_vec(),
+            v: *chip8.registers(),
+            ireg: chip8.ireg(),
+            dt: chip8.delay_timer(),
+            st: chip8.sound_timer(),
+            instruction: op.to_string(),
+        }
+    }
+
+    pub fn parse(line: &str) -> Result<TraceRecord, String> {
+        let (fields, instruction) = line.split_once(';').unwrap_or((line, ""));
+        let fields: Vec<_> = fields.split_whitespace().collect();
+        let [cycle, pc, opcode, rest @ ..] = fields.as_slice() else {
+            return Err(String::from("expected a cycle, an address and an opcode"));
+        };
+        let [v @ .., ireg, dt, st] = rest else {
+            return Err(String::from("expected the registers, I and the timers"));
+        };
+        if v.len() != 16 {
+            return Err(format!("expected 16 registers, found {}", v.len()));
+        }
+
+        let opcode = (opcode.len() % 2 == 0)
+            .then(|| {
+                (0..opcode.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(opcode.get(i..i + 2)?, 16).ok())
+                    .collect::<Option<Vec<_>>>()
+            })
+            .flatten()
+            .filter(|bytes| !bytes.is_empty())
+            .ok_or(format!("invalid opcode '{opcode}'"))?;
+        let mut registers = [0; 16];
+        for (register, field) in registers.iter_mut().zip(v) {
+            *register = parse_hex(field, "register")?;
+        }
+
+        Ok(TraceRecord {
+            cycle: cycle
+                .parse()
+                .map_err(|_| format!("invalid cycle '{cycle}'"))?,
+            pc: parse_hex(pc, "address")?,
+            opcode,
+            v: registers,
+            ireg: parse_hex(ireg, "I")?,
+            dt: parse_hex(dt, "delay timer")?,
+            st: parse_hex(st, "sound timer")?,
+            instruction: instruction.trim().to_string(),
+        })
+    }
+
+    /// Describes the state differing from `other`, ignoring the cycle and
+    /// the disassembly.
+    pub fn differences(&self, other: &TraceRecord) -> Vec<String> {
+        let mut differences = Vec::new();
+        if self.pc != other.pc {
+            differences.push(format!("pc: {:04X} != {:04X}", self.pc, other.pc));
+        }
+        if self.opcode != other.opcode {
+            differences.push(format!(
+                "opcode: {} != {}",
+                hex(&self.opcode),
+                hex(&other.opcode)
+            ));
+        }
+        for (x, (a, b)) in self.v.iter().zip(&other.v).enumerate() {
+            if a != b {
+                differences.push(format!("V{x:X}: {a:02X} != {b:02X}"));
+            }
+        }
+        if self.ireg != other.ireg {
+            differences.push(format!("I: {:04X} != {:04X}", self.ireg, other.ireg));
+        }
+        if self.dt != other.dt {
+            differences.push(format!("DT: {:02X} != {:02X}", self.dt, other.dt));
+        }
+        if self.st != other.st {
+            differences.push(format!("ST: {:02X} != {:02X}", self.st, other.st));
+        }
+        differences
+    }
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {:04X} {}", self.cycle, self.pc, hex(&self.opcode))?;
+        for v in self.v {
+            write!(f, " {v:02X}")?;
+        }
+        write!(f, " {:04X} {:02X} {:02X}", self.ireg, self.dt, self.st)?;
+        if !self.instruction.is_empty() {
+            write!(f, " ; {}", self.instruction)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_hex<T: TryFrom<u32>>(field: &str, name: &str) -> Result<T, String> {
+    u32::from_str_radix(field, 16)
+        .ok()
+        .and_then(|value| T::try_from(value).ok())
+        .ok_or(format!("invalid {name} '{field}'"))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02X}")).collect()
+}
+
+/// Reads the records of a trace, skipping comments and blank lines.
+pub struct TraceReader<R> {
+    name: String,
+    input: R,
+    line: usize,
+}
+
+impl<R: BufRead> TraceReader<R> {
+    /// `name` identifies the trace in errors and reports, e.g. its path.
+    pub fn new(name: impl Into<String>, input: R) -> Self {
+        TraceReader {
+            name: name.into(),
+            input,
+            line: 0,
+        }
+    }
+
+    /// The next record and its line number, or `None` at the end.
+    pub fn next_record(&mut self) -> Result<Option<(usize, TraceRecord)>, TraceError> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = self
+                .input
+                .read_line(&mut line)
+                .map_err(|err| TraceError::Io(self.name.clone(), err))?;
+            if read == 0 {
+                return Ok(None);
+            }
+            self.line += 1;
+
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            return match TraceRecord::parse(line) {
+                Ok(record) => Ok(Some((self.line, record))),
+                Err(message) => Err(TraceError::Parse {
+                    name: self.name.clone(),
+                    line: self.line,
+                    message,
+                }),
+            };
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TraceError {
+    Io(String, io::Error),
+    Parse {
+        name: String,
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceError::Io(name, err) => write!(f, "{name}: {err}"),
+            TraceError::Parse {
+                name,
+                line,
+                message,
+            } => write!(f, "{name}:{line}: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+/// Where two traces stop agreeing, as found by [`diff`].
+#[derive(Debug)]
+pub struct Divergence {
+    /// Number of instructions both traces agree on.
+    pub matching: u64,
+    /// The first differing record of each trace with its name and line
+    /// number, or `None` where that trace ended.
+    pub a: (String, Option<(usize, TraceRecord)>),
+    pub b: (String, Option<(usize, TraceRecord)>),
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "traces diverge after {} matching instructions",
+            self.matching
+        )?;
+        for (name, record) in [&self.a, &self.b] {
+            match record {
+                Some((line, record)) => writeln!(f, "{name}:{line}: {record}")?,
+                None => writeln!(f, "{name}: ends here")?,
+            }
+        }
+        if let (Some((_, a)), Some((_, b))) = (&self.a.1, &self.b.1) {
+            for difference in a.differences(b) {
+                writeln!(f, "  {difference}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compares two traces instruction by instruction, returning the first
+/// pair of records that differ, or `None` if the traces agree throughout.
+pub fn diff<A: BufRead, B: BufRead>(
+    a: &mut TraceReader<A>,
+    b: &mut TraceReader<B>,
+) -> Result<Option<Divergence>, TraceError> {
+    let mut matching = 0;
+    loop {
+        let (record_a, record_b) = (a.next_record()?, b.next_record()?);
+        let agree = match (&record_a, &record_b) {
+            (None, None) => return Ok(None),
+            (Some((_, a)), Some((_, b))) => a.differences(b).is_empty(),
+            _ => false,
+        };
+        if !agree {
+            return Ok(Some(Divergence {
+                matching,
+                a: (a.name.clone(), record_a),
+                b: (b.name.clone(), record_b),
+            }));
+        }
+        matching += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::rewind::Rewind;
+    use crate::{Quirks, Scheduler, Speed};
+
+    const LINE: &str =
+        "2 0204 F033 05 00 00 00 00 00 00 00 00 00 00 00 00 00 00 0A 0300 3C 00 ; LD B, V0";
+
+    /// A trace written to memory, readable while the tracer still holds it.
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Output {
+        fn lines(&self) -> Vec<String> {
+            let output = String::from_utf8(self.0.borrow().clone()).unwrap();
+            output.lines().map(String::from).collect()
+        }
+    }
+
+    fn reader(trace: &str) -> TraceReader<&[u8]> {
+        TraceReader::new("test", trace.as_bytes())
+    }
+
+    #[test]
+    fn parses_what_it_prints() {
+        let record = TraceRecord::parse(LINE).unwrap();
+        assert_eq!(record.cycle, 2);
+        assert_eq!(record.pc, 0x204);
+        assert_eq!(record.opcode, [0xF0, 0x33]);
+        assert_eq!((record.v[0], record.v[0xF]), (5, 10));
+        assert_eq!((record.ireg, record.dt, record.st), (0x300, 0x3C, 0));
+        assert_eq!(record.instruction, "LD B, V0");
+        assert_eq!(record.to_string(), LINE);
+
+        // The disassembly is optional and four-byte instructions fit too
+        let line = LINE.replace("F033", "F0001234").replace(" ; LD B, V0", "");
+        let record = TraceRecord::parse(&line).unwrap();
+        assert_eq!(record.opcode, [0xF0, 0x00, 0x12, 0x34]);
+        assert_eq!(record.instruction, "");
+    }
+
+    #[test]
+    fn rejects_malformed_records() {
+        let errors = [
+            ("2 0204", "expected a cycle, an address and an opcode"),
+            (
+                "2 0204 F033 05 00",
+                "expected the registers, I and the timers",
+            ),
+            (
+                "2 0204 F033 00 00 00 00 0300 00 00",
+                "expected 16 registers, found 4",
+            ),
+        ];
+        for (line, message) in errors {
+            assert_eq!(TraceRecord::parse(line), Err(String::from(message)));
+        }
+        for (from, to, message) in [
+            ("2 ", "x ", "invalid cycle 'x'"),
+            ("0204", "10000", "invalid address '10000'"),
+            ("F033", "F03", "invalid opcode 'F03'"),
+            (" 0A ", " 100 ", "invalid register '100'"),
+            (" 3C ", " -1 ", "invalid delay timer '-1'"),
+        ] {
+            let line = LINE.replacen(from, to, 1);
+            assert_eq!(TraceRecord::parse(&line), Err(String::from(message)));
+        }
+
+        let mut reader = reader("# comment\n\n2 0204\n");
+        let err = reader.next_record().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "test:3: expected a cycle, an address and an opcode"
+        );
+    }
+
+    #[test]
+    fn diff_finds_the_first_difference() {
+        let changed = LINE.replace(" 0A 0300 3C", " 0B 0300 3B");
+        let a = format!("{HEADER}\n{LINE}\n{LINE}\n{LINE}\n");
+        // Comments, cycles and the disassembly are not compared
+        let b = format!(
+            "{LINE}\n# comment\n{}\n{changed}\n",
+            LINE.replace("2 ", "7 ")
+        );
+        let divergence = diff(&mut reader(&a), &mut reader(&b)).unwrap().unwrap();
+        assert_eq!(divergence.matching, 2);
+        assert_eq!(divergence.a.1.as_ref().map(|(line, _)| *line), Some(4));
+        assert_eq!(divergence.b.1.as_ref().map(|(line, _)| *line), Some(4));
+        let report = divergence.to_string();
+        assert!(report.contains("VF: 0A != 0B"), "{report}");
+        assert!(report.contains("DT: 3C != 3B"), "{report}");
+
+        // A trace ending early diverges too
+        let b = format!("{LINE}\n");
+        let divergence = diff(&mut reader(&a), &mut reader(&b)).unwrap().unwrap();
+        assert_eq!(divergence.matching, 1);
+        assert!(divergence.b.1.is_none());
+
+        assert!(diff(&mut reader(&a), &mut reader(&a)).unwrap().is_none());
+    }
+
+    #[test]
+    fn traces_cycles_without_replays() {
+        let mut chip8 = Chip8::new(Quirks::CHIP8);
+        // loop: ADD V0, 1; JP loop
+        chip8.load_program(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+        let output = Output::default();
+        chip8.set_tracer(Tracer::new(output.clone()));
+        let mut scheduler = Scheduler::new(Speed::InstructionsPerFrame(4));
+        let mut rewind = Rewind::default();
+        rewind.record(&chip8, &scheduler);
+        scheduler.run_frame(&mut chip8).unwrap();
+
+        assert!(rewind.step_back(&mut chip8, &mut scheduler));
+        scheduler.step(&mut chip8).unwrap();
+
+        let lines = output.lines();
+        let cycles: Vec<_> = lines[2..]
+            .iter()
+            .map(|line| line.split_whitespace().next().unwrap())
+            .collect();
+        assert_eq!(cycles, ["0", "1", "2", "3", "#", "3"]);
+        assert_eq!(lines[6], "# rewound to cycle 3");
+    }
+}